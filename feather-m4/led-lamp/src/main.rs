@@ -3,6 +3,10 @@
 
 mod prng;
 use crate::prng::Prng;
+// shift register chains for panel builds, shared with the step sequencer
+#[allow(dead_code)]
+#[path = "../../../grand-central-m4/step-sequencer/src/shiftreg.rs"]
+mod shiftreg;
 
 use core::cell::Cell;
 use core::slice::Iter;
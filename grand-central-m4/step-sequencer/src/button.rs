@@ -1,8 +1,64 @@
-use crate::hal::gpio::v2::{Input, Pin, PinId, PullUp};
-use crate::hal::prelude::_atsamd_hal_embedded_hal_digital_v2_InputPin;
+use crate::hal::ehal::digital::v2::InputPin;
+use crate::shiftreg::InputRegister;
 
-pub struct Button<P: PinId> {
-    pin: Pin<P, Input<PullUp>>,
+// any active-low input pin, usually a pull-up input or a type-erased DynPin
+pub struct Button<P: InputPin> {
+    pin: P,
+    debouncer: Debouncer,
+    events: ButtonEvents,
+}
+
+impl<P: InputPin> Button<P> {
+    pub fn new(pin: P, debounce_time: u32) -> Self {
+        Button {
+            pin: pin,
+            debouncer: Debouncer::new(debounce_time),
+            events: ButtonEvents::disabled(),
+        }
+    }
+    
+    pub fn with_events(mut self, timing: EventTiming) -> Self {
+        self.events = ButtonEvents::new(timing);
+        self
+    }
+    
+    pub fn poll(&mut self, millis: u32) {
+        let current_state = self.pin.is_low().unwrap_or(false);
+        self.debouncer.update(current_state, millis);
+        self.events.update(self.debouncer.state, millis);
+    }
+    
+    pub fn next_event(&mut self) -> Option<ButtonEvent> {
+        self.events.next_event()
+    }
+    
+    pub fn press_duration(&self, millis: u32) -> u32 {
+        self.events.press_duration(self.debouncer.state, millis)
+    }
+    
+    pub fn state(&self) -> bool {
+        self.debouncer.state()
+    }
+    
+    pub fn last_state(&self) -> bool {
+        self.debouncer.last_state()
+    }
+    
+    pub fn toggle_state(&self) -> bool {
+        self.debouncer.toggle_state()
+    }
+    
+    pub fn rising_edge(&self) -> bool {
+        self.debouncer.rising_edge()
+    }
+    
+    pub fn falling_edge(&self) -> bool {
+        self.debouncer.falling_edge()
+    }
+}
+
+// the debounce state machine, separate from the pin so it can be fed from other inputs
+pub struct Debouncer {
     debounce_time: u32,
     debounce_max: u32,
     debounce_state: DebounceState,
@@ -13,10 +69,9 @@ pub struct Button<P: PinId> {
     last_off: u32,
 }
 
-impl<P: PinId> Button<P> {
-    pub fn new(pin: Pin<P, Input<PullUp>>, debounce_time: u32) -> Self {
-        Button {
-            pin: pin,
+impl Debouncer {
+    pub fn new(debounce_time: u32) -> Self {
+        Debouncer {
             debounce_time: debounce_time,
             debounce_max: 20,
             debounce_state: DebounceState::Off,
@@ -28,8 +83,7 @@ impl<P: PinId> Button<P> {
         }
     }
     
-    pub fn poll(&mut self, millis: u32) {
-        let current_state = self.pin.is_low().unwrap();
+    pub fn update(&mut self, current_state: bool, millis: u32) {
         let last_on_delta = sub_handle_overflow(millis, self.last_on);
         let last_off_delta = sub_handle_overflow(millis, self.last_off);
         self.last_state = self.state;
@@ -104,6 +158,229 @@ impl<P: PinId> Button<P> {
     }
 }
 
+// debounced buttons read through an InputRegister, such as a chain of 74HC165s.
+// the register is read first, then update passes every input through its own debouncer
+pub struct ShiftButtons<const N: usize> {
+    keys: [Debouncer; N],
+    active_low: bool,  // pull-up inputs read low while pressed
+}
+
+impl<const N: usize> ShiftButtons<N> {
+    pub fn new(debounce_time: u32, active_low: bool) -> Self {
+        ShiftButtons {
+            keys: [(); N].map(|_| Debouncer::new(debounce_time)),
+            active_low: active_low,
+        }
+    }
+    
+    pub fn update<R: InputRegister>(&mut self, register: &R, millis: u32) {
+        for (index, key) in self.keys.iter_mut().enumerate() {
+            let current_state = register.input(index) != self.active_low;
+            key.update(current_state, millis);
+        }
+    }
+    
+    pub fn key(&self, index: usize) -> Option<&Debouncer> {
+        self.keys.get(index)
+    }
+    
+    pub fn state(&self, index: usize) -> bool {
+        self.keys.get(index).map_or(false, |k| k.state())
+    }
+    
+    pub fn toggle_state(&self, index: usize) -> bool {
+        self.keys.get(index).map_or(false, |k| k.toggle_state())
+    }
+    
+    pub fn rising_edge(&self, index: usize) -> bool {
+        self.keys.get(index).map_or(false, |k| k.rising_edge())
+    }
+    
+    pub fn falling_edge(&self, index: usize) -> bool {
+        self.keys.get(index).map_or(false, |k| k.falling_edge())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ButtonEvent {
+    Press,        // debounced rising edge
+    Release,      // debounced falling edge
+    ShortPress,   // released before the long press time
+    LongPress,    // held for the long press time
+    DoublePress,  // pressed again within the double press window after a short press
+    Repeat,       // fired repeatedly while held, after the repeat delay
+}
+
+// times use the same unit as the value passed to poll, a value of 0 disables the event
+#[derive(Debug, Copy, Clone)]
+pub struct EventTiming {
+    pub long_press: u32,
+    pub double_press: u32,
+    pub repeat_delay: u32,
+    pub repeat_rate: u32,
+}
+
+impl EventTiming {
+    pub fn disabled() -> Self {
+        EventTiming {
+            long_press: 0,
+            double_press: 0,
+            repeat_delay: 0,
+            repeat_rate: 0,
+        }
+    }
+}
+
+const EVENT_QUEUE_SIZE: usize = 8;
+
+// fixed size FIFO, new events are dropped when it is full
+pub struct EventQueue {
+    events: [ButtonEvent; EVENT_QUEUE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl EventQueue {
+    pub fn new() -> Self {
+        EventQueue {
+            events: [ButtonEvent::Press; EVENT_QUEUE_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+    
+    pub fn push(&mut self, event: ButtonEvent) {
+        if self.len >= EVENT_QUEUE_SIZE { return }
+        self.events[(self.head + self.len) % EVENT_QUEUE_SIZE] = event;
+        self.len += 1;
+    }
+    
+    pub fn pop(&mut self) -> Option<ButtonEvent> {
+        if self.len == 0 { return None }
+        let event = self.events[self.head];
+        self.head = (self.head + 1) % EVENT_QUEUE_SIZE;
+        self.len -= 1;
+        Some(event)
+    }
+    
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+}
+
+// turns the debounced state into timed events. it only sees a bool and a time, so it
+// can be driven by any input source
+pub struct ButtonEvents {
+    timing: EventTiming,
+    queue: EventQueue,
+    queue_events: bool,  // false when nothing reads the events
+    last_state: bool,
+    press_time: u32,
+    release_time: u32,
+    last_repeat: u32,
+    long_sent: bool,
+    repeating: bool,
+    double_armed: bool,  // the last press was short, the next one may be a double press
+    double_sent: bool,   // the current press is the second half of a double press
+}
+
+impl ButtonEvents {
+    pub fn new(timing: EventTiming) -> Self {
+        ButtonEvents {
+            timing: timing,
+            queue: EventQueue::new(),
+            queue_events: true,
+            last_state: false,
+            press_time: 0,
+            release_time: 0,
+            last_repeat: 0,
+            long_sent: false,
+            repeating: false,
+            double_armed: false,
+            double_sent: false,
+        }
+    }
+    
+    // only keeps the press time for press_duration, without queueing events that would
+    // never be read
+    pub fn disabled() -> Self {
+        let mut events = ButtonEvents::new(EventTiming::disabled());
+        events.queue_events = false;
+        events
+    }
+    
+    pub fn update(&mut self, state: bool, millis: u32) {
+        if state == true && self.last_state == false {
+            self.push(ButtonEvent::Press);
+            self.double_sent = false;
+            if self.double_armed == true && self.timing.double_press > 0
+                && sub_handle_overflow(millis, self.release_time) <= self.timing.double_press {
+                self.push(ButtonEvent::DoublePress);
+                self.double_sent = true;
+            }
+            self.press_time = millis;
+            self.long_sent = false;
+            self.repeating = false;
+        }
+        
+        if state == true {
+            let held = sub_handle_overflow(millis, self.press_time);
+            if self.timing.long_press > 0 && self.long_sent == false && held >= self.timing.long_press {
+                self.push(ButtonEvent::LongPress);
+                self.long_sent = true;
+            }
+            if self.timing.repeat_delay > 0 && self.timing.repeat_rate > 0 {
+                if self.repeating == false && held >= self.timing.repeat_delay {
+                    self.push(ButtonEvent::Repeat);
+                    self.repeating = true;
+                    self.last_repeat = millis;
+                } else if self.repeating == true && sub_handle_overflow(millis, self.last_repeat) >= self.timing.repeat_rate {
+                    self.push(ButtonEvent::Repeat);
+                    self.last_repeat = self.last_repeat.wrapping_add(self.timing.repeat_rate);
+                }
+            }
+        }
+        
+        if state == false && self.last_state == true {
+            self.push(ButtonEvent::Release);
+            if self.long_sent == false {
+                self.push(ButtonEvent::ShortPress);
+                // a third press starts over instead of being another double press
+                self.double_armed = !self.double_sent;
+            } else {
+                self.double_armed = false;
+            }
+            self.release_time = millis;
+        }
+        
+        self.last_state = state;
+    }
+    
+    pub fn next_event(&mut self) -> Option<ButtonEvent> {
+        self.queue.pop()
+    }
+    
+    pub fn clear(&mut self) {
+        self.queue.clear();
+    }
+    
+    fn push(&mut self, event: ButtonEvent) {
+        if self.queue_events == true {
+            self.queue.push(event);
+        }
+    }
+    
+    // how long the button has been held, or 0 when it is released
+    pub fn press_duration(&self, state: bool, millis: u32) -> u32 {
+        if state == true {
+            sub_handle_overflow(millis, self.press_time)
+        } else {
+            0
+        }
+    }
+}
+
 fn sub_handle_overflow(millis: u32, last_time: u32) -> u32 {
     //~ until the millis variable overflows, it should always be larger than or equal to last_time
     if millis >= last_time {
@@ -121,3 +398,211 @@ enum DebounceState {
     DebouncingOn,
     On,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use core::convert::Infallible;
+    use std::rc::Rc;
+    use std::vec::Vec;
+    
+    // an active-low pin whose level is set by the test
+    struct FakePin(Rc<Cell<bool>>);
+    
+    impl InputPin for FakePin {
+        type Error = Infallible;
+        
+        fn is_high(&self) -> Result<bool, Infallible> {
+            Ok(self.0.get() == false)
+        }
+        
+        fn is_low(&self) -> Result<bool, Infallible> {
+            Ok(self.0.get())
+        }
+    }
+    
+    const TIMING: EventTiming = EventTiming {
+        long_press: 500,
+        double_press: 300,
+        repeat_delay: 0,
+        repeat_rate: 0,
+    };
+    
+    fn button(timing: Option<EventTiming>) -> (Button<FakePin>, Rc<Cell<bool>>) {
+        let pressed = Rc::new(Cell::new(false));
+        let mut button = Button::new(FakePin(pressed.clone()), 5);
+        if let Some(timing) = timing {
+            button = button.with_events(timing);
+        }
+        (button, pressed)
+    }
+    
+    // holds the pin pressed or released from start until just before end, polling every ms
+    fn hold(button: &mut Button<FakePin>, pin: &Cell<bool>, pressed: bool, start: u32, end: u32) {
+        pin.set(pressed);
+        for millis in start..end {
+            button.poll(millis);
+        }
+    }
+    
+    fn events(button: &mut Button<FakePin>) -> Vec<ButtonEvent> {
+        let mut events = Vec::new();
+        while let Some(event) = button.next_event() {
+            events.push(event);
+        }
+        events
+    }
+    
+    #[test]
+    fn debounces_press_and_release() {
+        let (mut button, pin) = button(None);
+        hold(&mut button, &pin, true, 0, 6);
+        assert_eq!(button.state(), false);
+        button.poll(6);
+        assert_eq!(button.state(), true);
+        assert_eq!(button.rising_edge(), true);
+        button.poll(7);
+        assert_eq!(button.rising_edge(), false);
+        assert_eq!(button.toggle_state(), true);
+        
+        hold(&mut button, &pin, false, 8, 14);
+        assert_eq!(button.state(), true);
+        button.poll(14);
+        assert_eq!(button.state(), false);
+        assert_eq!(button.falling_edge(), true);
+    }
+    
+    #[test]
+    fn ignores_a_short_bounce() {
+        let (mut button, pin) = button(None);
+        hold(&mut button, &pin, true, 0, 3);
+        hold(&mut button, &pin, false, 3, 50);
+        assert_eq!(button.state(), false);
+        assert_eq!(button.toggle_state(), false);
+    }
+    
+    #[test]
+    fn short_press() {
+        let (mut button, pin) = button(Some(TIMING));
+        hold(&mut button, &pin, true, 0, 100);
+        assert_eq!(events(&mut button), [ButtonEvent::Press]);
+        assert_eq!(button.press_duration(99), 93);
+        hold(&mut button, &pin, false, 100, 200);
+        assert_eq!(events(&mut button), [ButtonEvent::Release, ButtonEvent::ShortPress]);
+        assert_eq!(button.press_duration(200), 0);
+    }
+    
+    #[test]
+    fn long_press() {
+        let (mut button, pin) = button(Some(TIMING));
+        hold(&mut button, &pin, true, 0, 700);
+        assert_eq!(events(&mut button), [ButtonEvent::Press, ButtonEvent::LongPress]);
+        hold(&mut button, &pin, false, 700, 800);
+        assert_eq!(events(&mut button), [ButtonEvent::Release]);
+    }
+    
+    #[test]
+    fn double_press() {
+        let (mut button, pin) = button(Some(TIMING));
+        hold(&mut button, &pin, true, 0, 50);
+        hold(&mut button, &pin, false, 50, 100);
+        hold(&mut button, &pin, true, 100, 150);
+        hold(&mut button, &pin, false, 150, 200);
+        assert_eq!(events(&mut button), [
+            ButtonEvent::Press, ButtonEvent::Release, ButtonEvent::ShortPress,
+            ButtonEvent::Press, ButtonEvent::DoublePress, ButtonEvent::Release, ButtonEvent::ShortPress,
+        ]);
+        // a third press starts over
+        hold(&mut button, &pin, true, 200, 250);
+        hold(&mut button, &pin, false, 250, 300);
+        assert_eq!(events(&mut button), [ButtonEvent::Press, ButtonEvent::Release, ButtonEvent::ShortPress]);
+    }
+    
+    #[test]
+    fn no_double_press_after_the_window() {
+        let (mut button, pin) = button(Some(TIMING));
+        hold(&mut button, &pin, true, 0, 50);
+        hold(&mut button, &pin, false, 50, 500);
+        hold(&mut button, &pin, true, 500, 550);
+        let events = events(&mut button);
+        assert_eq!(events.contains(&ButtonEvent::DoublePress), false);
+    }
+    
+    #[test]
+    fn repeats_while_held() {
+        let timing = EventTiming { long_press: 0, double_press: 0, repeat_delay: 200, repeat_rate: 50 };
+        let (mut button, pin) = button(Some(timing));
+        // pressed at 6, repeats at 206, 256, 306 and 356
+        hold(&mut button, &pin, true, 0, 406);
+        assert_eq!(events(&mut button), [
+            ButtonEvent::Press, ButtonEvent::Repeat, ButtonEvent::Repeat, ButtonEvent::Repeat, ButtonEvent::Repeat,
+        ]);
+    }
+    
+    #[test]
+    fn no_events_without_timing() {
+        let (mut button, pin) = button(None);
+        for press in 0..20 {
+            hold(&mut button, &pin, true, press * 100, press * 100 + 50);
+            hold(&mut button, &pin, false, press * 100 + 50, press * 100 + 100);
+        }
+        assert_eq!(button.next_event(), None);
+        // the press time is still kept
+        hold(&mut button, &pin, true, 2000, 2020);
+        assert_eq!(button.press_duration(2019), 13);
+    }
+    
+    // a register whose inputs are set by the test
+    struct FakeRegister([bool; 16]);
+    
+    impl InputRegister for FakeRegister {
+        fn read(&mut self) -> Result<(), crate::shiftreg::ShiftError> {
+            Ok(())
+        }
+        
+        fn len(&self) -> usize {
+            16
+        }
+        
+        fn input(&self, index: usize) -> bool {
+            self.0[index]
+        }
+    }
+    
+    #[test]
+    fn shift_buttons_debounce_each_input() {
+        let mut buttons = ShiftButtons::<16>::new(5, true);
+        // pull-up inputs, all released
+        let mut register = FakeRegister([true; 16]);
+        register.0[3] = false;
+        register.0[12] = false;
+        for millis in 0..6 {
+            buttons.update(&register, millis);
+        }
+        assert_eq!(buttons.state(3), false);
+        buttons.update(&register, 6);
+        assert_eq!(buttons.rising_edge(3), true);
+        assert_eq!(buttons.rising_edge(12), true);
+        assert_eq!(buttons.state(4), false);
+        assert_eq!(buttons.state(16), false);
+        
+        register.0[3] = true;
+        for millis in 7..20 {
+            buttons.update(&register, millis);
+        }
+        assert_eq!(buttons.state(3), false);
+        assert_eq!(buttons.state(12), true);
+        assert_eq!(buttons.toggle_state(3), true);
+    }
+    
+    #[test]
+    fn full_queue_drops_new_events() {
+        let (mut button, pin) = button(Some(EventTiming::disabled()));
+        for press in 0..10 {
+            hold(&mut button, &pin, true, press * 100, press * 100 + 50);
+            hold(&mut button, &pin, false, press * 100 + 50, press * 100 + 100);
+        }
+        assert_eq!(events(&mut button).len(), EVENT_QUEUE_SIZE);
+    }
+}
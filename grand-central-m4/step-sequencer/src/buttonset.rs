@@ -1,232 +1,250 @@
-use crate::button::Button;
-use crate::hal::gpio::PinId;
-use crate::{NUM_CHANNELS, NUM_STEPS, Step, VELOCITY_LEVELS};
-
-pub struct StepButtonSet<
-    P00: PinId,
-    P01: PinId,
-    P02: PinId,
-    P03: PinId,
-    P04: PinId,
-    P05: PinId,
-    P06: PinId,
-    P07: PinId,
-    P08: PinId,
-    P09: PinId,
-    P10: PinId,
-    P11: PinId,
-    P12: PinId,
-    P13: PinId,
-    P14: PinId,
-    P15: PinId,
-    
-    V00: PinId,
-    V01: PinId,
-    V02: PinId,
-    V03: PinId,
-> 
-{
-    pub b00: Button<P00>,
-    pub b01: Button<P01>,
-    pub b02: Button<P02>,
-    pub b03: Button<P03>,
-    pub b04: Button<P04>,
-    pub b05: Button<P05>,
-    pub b06: Button<P06>,
-    pub b07: Button<P07>,
-    pub b08: Button<P08>,
-    pub b09: Button<P09>,
-    pub b10: Button<P10>,
-    pub b11: Button<P11>,
-    pub b12: Button<P12>,
-    pub b13: Button<P13>,
-    pub b14: Button<P14>,
-    pub b15: Button<P15>,
-    
-    pub v00: Button<V00>,
-    pub v01: Button<V01>,
-    pub v02: Button<V02>,
-    pub v03: Button<V03>,
+use crate::button::{Button, ShiftButtons};
+use crate::hal::gpio::v2::DynPin;
+use crate::scale::Scale;
+use crate::{ChannelMode, NUM_CHANNELS, NUM_STEPS, Step, VELOCITY_LEVELS};
+
+// a row of N buttons on type-erased pins, polled and queried together
+pub struct ButtonSet<const N: usize> {
+    buttons: [Button<DynPin>; N],
 }
 
-impl <
-    P00: PinId, 
-    P01: PinId, 
-    P02: PinId, 
-    P03: PinId,
-    P04: PinId,
-    P05: PinId,
-    P06: PinId,
-    P07: PinId,
-    P08: PinId,
-    P09: PinId,
-    P10: PinId,
-    P11: PinId,
-    P12: PinId,
-    P13: PinId,
-    P14: PinId,
-    P15: PinId,
-    
-    V00: PinId,
-    V01: PinId,
-    V02: PinId,
-    V03: PinId,
->
-StepButtonSet<
-    P00, 
-    P01, 
-    P02, 
-    P03,
-    P04,
-    P05,
-    P06,
-    P07,
-    P08,
-    P09,
-    P10,
-    P11,
-    P12,
-    P13,
-    P14,
-    P15,
-    
-    V00,
-    V01,
-    V02,
-    V03,
->
-{
+impl<const N: usize> ButtonSet<N> {
+    pub fn new(pins: [DynPin; N], debounce_time: u32) -> Self {
+        ButtonSet {
+            buttons: pins.map(|pin| Button::new(pin, debounce_time)),
+        }
+    }
+
     pub fn poll(&mut self, time: u32) {
-        self.b00.poll(time);
-        self.b01.poll(time);
-        self.b02.poll(time);
-        self.b03.poll(time);
-        self.b04.poll(time);
-        self.b05.poll(time);
-        self.b06.poll(time);
-        self.b07.poll(time);
-        self.b08.poll(time);
-        self.b09.poll(time);
-        self.b10.poll(time);
-        self.b11.poll(time);
-        self.b12.poll(time);
-        self.b13.poll(time);
-        self.b14.poll(time);
-        self.b15.poll(time);
-        
-        self.v00.poll(time);
-        self.v01.poll(time);
-        self.v02.poll(time);
-        self.v03.poll(time);
-    }
-    
-    pub fn update_steps(&self, steps: &mut [[Step; NUM_STEPS]; NUM_CHANNELS], step_offset: usize, step_channel: usize) {
-        if self.v00.state() == true || self.v01.state() == true || self.v02.state() == true || self.v03.state() == true {
-            let mut vel = 0;
-            if self.v00.state() == true { vel = VELOCITY_LEVELS[0] }
-            if self.v01.state() == true { vel = VELOCITY_LEVELS[1] }
-            if self.v02.state() == true { vel = VELOCITY_LEVELS[2] }
-            if self.v03.state() == true { vel = VELOCITY_LEVELS[3] }
-            
-            if self.b00.rising_edge() == true { steps[step_channel][step_offset +  0].vel = vel }
-            if self.b01.rising_edge() == true { steps[step_channel][step_offset +  1].vel = vel }
-            if self.b02.rising_edge() == true { steps[step_channel][step_offset +  2].vel = vel }
-            if self.b03.rising_edge() == true { steps[step_channel][step_offset +  3].vel = vel }
-            if self.b04.rising_edge() == true { steps[step_channel][step_offset +  4].vel = vel }
-            if self.b05.rising_edge() == true { steps[step_channel][step_offset +  5].vel = vel }
-            if self.b06.rising_edge() == true { steps[step_channel][step_offset +  6].vel = vel }
-            if self.b07.rising_edge() == true { steps[step_channel][step_offset +  7].vel = vel }
-            if self.b08.rising_edge() == true { steps[step_channel][step_offset +  8].vel = vel }
-            if self.b09.rising_edge() == true { steps[step_channel][step_offset +  9].vel = vel }
-            if self.b10.rising_edge() == true { steps[step_channel][step_offset + 10].vel = vel }
-            if self.b11.rising_edge() == true { steps[step_channel][step_offset + 11].vel = vel }
-            if self.b12.rising_edge() == true { steps[step_channel][step_offset + 12].vel = vel }
-            if self.b13.rising_edge() == true { steps[step_channel][step_offset + 13].vel = vel }
-            if self.b14.rising_edge() == true { steps[step_channel][step_offset + 14].vel = vel }
-            if self.b15.rising_edge() == true { steps[step_channel][step_offset + 15].vel = vel }
+        for button in self.buttons.iter_mut() {
+            button.poll(time);
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Button<DynPin>> {
+        self.buttons.get(index)
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Button<DynPin>> {
+        self.buttons.get_mut(index)
+    }
+
+    pub fn iter(&self) -> core::slice::Iter<'_, Button<DynPin>> {
+        self.buttons.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> core::slice::IterMut<'_, Button<DynPin>> {
+        self.buttons.iter_mut()
+    }
+}
+
+impl<const N: usize> ButtonGroup for ButtonSet<N> {
+    fn len(&self) -> usize {
+        N
+    }
+
+    fn state(&self, index: usize) -> bool {
+        self.buttons.get(index).map_or(false, |b| b.state())
+    }
+
+    fn rising_edge(&self, index: usize) -> bool {
+        self.buttons.get(index).map_or(false, |b| b.rising_edge())
+    }
+
+    fn falling_edge(&self, index: usize) -> bool {
+        self.buttons.get(index).map_or(false, |b| b.falling_edge())
+    }
+}
+
+impl<const N: usize> ButtonGroup for ShiftButtons<N> {
+    fn len(&self) -> usize {
+        N
+    }
+
+    fn state(&self, index: usize) -> bool {
+        ShiftButtons::state(self, index)
+    }
+
+    fn rising_edge(&self, index: usize) -> bool {
+        ShiftButtons::rising_edge(self, index)
+    }
+
+    fn falling_edge(&self, index: usize) -> bool {
+        ShiftButtons::falling_edge(self, index)
+    }
+}
+
+// anything that exposes a row of debounced buttons by index, so the step editing
+// works the same with direct GPIO buttons or a scanned matrix
+pub trait ButtonGroup: Sized {
+    fn len(&self) -> usize;
+    fn state(&self, index: usize) -> bool;
+    fn rising_edge(&self, index: usize) -> bool;
+    fn falling_edge(&self, index: usize) -> bool;
+
+    // indices of the buttons that were pressed on the last poll
+    fn rising_edges(&self) -> Indices<'_, Self> {
+        Indices { group: self, index: 0, filter: Filter::RisingEdge }
+    }
+
+    // indices of the buttons that were released on the last poll
+    fn falling_edges(&self) -> Indices<'_, Self> {
+        Indices { group: self, index: 0, filter: Filter::FallingEdge }
+    }
+
+    // indices of the buttons that are currently held
+    fn held(&self) -> Indices<'_, Self> {
+        Indices { group: self, index: 0, filter: Filter::Held }
+    }
+
+    fn first_rising_edge(&self) -> Option<usize> {
+        self.rising_edges().next()
+    }
+
+    // the highest held index, so that the last button in the row wins
+    fn last_held(&self) -> Option<usize> {
+        self.held().last()
+    }
+
+    fn any_held(&self) -> bool {
+        self.held().next().is_some()
+    }
+
+    // true on the poll where the last button of the chord goes down while the rest are held
+    fn chord(&self, indices: &[usize]) -> bool {
+        let mut all_held = true;
+        let mut any_edge = false;
+        for index in indices.iter() {
+            if *index >= self.len() || self.state(*index) == false { all_held = false }
+            if self.rising_edge(*index) == true { any_edge = true }
+        }
+        all_held == true && any_edge == true
+    }
+
+    // a run of len buttons from start, e.g. the step or channel keys of a matrix
+    fn slice(&self, start: usize, len: usize) -> Slice<'_, Self> {
+        Slice { group: self, start: start, len: len }
+    }
+}
+
+pub struct Slice<'a, G: ButtonGroup> {
+    group: &'a G,
+    start: usize,
+    len: usize,
+}
+
+impl<'a, G: ButtonGroup> ButtonGroup for Slice<'a, G> {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn state(&self, index: usize) -> bool {
+        index < self.len && self.group.state(self.start + index)
+    }
+
+    fn rising_edge(&self, index: usize) -> bool {
+        index < self.len && self.group.rising_edge(self.start + index)
+    }
+
+    fn falling_edge(&self, index: usize) -> bool {
+        index < self.len && self.group.falling_edge(self.start + index)
+    }
+}
+
+enum Filter {
+    RisingEdge,
+    FallingEdge,
+    Held,
+}
+
+pub struct Indices<'a, G: ButtonGroup> {
+    group: &'a G,
+    index: usize,
+    filter: Filter,
+}
+
+impl<'a, G: ButtonGroup> Iterator for Indices<'a, G> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        while self.index < self.group.len() {
+            let index = self.index;
+            self.index += 1;
+            let matched = match self.filter {
+                Filter::RisingEdge => self.group.rising_edge(index),
+                Filter::FallingEdge => self.group.falling_edge(index),
+                Filter::Held => self.group.state(index),
+            };
+            if matched == true { return Some(index) }
+        }
+        None
+    }
+}
+
+// melodic channels use the velocity row as pitch modifiers while a step button is pressed:
+// v00 = note down, v01 = note up, v02 = octave down, v03 = octave up
+pub fn update_pitches<S: ButtonGroup, V: ButtonGroup>(
+    step_buttons: &S,
+    velocity_buttons: &V,
+    steps: &mut [[Step; NUM_STEPS]; NUM_CHANNELS],
+    step_offset: usize,
+    step_channel: usize,
+    scale: &Scale,
+) {
+    for index in step_buttons.rising_edges() {
+        let step = &mut steps[step_channel][step_offset + index];
+        if velocity_buttons.any_held() == true {
+            step.gate = true;
+            for held in velocity_buttons.held() {
+                match held {
+                    0 => step.note_down(scale),
+                    1 => step.note_up(scale),
+                    2 => step.octave_down(),
+                    _ => step.octave_up(),
+                }
+            }
         } else {
-            if self.b00.rising_edge() == true { steps[step_channel][step_offset +  0].gate = !steps[step_channel][step_offset +  0].gate }
-            if self.b01.rising_edge() == true { steps[step_channel][step_offset +  1].gate = !steps[step_channel][step_offset +  1].gate }
-            if self.b02.rising_edge() == true { steps[step_channel][step_offset +  2].gate = !steps[step_channel][step_offset +  2].gate }
-            if self.b03.rising_edge() == true { steps[step_channel][step_offset +  3].gate = !steps[step_channel][step_offset +  3].gate }
-            if self.b04.rising_edge() == true { steps[step_channel][step_offset +  4].gate = !steps[step_channel][step_offset +  4].gate }
-            if self.b05.rising_edge() == true { steps[step_channel][step_offset +  5].gate = !steps[step_channel][step_offset +  5].gate }
-            if self.b06.rising_edge() == true { steps[step_channel][step_offset +  6].gate = !steps[step_channel][step_offset +  6].gate }
-            if self.b07.rising_edge() == true { steps[step_channel][step_offset +  7].gate = !steps[step_channel][step_offset +  7].gate }
-            if self.b08.rising_edge() == true { steps[step_channel][step_offset +  8].gate = !steps[step_channel][step_offset +  8].gate }
-            if self.b09.rising_edge() == true { steps[step_channel][step_offset +  9].gate = !steps[step_channel][step_offset +  9].gate }
-            if self.b10.rising_edge() == true { steps[step_channel][step_offset + 10].gate = !steps[step_channel][step_offset + 10].gate }
-            if self.b11.rising_edge() == true { steps[step_channel][step_offset + 11].gate = !steps[step_channel][step_offset + 11].gate }
-            if self.b12.rising_edge() == true { steps[step_channel][step_offset + 12].gate = !steps[step_channel][step_offset + 12].gate }
-            if self.b13.rising_edge() == true { steps[step_channel][step_offset + 13].gate = !steps[step_channel][step_offset + 13].gate }
-            if self.b14.rising_edge() == true { steps[step_channel][step_offset + 14].gate = !steps[step_channel][step_offset + 14].gate }
-            if self.b15.rising_edge() == true { steps[step_channel][step_offset + 15].gate = !steps[step_channel][step_offset + 15].gate }
+            step.gate = !step.gate;
+            if step.gate == false { step.lock = None }
         }
     }
 }
 
+pub fn update_steps<S: ButtonGroup, V: ButtonGroup>(
+    step_buttons: &S,
+    velocity_buttons: &V,
+    steps: &mut [[Step; NUM_STEPS]; NUM_CHANNELS],
+    step_offset: usize,
+    step_channel: usize,
+    mode: ChannelMode,
+    scale: &Scale,
+) {
+    if mode == ChannelMode::Melodic {
+        update_pitches(step_buttons, velocity_buttons, steps, step_offset, step_channel, scale);
+        return;
+    }
 
-pub struct ChannelButtonSet<
-    P00: PinId,
-    P01: PinId,
-    P02: PinId,
-    P03: PinId,
-    P04: PinId,
-    P05: PinId,
-    P06: PinId,
-    P07: PinId,
-> 
-{
-    pub p00: Button<P00>,
-    pub p01: Button<P01>,
-    pub p02: Button<P02>,
-    pub p03: Button<P03>,
-    pub p04: Button<P04>,
-    pub p05: Button<P05>,
-    pub p06: Button<P06>,
-    pub p07: Button<P07>,
+    match velocity_buttons.last_held() {
+        // a held velocity button sets the velocity of pressed steps
+        Some(level) => {
+            let vel = VELOCITY_LEVELS[level.min(VELOCITY_LEVELS.len() - 1)];
+            for index in step_buttons.rising_edges() {
+                steps[step_channel][step_offset + index].vel = vel;
+            }
+        }
+        None => {
+            for index in step_buttons.rising_edges() {
+                let step = &mut steps[step_channel][step_offset + index];
+                step.gate = !step.gate;
+                // turning a step off also removes its parameter lock
+                if step.gate == false { step.lock = None }
+            }
+        }
+    }
 }
 
-impl <
-    P00: PinId, 
-    P01: PinId, 
-    P02: PinId, 
-    P03: PinId,
-    P04: PinId,
-    P05: PinId,
-    P06: PinId,
-    P07: PinId,
->
-ChannelButtonSet<
-    P00, 
-    P01, 
-    P02, 
-    P03,
-    P04,
-    P05,
-    P06,
-    P07,
->
-{
-    pub fn poll(&mut self, time: u32) {
-        self.p00.poll(time);
-        self.p01.poll(time);
-        self.p02.poll(time);
-        self.p03.poll(time);
-        self.p04.poll(time);
-        self.p05.poll(time);
-        self.p06.poll(time);
-        self.p07.poll(time);
-    }
-    
-    pub fn update_channel(&self, step_channel: &mut usize) {
-        if self.p00.rising_edge() == true { *step_channel = 0 }
-        if self.p01.rising_edge() == true { *step_channel = 1 }
-        if self.p02.rising_edge() == true { *step_channel = 2 }
-        if self.p03.rising_edge() == true { *step_channel = 3 }
-        if self.p04.rising_edge() == true { *step_channel = 4 }
-        if self.p05.rising_edge() == true { *step_channel = 5 }
-        if self.p06.rising_edge() == true { *step_channel = 6 }
-        if self.p07.rising_edge() == true { *step_channel = 7 }
+pub fn update_channel<C: ButtonGroup>(channel_buttons: &C, step_channel: &mut usize) {
+    for index in channel_buttons.rising_edges() {
+        *step_channel = index;
     }
 }
@@ -0,0 +1,62 @@
+use crate::hal::pac::{DAC, MCLK};
+
+const DAC_MAX: u16 = 4095;
+const DAC_VOLTS: f32 = 3.3;        // full scale output with the VDDANA reference
+const CV_BASE_NOTE: u8 = 36;       // note that outputs 0V in pitch mode
+// 3.3V full scale covers 3.3 octaves, notes 36-75. lower notes output 0V and higher
+// ones the full scale
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CvMode {
+    Pitch,     // 1V/octave from the note number
+    Velocity,  // 0V..full scale from the note velocity
+}
+
+// 12-bit output on DAC0 (the A0 pin). the DAC needs a generic clock of 12MHz or less,
+// which is set up in main before this is created
+pub struct CvOutput {
+    dac: DAC,
+    mode: CvMode,
+}
+
+impl CvOutput {
+    pub fn new(dac: DAC, mclk: &mut MCLK, mode: CvMode) -> Self {
+        mclk.apbdmask.modify(|_, w| w.dac_().set_bit());
+        
+        dac.ctrla.modify(|_, w| w.swrst().set_bit());
+        while dac.syncbusy.read().swrst().bit_is_set() {}
+        
+        dac.ctrlb.write(|w| w.refsel().vddana());
+        dac.dacctrl[0].write(|w| w.cctrl().cc12m().enable().set_bit());
+        dac.ctrla.modify(|_, w| w.enable().set_bit());
+        while dac.syncbusy.read().enable().bit_is_set() {}
+        while dac.status.read().ready0().bit_is_clear() {}
+        
+        CvOutput {
+            dac: dac,
+            mode: mode,
+        }
+    }
+    
+    pub fn mode(&self) -> CvMode {
+        self.mode
+    }
+    
+    pub fn write(&mut self, value: u16) {
+        while self.dac.syncbusy.read().data0().bit_is_set() {}
+        self.dac.data[0].write(|w| unsafe { w.data().bits(value.min(DAC_MAX)) });
+    }
+    
+    pub fn set_note(&mut self, note: u8, velocity: u8) {
+        let value = match self.mode {
+            CvMode::Pitch => {
+                let volts = (note.saturating_sub(CV_BASE_NOTE) as f32) / 12.0;
+                (volts / DAC_VOLTS * (DAC_MAX as f32)) as u16
+            }
+            CvMode::Velocity => {
+                ((velocity.min(127) as u32 * DAC_MAX as u32) / 127) as u16
+            }
+        };
+        self.write(value);
+    }
+}
@@ -0,0 +1,108 @@
+use crate::hal::ehal::digital::v2::InputPin;
+
+// full step state table for a quadrature encoder. a direction is only reported after the
+// whole sequence between two detents has been seen, so contact bounce on either pin
+// moves back and forth inside the table without producing counts
+const R_START: u8 = 0x0;
+const R_CW_FINAL: u8 = 0x1;
+const R_CW_BEGIN: u8 = 0x2;
+const R_CW_NEXT: u8 = 0x3;
+const R_CCW_BEGIN: u8 = 0x4;
+const R_CCW_FINAL: u8 = 0x5;
+const R_CCW_NEXT: u8 = 0x6;
+const DIR_CW: u8 = 0x10;
+const DIR_CCW: u8 = 0x20;
+
+// indexed by [state][(b << 1) | a], with both pins high at rest
+const STATE_TABLE: [[u8; 4]; 7] = [
+    // R_START
+    [R_START,    R_CW_BEGIN,  R_CCW_BEGIN, R_START],
+    // R_CW_FINAL
+    [R_CW_NEXT,  R_START,     R_CW_FINAL,  R_START | DIR_CW],
+    // R_CW_BEGIN
+    [R_CW_NEXT,  R_CW_BEGIN,  R_START,     R_START],
+    // R_CW_NEXT
+    [R_CW_NEXT,  R_CW_BEGIN,  R_CW_FINAL,  R_START],
+    // R_CCW_BEGIN
+    [R_CCW_NEXT, R_START,     R_CCW_BEGIN, R_START],
+    // R_CCW_FINAL
+    [R_CCW_NEXT, R_CCW_FINAL, R_START,     R_START | DIR_CCW],
+    // R_CCW_NEXT
+    [R_CCW_NEXT, R_CCW_FINAL, R_CCW_BEGIN, R_START],
+];
+
+// decode one pin reading, returns +1 or -1 when a detent is completed
+pub fn decode(state: &mut u8, a: bool, b: bool) -> i8 {
+    let pins = ((b as usize) << 1) | (a as usize);
+    let next = STATE_TABLE[(*state & 0x0F) as usize][pins];
+    *state = next & 0x0F;
+    match next & 0x30 {
+        DIR_CW => 1,
+        DIR_CCW => -1,
+        _ => 0,
+    }
+}
+
+// detents closer together than threshold are multiplied, up to max_multiplier,
+// so fast turns cover a large range. times use the same unit as poll
+#[derive(Debug, Copy, Clone)]
+pub struct Acceleration {
+    pub threshold: u32,
+    pub max_multiplier: u32,
+}
+
+pub fn accelerate(acceleration: &Acceleration, interval: u32) -> i32 {
+    if interval >= acceleration.threshold || acceleration.max_multiplier <= 1 {
+        return 1;
+    }
+    (acceleration.threshold / interval.max(1)).max(1).min(acceleration.max_multiplier) as i32
+}
+
+pub struct Encoder<A: InputPin, B: InputPin> {
+    pin_a: A,
+    pin_b: B,
+    state: u8,
+    delta: i32,
+    acceleration: Option<Acceleration>,
+    last_detent: u32,
+}
+
+impl<A: InputPin, B: InputPin> Encoder<A, B> {
+    pub fn new(pin_a: A, pin_b: B) -> Self {
+        Encoder {
+            pin_a: pin_a,
+            pin_b: pin_b,
+            state: R_START,
+            delta: 0,
+            acceleration: None,
+            last_detent: 0,
+        }
+    }
+    
+    pub fn with_acceleration(mut self, acceleration: Acceleration) -> Self {
+        self.acceleration = Some(acceleration);
+        self
+    }
+    
+    // call as often as possible from the main loop, like Button::poll
+    pub fn poll(&mut self, millis: u32) {
+        let a = self.pin_a.is_high().unwrap_or(true);
+        let b = self.pin_b.is_high().unwrap_or(true);
+        let direction = decode(&mut self.state, a, b);
+        if direction == 0 { return }
+        
+        let multiplier = match self.acceleration {
+            Some(ref acceleration) => accelerate(acceleration, millis.wrapping_sub(self.last_detent)),
+            None => 1,
+        };
+        self.last_detent = millis;
+        self.delta += (direction as i32) * multiplier;
+    }
+    
+    // returns the detents counted since the last call, clockwise is positive
+    pub fn take_delta(&mut self) -> i32 {
+        let delta = self.delta;
+        self.delta = 0;
+        delta
+    }
+}
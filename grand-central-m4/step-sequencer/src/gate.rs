@@ -0,0 +1,63 @@
+use crate::hal::gpio::v2::DynPin;
+use crate::hal::prelude::_atsamd_hal_embedded_hal_digital_v2_OutputPin;
+use crate::NUM_CHANNELS;
+
+// a GPIO output that goes high for pulse_width microseconds each time it is fired
+pub struct Trigger {
+    pin: DynPin,
+    enabled: bool,
+    pulse_width: u32,
+    start: u32,
+    active: bool,
+}
+
+impl Trigger {
+    pub fn new(mut pin: DynPin, enabled: bool, pulse_width: u32) -> Self {
+        let _ = pin.set_low();
+        Trigger {
+            pin: pin,
+            enabled: enabled,
+            pulse_width: pulse_width,
+            start: 0,
+            active: false,
+        }
+    }
+    
+    pub fn fire(&mut self, time: u32) {
+        if self.enabled == false { return }
+        let _ = self.pin.set_high();
+        self.start = time;
+        self.active = true;
+    }
+    
+    pub fn poll(&mut self, time: u32) {
+        if self.active == true && time.wrapping_sub(self.start) >= self.pulse_width {
+            let _ = self.pin.set_low();
+            self.active = false;
+        }
+    }
+}
+
+// per-channel trigger outputs plus clock and reset pulses, fired from the same place
+// in the main loop as the MIDI notes so the gates line up with them
+pub struct GateOutputs {
+    pub channels: [Trigger; NUM_CHANNELS],
+    pub clock: Trigger,
+    pub reset: Trigger,
+}
+
+impl GateOutputs {
+    pub fn fire_channel(&mut self, channel: usize, time: u32) {
+        if let Some(trigger) = self.channels.get_mut(channel) {
+            trigger.fire(time);
+        }
+    }
+    
+    pub fn poll(&mut self, time: u32) {
+        for trigger in self.channels.iter_mut() {
+            trigger.poll(time);
+        }
+        self.clock.poll(time);
+        self.reset.poll(time);
+    }
+}
@@ -1,29 +1,52 @@
-#![no_std]
-#![no_main]
+// host tests build with std and the test harness's main
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 
 mod button;
-use crate::button::Button;
+use crate::button::{Button, ButtonEvent, EventTiming};
+#[cfg(feature = "shiftreg")]
+use crate::button::ShiftButtons;
 mod buttonset;
-use crate::buttonset::{ChannelButtonSet,StepButtonSet};
+use crate::buttonset::{ButtonGroup, ButtonSet};
+mod dac;
+use crate::dac::{CvMode, CvOutput};
+mod encoder;
+use crate::encoder::{Acceleration, Encoder};
+mod gate;
+use crate::gate::{GateOutputs, Trigger};
+#[cfg(any(test, feature = "matrix"))]
+mod matrix;
+#[cfg(feature = "matrix")]
+use crate::matrix::{ButtonMatrix, MatrixKeys};
 mod midi;
+use crate::midi::{MidiEncoder, MidiMessage, MidiParser, ParameterDecoder, ParameterKind, ParameterMessage};
+mod scale;
+mod shiftreg;
+#[cfg(feature = "shiftreg")]
+use crate::shiftreg::{BitBangSpi, InputChain, InputRegister, NoPin, OutputChain, OutputRegister};
+mod txqueue;
+use crate::txqueue::TxQueue;
+use crate::scale::ScaleSettings;
 
-use core::cell::Cell;
+use core::cell::{Cell, RefCell};
 
 use bsp::hal;
 use grand_central_m4 as bsp;
 
-#[cfg(not(feature = "use_semihosting"))]
+#[cfg(all(not(test), not(feature = "use_semihosting")))]
 use panic_halt as _;
-#[cfg(feature = "use_semihosting")]
+#[cfg(all(not(test), feature = "use_semihosting"))]
 use panic_semihosting as _;
 
 use bsp::entry;
 use cortex_m::interrupt as cortex_interrupt;
 use cortex_m::interrupt::Mutex;
 use cortex_m::peripheral::NVIC;
-use hal::clock::GenericClockController;
+use hal::clock::{ClockGenId, ClockSource, GenericClockController};
 use hal::delay::Delay;
-use hal::pac::{CorePeripherals, interrupt, Peripherals, TC3};
+use hal::gpio::v2::{DynPin, Pin as PinV2, PullUpInput, PushPullOutput};
+use hal::pac::{CorePeripherals, interrupt, Peripherals, SERCOM0, TC3};
 use hal::prelude::*;
 use hal::sercom::v2::spi::MODE_0;
 use hal::sercom::v2::{IoSet2, Sercom0, uart::{self, EightBit, Parity, StopBits}};
@@ -35,11 +58,59 @@ const NUM_LEDS: usize = 16;
 const BUF_SIZE: usize = 4 + (NUM_LEDS * 4) + ((NUM_LEDS + 1) / 2);
 const NUM_STEPS: usize = 32;   // max number of steps per pattern
 const NUM_CHANNELS: usize = 8; // number of pattern channels
+const DEFAULT_BPM: u32 = 120;
+const MIN_BPM: u32 = 30;
+const MAX_BPM: u32 = 300;
 const US_PER_UPDATE_LEDS: u32 = 10000;  // microseconds between LED updates
+const US_OVERFLOW_FLASH: u32 = 100000;  // the red LED flips this long when MIDI output is dropped
+#[cfg(feature = "matrix")]
+const US_PER_MATRIX_ROW: u32 = 250;     // microseconds between matrix row scans
+#[cfg(feature = "matrix")]
+const MATRIX_DIODES: bool = true;       // set to false for a matrix without a diode per key
+#[cfg(all(feature = "matrix", feature = "shiftreg"))]
+compile_error!("the matrix and shiftreg features both replace the step and channel buttons, enable one of them");
 const CHANNEL_NOTES: [u8; NUM_CHANNELS] = [36, 37, 38, 39, 40, 41, 42, 43];
 const VELOCITY_LEVELS: [u8; 4] = [31, 63, 95, 127];
+const MAX_OCTAVE: u8 = 9;
+const DEFAULT_OCTAVE: u8 = 3;     // octave 3 with the root at C plays note 36
+const TRANSPOSE_CENTER: u8 = 60;  // incoming note that leaves melodic channels untransposed
+const TRANSPOSE_CHANNEL: u8 = 1;  // MIDI input channel for transposing melodic channels
+const TRIGGER_CHANNEL: u8 = 10;   // MIDI input channel for playing channels with CHANNEL_NOTES
+const SYSEX_SIZE: usize = 64;     // longest SysEx message kept from the MIDI input
+// gate/trigger outputs on d2, d3 and d26-d31, one per channel (d24/d25 are the USB pins)
+const TRIGGER_ENABLED: [bool; NUM_CHANNELS] = [true; NUM_CHANNELS];
+const TRIGGER_PULSE_US: [u32; NUM_CHANNELS] = [5000; NUM_CHANNELS];
+const CLOCK_PULSE_US: u32 = 5000;  // clock out on d48, one pulse per step
+const RESET_PULSE_US: u32 = 5000;  // reset out on d49, pulses when playback starts
+const CV_CHANNEL: usize = 0;       // channel that drives the CV output on A0
+const CV_MODE: CvMode = CvMode::Pitch;
+// NRPN locked per step on each channel, sent on MIDI channel 1 before the note on.
+// set these to parameters of the receiving synth, e.g. the tune or decay of each drum voice
+const LOCK_PARAMETERS: [u16; NUM_CHANNELS] = [0, 1, 2, 3, 4, 5, 6, 7];
+const LOCK_DEFAULT: u16 = 8192;   // value of unlocked steps until one arrives on the MIDI input
+// LED colors for enabled melodic steps, indexed by octave
+const OCTAVE_COLORS: [Pixel; 10] = [
+    Pixel { r: 63, g:  0, b: 63 },
+    Pixel { r: 31, g:  0, b: 63 },
+    Pixel { r:  0, g:  0, b: 63 },
+    Pixel { r:  0, g: 31, b: 63 },
+    Pixel { r:  0, g: 63, b: 31 },
+    Pixel { r:  0, g: 63, b:  0 },
+    Pixel { r: 31, g: 63, b:  0 },
+    Pixel { r: 63, g: 63, b:  0 },
+    Pixel { r: 63, g: 31, b:  0 },
+    Pixel { r: 63, g:  0, b:  0 },
+];
+
+const TX_QUEUE_SIZE: usize = 256;      // bytes of MIDI output waiting for the UART
 
 static MICROS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+// scanned from the TC3 interrupt, one row every US_PER_MATRIX_ROW
+#[cfg(feature = "matrix")]
+static MATRIX: Mutex<RefCell<Option<ButtonMatrix<DynPin, DynPin, 4, 8>>>> = Mutex::new(RefCell::new(None));
+// MIDI output is queued here and sent by the SERCOM0 data register empty interrupt,
+// so the main loop doesn't wait about 1ms for each message at 31250 baud
+static MIDI_TX: Mutex<RefCell<TxQueue<TX_QUEUE_SIZE>>> = Mutex::new(RefCell::new(TxQueue::new()));
 
 #[derive(Debug, Copy, Clone)]
 struct Pixel {
@@ -96,14 +167,55 @@ impl Leds {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ChannelMode {
+    Drum,     // plays the fixed note from CHANNEL_NOTES
+    Melodic,  // plays each step's own note, quantized to the selected scale
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct Step {
     gate: bool,
     vel: u8,
+    note: u8,       // semitone above the root, 0..11
+    octave: u8,
+    sent_note: u8,  // note number used for the last note on, so the note off matches it
     start: f32,
     duration: f32,
     note_on_sent: bool,
     note_off_sent: bool,
+    lock: Option<u16>,  // parameter lock, the channel's LOCK_PARAMETERS value for this step
+}
+
+impl Step {
+    // step through VELOCITY_LEVELS, used by the encoder
+    fn velocity_offset(&mut self, offset: i32) {
+        let index = VELOCITY_LEVELS.iter().position(|v| *v == self.vel).unwrap_or(VELOCITY_LEVELS.len() - 1) as i32;
+        let index = (index + offset).max(0).min(VELOCITY_LEVELS.len() as i32 - 1);
+        self.vel = VELOCITY_LEVELS[index as usize];
+    }
+    
+    fn note_up(&mut self, scale: &scale::Scale) {
+        let (note, carry) = scale.step_up(self.note);
+        if carry > 0 && self.octave >= MAX_OCTAVE { return }
+        self.note = note;
+        self.octave = ((self.octave as i8) + carry) as u8;
+    }
+    
+    fn note_down(&mut self, scale: &scale::Scale) {
+        let (note, carry) = scale.step_down(self.note);
+        if carry < 0 && self.octave == 0 { return }
+        self.note = note;
+        self.octave = ((self.octave as i8) + carry) as u8;
+    }
+    
+    fn octave_up(&mut self) {
+        self.octave = (self.octave + 1).min(MAX_OCTAVE);
+    }
+    
+    fn octave_down(&mut self) {
+        self.octave = self.octave.saturating_sub(1);
+    }
 }
 
 pub struct StepTimer {
@@ -115,6 +227,50 @@ pub struct StepTimer {
     run_state: bool,
 }
 
+// quantize a recorded velocity to the nearest level that the step buttons can enter
+fn nearest_velocity(velocity: u8) -> u8 {
+    let mut out = VELOCITY_LEVELS[0];
+    for level in VELOCITY_LEVELS.iter() {
+        if (*level as i16 - velocity as i16).abs() < (out as i16 - velocity as i16).abs() { out = *level }
+    }
+    out
+}
+
+// the channel when an incoming parameter is one of LOCK_PARAMETERS
+fn lock_index(message: &ParameterMessage) -> Option<usize> {
+    let (parameter, kind) = match *message {
+        ParameterMessage::Parameter { parameter, kind, .. } => (parameter, kind),
+        ParameterMessage::Increment { parameter, kind, .. } => (parameter, kind),
+        ParameterMessage::Decrement { parameter, kind, .. } => (parameter, kind),
+        ParameterMessage::Controller14 { .. } => return None,
+    };
+    if kind != ParameterKind::Nrpn { return None }
+    LOCK_PARAMETERS.iter().position(|p| *p == parameter)
+}
+
+// the new value of a lock parameter, increment and decrement move from current
+fn lock_value(message: &ParameterMessage, current: u16) -> u16 {
+    match *message {
+        ParameterMessage::Increment { amount, .. } => current.saturating_add(amount as u16).min(16383),
+        ParameterMessage::Decrement { amount, .. } => current.saturating_sub(amount as u16),
+        ParameterMessage::Parameter { value, .. } => value,
+        ParameterMessage::Controller14 { .. } => current,
+    }
+}
+
+// write a played trigger into the nearest step of a channel. the step is marked as sent
+// so the sequencer doesn't repeat the note that was just played
+fn record_trigger(channel: &mut [Step; NUM_STEPS], step_timer: &StepTimer, velocity: u8) {
+    let mut index = step_timer.get_step();
+    if step_timer.get_step_progress() >= 0.5 {
+        index = (index + 1) % step_timer.num_steps;
+    }
+    channel[index].gate = true;
+    channel[index].vel = nearest_velocity(velocity);
+    channel[index].note_on_sent = true;
+    channel[index].note_off_sent = true;
+}
+
 impl StepTimer {
     fn new(num_steps: usize, time_per_step: u32) -> Self {
         StepTimer {
@@ -137,6 +293,21 @@ impl StepTimer {
         self.run_state = false;
     }
     
+    // jump back to the first step without stopping
+    fn restart(&mut self, time: u32, steps: &mut [[Step; NUM_STEPS]; NUM_CHANNELS]) {
+        for channel in steps.iter_mut() {
+            channel[self.step].note_on_sent = false;
+            channel[self.step].note_off_sent = false;
+        }
+        self.step = 0;
+        self.step_progress = 0.0;
+        self.start(time);
+    }
+    
+    fn set_time_per_step(&mut self, time_per_step: u32) {
+        self.time_per_step = time_per_step;
+    }
+    
     fn get_step(&self) -> usize {
         self.step
     }
@@ -149,7 +320,9 @@ impl StepTimer {
         self.run_state
     }
     
-    fn poll(&mut self, time: u32, steps: &mut [[Step; NUM_STEPS]; NUM_CHANNELS]) {
+    // returns true when the timer moves to the next step
+    fn poll(&mut self, time: u32, steps: &mut [[Step; NUM_STEPS]; NUM_CHANNELS]) -> bool {
+        let mut advanced = false;
         if self.run_state == true {
             self.step_progress = (time.wrapping_sub(self.last_step_time) as f32) / (self.time_per_step as f32);
             
@@ -163,12 +336,14 @@ impl StepTimer {
                 self.step_progress = 0.0;
                 //~ self.last_step_time = self.last_step_time.wrapping_add(self.time_per_step);
                 self.last_step_time = time;
+                advanced = true;
             }
         }
+        advanced
     }
 }
 
-#[entry]
+#[cfg_attr(not(test), entry)]
 fn main() -> ! {
     let mut peripherals = Peripherals::take().unwrap();
     let mut core = CorePeripherals::take().unwrap();
@@ -215,66 +390,177 @@ fn main() -> ! {
         //~ .parity(Parity::None)
         //~ .stop_bits(StopBits::OneBit)
         .enable();
+    unsafe {
+        core.NVIC.set_priority(interrupt::SERCOM0_0, 2);
+        NVIC::unmask(interrupt::SERCOM0_0);
+    }
+    let mut midi_parser = MidiParser::<SYSEX_SIZE>::new();
+    // only (N)RPN are collected from the input, no controllers are 14-bit pairs
+    let mut parameter_decoder = ParameterDecoder::new(0);
+    // running status is safe to use since input messages are merged through the same encoder
+    let mut midi_out = MidiEncoder::new(true);
 
     let mut leds = Leds::new();
     leds.fill_buffer();
     let _ = spi.write(&leds.buffer[..]);
     
     let mut last_update_leds: u32 = 0;
+    let mut last_overflows: u32 = 0;
+    let mut overflow_flash: Option<u32> = None;
 
-    let mut step_buttons = StepButtonSet { 
-        b00: Button::new(pins.d32.into_pull_up_input(&mut pins.port).into(), 1000),
-        b01: Button::new(pins.d33.into_pull_up_input(&mut pins.port).into(), 1000),
-        b02: Button::new(pins.d34.into_pull_up_input(&mut pins.port).into(), 1000),
-        b03: Button::new(pins.d35.into_pull_up_input(&mut pins.port).into(), 1000),
-        b04: Button::new(pins.d36.into_pull_up_input(&mut pins.port).into(), 1000),
-        b05: Button::new(pins.d37.into_pull_up_input(&mut pins.port).into(), 1000),
-        b06: Button::new(pins.d38.into_pull_up_input(&mut pins.port).into(), 1000),
-        b07: Button::new(pins.d39.into_pull_up_input(&mut pins.port).into(), 1000),
-        b08: Button::new(pins.d40.into_pull_up_input(&mut pins.port).into(), 1000),
-        b09: Button::new(pins.d41.into_pull_up_input(&mut pins.port).into(), 1000),
-        b10: Button::new(pins.d42.into_pull_up_input(&mut pins.port).into(), 1000),
-        b11: Button::new(pins.d43.into_pull_up_input(&mut pins.port).into(), 1000),
-        b12: Button::new(pins.d44.into_pull_up_input(&mut pins.port).into(), 1000),
-        b13: Button::new(pins.d45.into_pull_up_input(&mut pins.port).into(), 1000),
-        b14: Button::new(pins.d46.into_pull_up_input(&mut pins.port).into(), 1000),
-        b15: Button::new(pins.d47.into_pull_up_input(&mut pins.port).into(), 1000),
-        
-        v00: Button::new(pins.d4.into_pull_up_input(&mut pins.port).into(), 1000),
-        v01: Button::new(pins.d5.into_pull_up_input(&mut pins.port).into(), 1000),
-        v02: Button::new(pins.d6.into_pull_up_input(&mut pins.port).into(), 1000),
-        v03: Button::new(pins.d7.into_pull_up_input(&mut pins.port).into(), 1000),
-    };
+    #[cfg(not(any(feature = "matrix", feature = "shiftreg")))]
+    let mut step_buttons = ButtonSet::new([
+        input_pin(pins.d32.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.d33.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.d34.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.d35.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.d36.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.d37.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.d38.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.d39.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.d40.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.d41.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.d42.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.d43.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.d44.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.d45.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.d46.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.d47.into_pull_up_input(&mut pins.port).into()),
+    ], 1000);
+    
+    // with the matrix feature, the step and channel buttons are a 4x8 matrix on rows d32-d35
+    // and columns d36-d43. keys 0-15 are steps, 16-23 are channels and 24-31 are unused
+    #[cfg(feature = "matrix")]
+    let matrix = ButtonMatrix::<_, _, 4, 8>::new(
+        [
+            output_pin(pins.d32.into_push_pull_output(&mut pins.port).into()),
+            output_pin(pins.d33.into_push_pull_output(&mut pins.port).into()),
+            output_pin(pins.d34.into_push_pull_output(&mut pins.port).into()),
+            output_pin(pins.d35.into_push_pull_output(&mut pins.port).into()),
+        ],
+        [
+            input_pin(pins.d36.into_pull_up_input(&mut pins.port).into()),
+            input_pin(pins.d37.into_pull_up_input(&mut pins.port).into()),
+            input_pin(pins.d38.into_pull_up_input(&mut pins.port).into()),
+            input_pin(pins.d39.into_pull_up_input(&mut pins.port).into()),
+            input_pin(pins.d40.into_pull_up_input(&mut pins.port).into()),
+            input_pin(pins.d41.into_pull_up_input(&mut pins.port).into()),
+            input_pin(pins.d42.into_pull_up_input(&mut pins.port).into()),
+            input_pin(pins.d43.into_pull_up_input(&mut pins.port).into()),
+        ],
+        1000,
+        MATRIX_DIODES,
+    );
+    #[cfg(feature = "matrix")]
+    cortex_interrupt::free(|cs| MATRIX.borrow(cs).replace(Some(matrix)));
+    #[cfg(feature = "matrix")]
+    let mut matrix_keys = MatrixKeys::<4, 8>::new();
+    
+    // with the shiftreg feature, the step and channel buttons are read from three 74HC165s
+    // (CLK d32, SH/LD d33, QH d34), inputs 0-15 are steps and 16-23 are channels. a 74HC595
+    // (SRCLK d35, RCLK d36, SER d37) lights Q0-Q7 for the selected channel
+    #[cfg(feature = "shiftreg")]
+    let mut button_chain = InputChain::<_, _, 3>::new(
+        BitBangSpi::new(
+            output_pin(pins.d32.into_push_pull_output(&mut pins.port).into()),
+            NoPin,
+            input_pin(pins.d34.into_pull_up_input(&mut pins.port).into()),
+        ),
+        output_pin(pins.d33.into_push_pull_output(&mut pins.port).into()),
+    );
+    #[cfg(feature = "shiftreg")]
+    let mut shift_buttons = ShiftButtons::<24>::new(1000, true);
+    #[cfg(feature = "shiftreg")]
+    let mut channel_leds = OutputChain::<_, _, 1>::new(
+        BitBangSpi::new(
+            output_pin(pins.d35.into_push_pull_output(&mut pins.port).into()),
+            output_pin(pins.d37.into_push_pull_output(&mut pins.port).into()),
+            NoPin,
+        ),
+        output_pin(pins.d36.into_push_pull_output(&mut pins.port).into()),
+    );
+    #[cfg(feature = "shiftreg")]
+    let mut shown_channel: Option<usize> = None;
+    
+    let mut velocity_buttons = ButtonSet::new([
+        input_pin(pins.d4.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.d5.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.d6.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.d7.into_pull_up_input(&mut pins.port).into()),
+    ], 1000);
     
-    let mut channel_buttons = ChannelButtonSet { 
-        p00: Button::new(pins.uart3_tx.into_pull_up_input(&mut pins.port).into(), 1000),
-        p01: Button::new(pins.uart3_rx.into_pull_up_input(&mut pins.port).into(), 1000),
-        p02: Button::new(pins.uart2_tx.into_pull_up_input(&mut pins.port).into(), 1000),
-        p03: Button::new(pins.uart2_rx.into_pull_up_input(&mut pins.port).into(), 1000),
-        p04: Button::new(pins.uart1_tx.into_pull_up_input(&mut pins.port).into(), 1000),
-        p05: Button::new(pins.uart1_rx.into_pull_up_input(&mut pins.port).into(), 1000),
-        p06: Button::new(pins.d22.into_pull_up_input(&mut pins.port).into(), 1000),
-        p07: Button::new(pins.d23.into_pull_up_input(&mut pins.port).into(), 1000),
+    #[cfg(not(any(feature = "matrix", feature = "shiftreg")))]
+    let mut channel_buttons = ButtonSet::new([
+        input_pin(pins.uart3_tx.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.uart3_rx.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.uart2_tx.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.uart2_rx.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.uart1_tx.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.uart1_rx.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.d22.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.d23.into_pull_up_input(&mut pins.port).into()),
+    ], 1000);
+    
+    let mut gates = GateOutputs {
+        channels: [
+            Trigger::new(output_pin(pins.d2.into_push_pull_output(&mut pins.port).into()), TRIGGER_ENABLED[0], TRIGGER_PULSE_US[0]),
+            Trigger::new(output_pin(pins.d3.into_push_pull_output(&mut pins.port).into()), TRIGGER_ENABLED[1], TRIGGER_PULSE_US[1]),
+            Trigger::new(output_pin(pins.d26.into_push_pull_output(&mut pins.port).into()), TRIGGER_ENABLED[2], TRIGGER_PULSE_US[2]),
+            Trigger::new(output_pin(pins.d27.into_push_pull_output(&mut pins.port).into()), TRIGGER_ENABLED[3], TRIGGER_PULSE_US[3]),
+            Trigger::new(output_pin(pins.d28.into_push_pull_output(&mut pins.port).into()), TRIGGER_ENABLED[4], TRIGGER_PULSE_US[4]),
+            Trigger::new(output_pin(pins.d29.into_push_pull_output(&mut pins.port).into()), TRIGGER_ENABLED[5], TRIGGER_PULSE_US[5]),
+            Trigger::new(output_pin(pins.d30.into_push_pull_output(&mut pins.port).into()), TRIGGER_ENABLED[6], TRIGGER_PULSE_US[6]),
+            Trigger::new(output_pin(pins.d31.into_push_pull_output(&mut pins.port).into()), TRIGGER_ENABLED[7], TRIGGER_PULSE_US[7]),
+        ],
+        clock: Trigger::new(output_pin(pins.d48.into_push_pull_output(&mut pins.port).into()), true, CLOCK_PULSE_US),
+        reset: Trigger::new(output_pin(pins.d49.into_push_pull_output(&mut pins.port).into()), true, RESET_PULSE_US),
     };
     
-    let mut start_button = Button::new(pins.d12.into_pull_up_input(&mut pins.port).into(), 1000);
-    let mut stop_button = Button::new(pins.d11.into_pull_up_input(&mut pins.port).into(), 1000);
+    // the DAC runs from GCLK4 at 12MHz (48MHz DFLL / 4)
+    let gclk4 = clocks.configure_gclk_divider_and_source(ClockGenId::GCLK4, 4, ClockSource::DFLL, false).unwrap();
+    let _dac_clock = clocks.dac(&gclk4).unwrap();
+    let _cv_pin = pins.a0.into_function_b(&mut pins.port);
+    let mut cv_output = CvOutput::new(peripherals.DAC, &mut peripherals.MCLK, CV_MODE);
+    
+    // double pressing start restarts playback from the first step
+    let start_timing = EventTiming { long_press: 0, double_press: 400000, repeat_delay: 0, repeat_rate: 0 };
+    // turning the encoder changes the tempo, or edits the held steps
+    let mut encoder = Encoder::new(
+        input_pin(pins.d8.into_pull_up_input(&mut pins.port).into()),
+        input_pin(pins.d9.into_pull_up_input(&mut pins.port).into()),
+    ).with_acceleration(Acceleration { threshold: 40000, max_multiplier: 10 });
+    let mut bpm = DEFAULT_BPM;
+    
+    let mut start_button = Button::new(input_pin(pins.d12.into_pull_up_input(&mut pins.port).into()), 1000).with_events(start_timing);
+    let mut stop_button = Button::new(input_pin(pins.d11.into_pull_up_input(&mut pins.port).into()), 1000);
     
     //~ let mut steps = [false; NUM_STEPS];
     let init_step = Step { 
         gate: false,
         vel: 127,
+        note: 0,
+        octave: DEFAULT_OCTAVE,
+        sent_note: 0,
         start: 0.0,
         duration: 0.25,
         note_on_sent: false,
         note_off_sent: false,
+        lock: None,
     };
     let mut steps = [[init_step; NUM_STEPS]; NUM_CHANNELS];
     
     let mut step_page: usize = 0;    // which set of 16 steps to display on LEDs
     let mut step_channel: usize = 0; // which channel or row is displayed
+    let mut channel_modes = [ChannelMode::Drum; NUM_CHANNELS];
+    let mut scale_settings = ScaleSettings::new();
+    let mut shift_used = false;      // set when the stop button is held as shift for another button
+    let mut record_enabled = false;  // triggers from the MIDI input are written into the steps
+    let mut thru_enabled = false;    // unused MIDI input messages are merged into the output
+    let mut trigger_notes: [Option<u8>; NUM_CHANNELS] = [None; NUM_CHANNELS];
+    let mut lock_values = [LOCK_DEFAULT; NUM_CHANNELS];  // LOCK_PARAMETERS value of unlocked steps
+    let mut lock_sent = [false; NUM_CHANNELS];           // the last value sent was a step's lock
     
-    let mut step_timer = StepTimer::new(NUM_STEPS, 125000);
+    let mut step_timer = StepTimer::new(NUM_STEPS, bpm_to_step_time(bpm));
     //~ step_timer.start(micros);
     
     // 16th/step -- 1.0 / (tempo / 60.0) / 4.0 * 1,000,000
@@ -285,33 +571,236 @@ fn main() -> ! {
             micros = MICROS.borrow(cs).get();
         });
         
+        while let Ok(byte) = uart.read() {
+            let message = match midi_parser.push(byte) {
+                Some(v) => v,
+                None => continue,
+            };
+            let mut consumed = false;
+            
+            // NRPNs for LOCK_PARAMETERS are locked to the playing step while recording,
+            // otherwise they set the value of the unlocked steps
+            if let Some(parameter) = parameter_decoder.push(&message) {
+                if parameter.channel() == TRIGGER_CHANNEL {
+                    if let Some(index) = lock_index(&parameter) {
+                        let step = &mut steps[index][step_timer.get_step()];
+                        if record_enabled == true && step_timer.get_run_state() == true && step.gate == true {
+                            // a step that is already locked moves from its own value
+                            let current = step.lock.unwrap_or(lock_values[index]);
+                            step.lock = Some(lock_value(&parameter, current));
+                        } else {
+                            lock_values[index] = lock_value(&parameter, lock_values[index]);
+                        }
+                    }
+                }
+            }
+            
+            if let MidiMessage::NoteOn { channel, note, velocity } = message {
+                // incoming note ons transpose the melodic channels relative to TRANSPOSE_CENTER
+                if channel == TRANSPOSE_CHANNEL {
+                    scale_settings.transpose = (note as i8) - (TRANSPOSE_CENTER as i8);
+                }
+                // notes matching CHANNEL_NOTES play that channel, and are recorded when enabled
+                if channel == TRIGGER_CHANNEL {
+                    if let Some(index) = CHANNEL_NOTES.iter().position(|n| *n == note) {
+                        let out_note = match channel_modes[index] {
+                            ChannelMode::Drum => CHANNEL_NOTES[index],
+                            ChannelMode::Melodic => scale_settings.note(0, DEFAULT_OCTAVE),
+                        };
+                        if let Some(last_note) = trigger_notes[index] {
+                            send_midi(&mut midi_out, &MidiMessage::NoteOn { channel: 1, note: last_note, velocity: 0 });
+                        }
+                        send_midi(&mut midi_out, &MidiMessage::NoteOn { channel: 1, note: out_note, velocity: velocity });
+                        trigger_notes[index] = Some(out_note);
+                        gates.fire_channel(index, micros);
+                        if index == CV_CHANNEL {
+                            if cv_output.mode() == CvMode::Velocity || channel_modes[index] == ChannelMode::Melodic {
+                                cv_output.set_note(out_note, velocity);
+                            }
+                        }
+                        
+                        if record_enabled == true && step_timer.get_run_state() == true {
+                            record_trigger(&mut steps[index], &step_timer, velocity);
+                        }
+                        consumed = true;
+                    }
+                }
+            }
+            
+            if let MidiMessage::NoteOff { channel, note, .. } = message {
+                if channel == TRIGGER_CHANNEL {
+                    if let Some(index) = CHANNEL_NOTES.iter().position(|n| *n == note) {
+                        if let Some(last_note) = trigger_notes[index] {
+                            // a note off that didn't fit in the queue is sent again by the next note on
+                            if send_midi(&mut midi_out, &MidiMessage::NoteOn { channel: 1, note: last_note, velocity: 0 }) == true {
+                                trigger_notes[index] = None;
+                            }
+                        }
+                        consumed = true;
+                    }
+                }
+            }
+            
+            // only complete messages are forwarded, so they can't split a sequencer message
+            if thru_enabled == true && consumed == false {
+                match message {
+                    MidiMessage::SysEx { truncated: false, .. } => {
+                        send_midi_sysex(&mut midi_out, midi_parser.sysex());
+                    }
+                    MidiMessage::SysEx { truncated: true, .. } => {}
+                    _ => {
+                        send_midi(&mut midi_out, &message);
+                    }
+                }
+            }
+        }
+        
         let step_offset = step_page * NUM_LEDS;
         
+        #[cfg(not(any(feature = "matrix", feature = "shiftreg")))]
         step_buttons.poll(micros);
-        step_buttons.update_steps(&mut steps, step_offset, step_channel);
-        
+        #[cfg(not(any(feature = "matrix", feature = "shiftreg")))]
         channel_buttons.poll(micros);
-        channel_buttons.update_channel(&mut step_channel);
+        #[cfg(feature = "matrix")]
+        cortex_interrupt::free(|cs| {
+            if let Some(matrix) = MATRIX.borrow(cs).borrow().as_ref() {
+                matrix_keys.update(matrix.states());
+            }
+        });
+        #[cfg(feature = "matrix")]
+        let step_buttons = matrix_keys.slice(0, 16);
+        #[cfg(feature = "matrix")]
+        let channel_buttons = matrix_keys.slice(16, NUM_CHANNELS);
+        #[cfg(feature = "shiftreg")]
+        if button_chain.read().is_ok() {
+            shift_buttons.update(&button_chain, micros);
+        }
+        #[cfg(feature = "shiftreg")]
+        let step_buttons = shift_buttons.slice(0, 16);
+        #[cfg(feature = "shiftreg")]
+        let channel_buttons = shift_buttons.slice(16, NUM_CHANNELS);
+        velocity_buttons.poll(micros);
+        encoder.poll(micros);
         
+        let delta = encoder.take_delta();
+        if delta != 0 {
+            if step_buttons.any_held() == true {
+                // held steps: velocity on drum channels, pitch on melodic channels
+                for index in step_buttons.held() {
+                    let step = &mut steps[step_channel][step_offset + index];
+                    match channel_modes[step_channel] {
+                        ChannelMode::Drum => step.velocity_offset(delta),
+                        ChannelMode::Melodic => {
+                            for _ in 0..delta.abs() {
+                                if delta > 0 {
+                                    step.note_up(&scale_settings.scale);
+                                } else {
+                                    step.note_down(&scale_settings.scale);
+                                }
+                            }
+                        }
+                    }
+                }
+            } else {
+                bpm = ((bpm as i32) + delta).max(MIN_BPM as i32).min(MAX_BPM as i32) as u32;
+                step_timer.set_time_per_step(bpm_to_step_time(bpm));
+            }
+        }
         start_button.poll(micros);
+        stop_button.poll(micros);
+        
+        if stop_button.state() == true {
+            // stop is held as shift:
+            // shift + channel button  = toggle melodic mode for that channel
+            // shift + step 0..11      = set the scale root
+            // shift + step 12/13      = previous/next scale
+            // shift + step 14         = toggle recording of MIDI input triggers
+            // shift + step 15         = toggle MIDI thru
+            if stop_button.rising_edge() == true { shift_used = false }
+            
+            for index in channel_buttons.rising_edges() {
+                channel_modes[index] = match channel_modes[index] {
+                    ChannelMode::Drum => ChannelMode::Melodic,
+                    ChannelMode::Melodic => ChannelMode::Drum,
+                };
+                shift_used = true;
+            }
+            for index in step_buttons.rising_edges() {
+                match index {
+                    0..=11 => { scale_settings.root = index as u8 }
+                    12 => { scale_settings.scale = scale_settings.scale.prev() }
+                    13 => { scale_settings.scale = scale_settings.scale.next() }
+                    14 => { record_enabled = !record_enabled }
+                    15 => { thru_enabled = !thru_enabled }
+                    _ => {}
+                }
+                shift_used = true;
+            }
+        } else {
+            buttonset::update_steps(&step_buttons, &velocity_buttons, &mut steps, step_offset, step_channel, channel_modes[step_channel], &scale_settings.scale);
+            buttonset::update_channel(&channel_buttons, &mut step_channel);
+        }
+        #[cfg(feature = "shiftreg")]
+        if shown_channel != Some(step_channel) {
+            for index in 0..NUM_CHANNELS {
+                channel_leds.set_output(index, index == step_channel);
+            }
+            if channel_leds.write().is_ok() {
+                shown_channel = Some(step_channel);
+            }
+        }
+        
         if start_button.rising_edge() == true {
             step_timer.start(micros);
+            gates.reset.fire(micros);
+            gates.clock.fire(micros);
         }
-        stop_button.poll(micros);
-        if stop_button.rising_edge() == true {
+        while let Some(event) = start_button.next_event() {
+            if event == ButtonEvent::DoublePress {
+                let current_step = step_timer.get_step();
+                for channel in steps.iter_mut() {
+                    if channel[current_step].note_on_sent == true && channel[current_step].note_off_sent == false {
+                        send_midi(&mut midi_out, &MidiMessage::NoteOn { channel: 1, note: channel[current_step].sent_note, velocity: 0 });
+                    }
+                }
+                step_timer.restart(micros, &mut steps);
+                gates.reset.fire(micros);
+            }
+        }
+        // the red LED shows that recording is armed, and flips briefly when the MIDI output
+        // queue was full and a message was dropped
+        let overflows = cortex_interrupt::free(|cs| MIDI_TX.borrow(cs).borrow().overflows());
+        if overflows != last_overflows {
+            last_overflows = overflows;
+            overflow_flash = Some(micros);
+        }
+        if let Some(start) = overflow_flash {
+            if micros.wrapping_sub(start) >= US_OVERFLOW_FLASH { overflow_flash = None }
+        }
+        if record_enabled != overflow_flash.is_some() {
+            let _ = red_led.set_high();
+        } else {
+            let _ = red_led.set_low();
+        }
+        // stop acts on release so that the button can double as shift
+        if stop_button.falling_edge() == true && shift_used == false {
             step_timer.stop();
             
+        }
+        
+        if step_timer.get_run_state() == false {
             let current_step = step_timer.get_step();
-            // send a note off on all channels' active steps to stop hung notes
-            for (index, channel) in steps.iter_mut().enumerate() {
+            // send a note off on all channels' active steps to stop hung notes, until the queue takes them
+            for channel in steps.iter_mut() {
                 if channel[current_step].note_on_sent == true && channel[current_step].note_off_sent == false {
-                    midi::note_on(&mut uart, 1, CHANNEL_NOTES[index], 0);
-                    channel[current_step].note_off_sent = true;
+                    channel[current_step].note_off_sent = send_midi(&mut midi_out, &MidiMessage::NoteOn { channel: 1, note: channel[current_step].sent_note, velocity: 0 });
                 }
-            }    
+            }
         }
         
-        step_timer.poll(micros, &mut steps);
+        if step_timer.poll(micros, &mut steps) == true {
+            gates.clock.fire(micros);
+        }
         
         if step_timer.get_run_state() == true {
             let current_step = step_timer.get_step();
@@ -320,15 +809,38 @@ fn main() -> ! {
             for (index, channel) in steps.iter_mut().enumerate() {
                 if channel[current_step].gate == true {
                     if current_progress >= channel[current_step].start && channel[current_step].note_on_sent == false {
+                        let note = match channel_modes[index] {
+                            ChannelMode::Drum => CHANNEL_NOTES[index],
+                            ChannelMode::Melodic => scale_settings.note(channel[current_step].note, channel[current_step].octave),
+                        };
+                        // a locked step sends its value first, the next unlocked step puts the channel's value back
+                        let lock = match channel[current_step].lock {
+                            Some(value) => Some(value),
+                            None if lock_sent[index] == true => Some(lock_values[index]),
+                            None => None,
+                        };
+                        if let Some(value) = lock {
+                            let message = ParameterMessage::Parameter { channel: 1, kind: ParameterKind::Nrpn, parameter: LOCK_PARAMETERS[index], value: value };
+                            if send_midi_parameter(&mut midi_out, &message) == true {
+                                lock_sent[index] = channel[current_step].lock.is_some();
+                            }
+                        }
                         // send midi note on
-                        midi::note_on(&mut uart, 1, CHANNEL_NOTES[index], channel[current_step].vel);
+                        send_midi(&mut midi_out, &MidiMessage::NoteOn { channel: 1, note: note, velocity: channel[current_step].vel });
+                        // gate and CV are set alongside the note on so they share its timing
+                        gates.fire_channel(index, micros);
+                        if index == CV_CHANNEL {
+                            if cv_output.mode() == CvMode::Velocity || channel_modes[index] == ChannelMode::Melodic {
+                                cv_output.set_note(note, channel[current_step].vel);
+                            }
+                        }
+                        channel[current_step].sent_note = note;
                         channel[current_step].note_on_sent = true;
                     }
                     
                     if current_progress >= channel[current_step].duration && channel[current_step].note_off_sent == false {
-                        // send midi note off
-                        midi::note_on(&mut uart, 1, CHANNEL_NOTES[index], 0);
-                        channel[current_step].note_off_sent = true;
+                        // send midi note off, a full queue leaves it to be sent again on the next loop
+                        channel[current_step].note_off_sent = send_midi(&mut midi_out, &MidiMessage::NoteOn { channel: 1, note: channel[current_step].sent_note, velocity: 0 });
                     }
                 }
             }
@@ -341,6 +853,8 @@ fn main() -> ! {
                 if step_timer.get_step() == actual_step && step_timer.get_run_state() == true {
                     leds.set_led(Pixel { r: 95, g: 31, b: 31 }, index);
                 // step is enabled
+                } else if channel_modes[step_channel] == ChannelMode::Melodic {
+                    leds.set_led(OCTAVE_COLORS[step.octave.min(MAX_OCTAVE) as usize], index);
                 } else {
                     //~ leds.set_led(Pixel { r: 63, g: 0, b: 0 }, index);
                     
@@ -360,6 +874,8 @@ fn main() -> ! {
             }
         }
         
+        gates.poll(micros);
+        
         if micros.wrapping_sub(last_update_leds) >= US_PER_UPDATE_LEDS {
             last_update_leds = micros;
             let _ = spi.write(&leds.buffer[..]);
@@ -367,9 +883,71 @@ fn main() -> ! {
     }
 }
 
+// queue a message for the SERCOM0 interrupt and make sure the interrupt is enabled,
+// returns false when the queue was full and the message was dropped
+fn send_midi(encoder: &mut MidiEncoder, message: &MidiMessage) -> bool {
+    let queued = cortex_interrupt::free(|cs| {
+        encoder.queue(&mut MIDI_TX.borrow(cs).borrow_mut(), message)
+    });
+    enable_midi_tx();
+    queued
+}
+
+// ends with the null RPN so data entry from the MIDI thru can't change the parameter
+fn send_midi_parameter(encoder: &mut MidiEncoder, message: &ParameterMessage) -> bool {
+    let queued = cortex_interrupt::free(|cs| {
+        encoder.queue_parameter(&mut MIDI_TX.borrow(cs).borrow_mut(), message, true)
+    });
+    enable_midi_tx();
+    queued
+}
+
+fn send_midi_sysex(encoder: &mut MidiEncoder, data: &[u8]) -> bool {
+    let queued = cortex_interrupt::free(|cs| {
+        encoder.queue_sysex(&mut MIDI_TX.borrow(cs).borrow_mut(), data)
+    });
+    enable_midi_tx();
+    queued
+}
+
+fn enable_midi_tx() {
+    unsafe {
+        SERCOM0::ptr()
+            .as_ref()
+            .unwrap()
+            .usart_int()
+            .intenset
+            .write(|w| w.dre().set_bit());
+    }
+}
+
+// microseconds per 16th note step
+fn bpm_to_step_time(bpm: u32) -> u32 {
+    60_000_000 / bpm.max(1) / 4
+}
+
+// type-erase an input pin so buttons can be kept in a ButtonSet
+fn input_pin<I: hal::gpio::v2::PinId>(pin: PinV2<I, PullUpInput>) -> DynPin {
+    pin.into()
+}
+
+// type-erase an output pin so the trigger outputs can be kept in an array
+fn output_pin<I: hal::gpio::v2::PinId>(pin: PinV2<I, PushPullOutput>) -> DynPin {
+    pin.into()
+}
+
 #[interrupt]
 fn TC3() {
-    cortex_interrupt::free(|cs| MICROS.borrow(cs).set(MICROS.borrow(cs).get().wrapping_add(1)));
+    cortex_interrupt::free(|cs| {
+        let micros = MICROS.borrow(cs).get().wrapping_add(1);
+        MICROS.borrow(cs).set(micros);
+        #[cfg(feature = "matrix")]
+        if micros % US_PER_MATRIX_ROW == 0 {
+            if let Some(matrix) = MATRIX.borrow(cs).borrow_mut().as_mut() {
+                matrix.tick(micros);
+            }
+        }
+    });
     unsafe {
         TC3::ptr()
             .as_ref()
@@ -379,3 +957,15 @@ fn TC3() {
             .modify(|_, w| w.ovf().set_bit());
         }
 }
+
+#[interrupt]
+fn SERCOM0_0() {
+    let usart = unsafe { SERCOM0::ptr().as_ref().unwrap().usart_int() };
+    cortex_interrupt::free(|cs| {
+        match MIDI_TX.borrow(cs).borrow_mut().pop() {
+            Some(byte) => usart.data.write(|w| unsafe { w.data().bits(byte as u32) }),
+            // nothing left to send, the interrupt is enabled again by the next send_midi
+            None => usart.intenclr.write(|w| w.dre().set_bit()),
+        }
+    });
+}
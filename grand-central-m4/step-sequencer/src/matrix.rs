@@ -0,0 +1,345 @@
+use crate::button::Debouncer;
+use crate::buttonset::ButtonGroup;
+use crate::hal::ehal::digital::v2::{InputPin, OutputPin};
+
+// a row/column key matrix. rows are outputs driven low one at a time, columns are
+// pull-up inputs. it is scanned from the timer interrupt so a slow pass of the main loop
+// doesn't stretch the debounce timing, and each key has its own debouncer. the main loop
+// reads the debounced keys through MatrixKeys
+pub struct ButtonMatrix<R: OutputPin, C: InputPin, const ROWS: usize, const COLS: usize> {
+    rows: [R; ROWS],
+    cols: [C; COLS],
+    keys: [[Debouncer; COLS]; ROWS],
+    raw: [[bool; COLS]; ROWS],      // undebounced key states for the current scan
+    diodes: bool,                   // with a diode per key there are no ghost keys to suppress
+    row: usize,                     // row that is currently driven low
+}
+
+impl<R: OutputPin, C: InputPin, const ROWS: usize, const COLS: usize> ButtonMatrix<R, C, ROWS, COLS> {
+    pub fn new(mut rows: [R; ROWS], cols: [C; COLS], debounce_time: u32, diodes: bool) -> Self {
+        for (index, row) in rows.iter_mut().enumerate() {
+            if index == 0 {
+                let _ = row.set_low();
+            } else {
+                let _ = row.set_high();
+            }
+        }
+        
+        ButtonMatrix {
+            rows: rows,
+            cols: cols,
+            keys: [[(); COLS]; ROWS].map(|r| r.map(|_| Debouncer::new(debounce_time))),
+            raw: [[false; COLS]; ROWS],
+            diodes: diodes,
+            row: 0,
+        }
+    }
+    
+    // called from the timer interrupt once per row interval, which gives the selected row
+    // time to settle before its columns are read. reads the row's columns and moves on to
+    // the next row, after the last row the whole frame is passed to the debouncers
+    pub fn tick(&mut self, time: u32) {
+        for (col, pin) in self.cols.iter().enumerate() {
+            self.raw[self.row][col] = pin.is_low().unwrap_or(false);
+        }
+        
+        let _ = self.rows[self.row].set_high();
+        self.row = (self.row + 1) % ROWS;
+        let _ = self.rows[self.row].set_low();
+        
+        if self.row == 0 {
+            let ghosts = if self.diodes == true { [[false; COLS]; ROWS] } else { self.ghosts() };
+            for (r, row) in self.keys.iter_mut().enumerate() {
+                for (c, key) in row.iter_mut().enumerate() {
+                    // ambiguous keys keep their previous state
+                    if ghosts[r][c] == false {
+                        key.update(self.raw[r][c], time);
+                    }
+                }
+            }
+        }
+    }
+    
+    // the debounced state of every key
+    pub fn states(&self) -> [[bool; COLS]; ROWS] {
+        core::array::from_fn(|r| core::array::from_fn(|c| self.keys[r][c].state()))
+    }
+    
+    // without diodes, three pressed keys on the corners of a rectangle make the fourth
+    // corner read as pressed. whenever two rows share two or more pressed columns, the
+    // keys in those columns can't be trusted
+    fn ghosts(&self) -> [[bool; COLS]; ROWS] {
+        let mut out = [[false; COLS]; ROWS];
+        for r1 in 0..ROWS {
+            for r2 in (r1 + 1)..ROWS {
+                let shared = (0..COLS).filter(|c| self.raw[r1][*c] == true && self.raw[r2][*c] == true).count();
+                if shared >= 2 {
+                    for c in 0..COLS {
+                        if self.raw[r1][c] == true && self.raw[r2][c] == true {
+                            out[r1][c] = true;
+                            out[r2][c] = true;
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+// the matrix keys as the main loop sees them. update copies the debounced states once per
+// pass of the loop and the edges come from comparing them with the last copy, so every
+// press and release is seen by exactly one pass however the scan lines up with the loop
+pub struct MatrixKeys<const ROWS: usize, const COLS: usize> {
+    state: [[bool; COLS]; ROWS],
+    last_state: [[bool; COLS]; ROWS],
+}
+
+impl<const ROWS: usize, const COLS: usize> MatrixKeys<ROWS, COLS> {
+    pub fn new() -> Self {
+        MatrixKeys {
+            state: [[false; COLS]; ROWS],
+            last_state: [[false; COLS]; ROWS],
+        }
+    }
+    
+    pub fn update(&mut self, states: [[bool; COLS]; ROWS]) {
+        self.last_state = self.state;
+        self.state = states;
+    }
+    
+    // the current and last state of a key in row-major order, so slice(0, 16) is the
+    // first two rows of 8
+    fn index(&self, index: usize) -> (bool, bool) {
+        if index >= ROWS * COLS { return (false, false) }
+        (self.state[index / COLS][index % COLS], self.last_state[index / COLS][index % COLS])
+    }
+}
+
+impl<const ROWS: usize, const COLS: usize> ButtonGroup for MatrixKeys<ROWS, COLS> {
+    fn len(&self) -> usize {
+        ROWS * COLS
+    }
+    
+    fn state(&self, index: usize) -> bool {
+        self.index(index).0
+    }
+    
+    fn rising_edge(&self, index: usize) -> bool {
+        self.index(index) == (true, false)
+    }
+    
+    fn falling_edge(&self, index: usize) -> bool {
+        self.index(index) == (false, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+    use core::convert::Infallible;
+    use std::rc::Rc;
+    use std::vec::Vec;
+    
+    const ROWS: usize = 3;
+    const COLS: usize = 4;
+    
+    // the keys and row levels behind the fake pins. without diodes a driven row pulls down
+    // every column it reaches through pressed keys, including the way back through other rows
+    struct Wiring {
+        pressed: [[bool; COLS]; ROWS],
+        driven: [bool; ROWS],
+        diodes: bool,
+    }
+    
+    impl Wiring {
+        fn column_low(&self, col: usize) -> bool {
+            let mut rows = self.driven;
+            let mut cols = [false; COLS];
+            loop {
+                let mut changed = false;
+                for r in 0..ROWS {
+                    for c in 0..COLS {
+                        if self.pressed[r][c] == false { continue }
+                        if rows[r] == true && cols[c] == false {
+                            cols[c] = true;
+                            changed = true;
+                        }
+                        if self.diodes == false && cols[c] == true && rows[r] == false {
+                            rows[r] = true;
+                            changed = true;
+                        }
+                    }
+                }
+                if changed == false { return cols[col] }
+            }
+        }
+    }
+    
+    struct FakeRow(usize, Rc<RefCell<Wiring>>);
+    
+    impl OutputPin for FakeRow {
+        type Error = Infallible;
+        
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.1.borrow_mut().driven[self.0] = true;
+            Ok(())
+        }
+        
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.1.borrow_mut().driven[self.0] = false;
+            Ok(())
+        }
+    }
+    
+    struct FakeColumn(usize, Rc<RefCell<Wiring>>);
+    
+    impl InputPin for FakeColumn {
+        type Error = Infallible;
+        
+        fn is_high(&self) -> Result<bool, Infallible> {
+            Ok(self.1.borrow().column_low(self.0) == false)
+        }
+        
+        fn is_low(&self) -> Result<bool, Infallible> {
+            Ok(self.1.borrow().column_low(self.0))
+        }
+    }
+    
+    fn matrix(diodes: bool) -> (ButtonMatrix<FakeRow, FakeColumn, ROWS, COLS>, Rc<RefCell<Wiring>>) {
+        let wiring = Rc::new(RefCell::new(Wiring {
+            pressed: [[false; COLS]; ROWS],
+            driven: [false; ROWS],
+            diodes: diodes,
+        }));
+        let rows = core::array::from_fn(|r| FakeRow(r, wiring.clone()));
+        let cols = core::array::from_fn(|c| FakeColumn(c, wiring.clone()));
+        (ButtonMatrix::new(rows, cols, 5, diodes), wiring)
+    }
+    
+    // one full scan of every row per ms, from start until just before end
+    fn scan(matrix: &mut ButtonMatrix<FakeRow, FakeColumn, ROWS, COLS>, start: u32, end: u32) {
+        for millis in start..end {
+            for _ in 0..ROWS {
+                matrix.tick(millis);
+            }
+        }
+    }
+    
+    fn pressed(states: [[bool; COLS]; ROWS]) -> Vec<(usize, usize)> {
+        let mut keys = Vec::new();
+        for r in 0..ROWS {
+            for c in 0..COLS {
+                if states[r][c] == true { keys.push((r, c)) }
+            }
+        }
+        keys
+    }
+    
+    #[test]
+    fn drives_one_row_at_a_time() {
+        let (mut matrix, wiring) = matrix(true);
+        assert_eq!(wiring.borrow().driven, [true, false, false]);
+        matrix.tick(0);
+        assert_eq!(wiring.borrow().driven, [false, true, false]);
+        matrix.tick(0);
+        assert_eq!(wiring.borrow().driven, [false, false, true]);
+        matrix.tick(0);
+        assert_eq!(wiring.borrow().driven, [true, false, false]);
+    }
+    
+    #[test]
+    fn debounces_press_and_release() {
+        let (mut matrix, wiring) = matrix(true);
+        wiring.borrow_mut().pressed[1][2] = true;
+        scan(&mut matrix, 0, 6);
+        assert_eq!(pressed(matrix.states()), []);
+        scan(&mut matrix, 6, 7);
+        assert_eq!(pressed(matrix.states()), [(1, 2)]);
+        
+        wiring.borrow_mut().pressed[1][2] = false;
+        scan(&mut matrix, 7, 13);
+        assert_eq!(pressed(matrix.states()), [(1, 2)]);
+        scan(&mut matrix, 13, 14);
+        assert_eq!(pressed(matrix.states()), []);
+    }
+    
+    #[test]
+    fn ignores_a_short_glitch() {
+        let (mut matrix, wiring) = matrix(true);
+        wiring.borrow_mut().pressed[0][3] = true;
+        scan(&mut matrix, 0, 3);
+        wiring.borrow_mut().pressed[0][3] = false;
+        scan(&mut matrix, 3, 40);
+        assert_eq!(pressed(matrix.states()), []);
+    }
+    
+    #[test]
+    fn keys_only_update_after_a_full_scan() {
+        let (mut matrix, wiring) = matrix(true);
+        wiring.borrow_mut().pressed[2][0] = true;
+        scan(&mut matrix, 0, 10);
+        // a partial scan of the next frame leaves the release pending
+        wiring.borrow_mut().pressed[2][0] = false;
+        for _ in 0..(ROWS - 1) {
+            matrix.tick(100);
+        }
+        assert_eq!(pressed(matrix.states()), [(2, 0)]);
+    }
+    
+    #[test]
+    fn suppresses_ghost_keys_without_diodes() {
+        let (mut matrix, wiring) = matrix(false);
+        wiring.borrow_mut().pressed[0][0] = true;
+        wiring.borrow_mut().pressed[0][1] = true;
+        scan(&mut matrix, 0, 10);
+        assert_eq!(pressed(matrix.states()), [(0, 0), (0, 1)]);
+        
+        // the third corner makes row 1 read both columns, the fourth corner is a ghost
+        wiring.borrow_mut().pressed[1][0] = true;
+        scan(&mut matrix, 10, 20);
+        assert_eq!(pressed(matrix.states()), [(0, 0), (0, 1)]);
+        
+        // once the rectangle is broken the real key comes through
+        wiring.borrow_mut().pressed[0][1] = false;
+        scan(&mut matrix, 20, 40);
+        assert_eq!(pressed(matrix.states()), [(0, 0), (1, 0)]);
+    }
+    
+    #[test]
+    fn keys_outside_the_rectangle_still_work() {
+        let (mut matrix, wiring) = matrix(false);
+        wiring.borrow_mut().pressed[0][0] = true;
+        wiring.borrow_mut().pressed[0][1] = true;
+        wiring.borrow_mut().pressed[1][0] = true;
+        wiring.borrow_mut().pressed[2][3] = true;
+        scan(&mut matrix, 0, 10);
+        assert_eq!(pressed(matrix.states()), [(2, 3)]);
+    }
+    
+    #[test]
+    fn diodes_read_every_key() {
+        let (mut matrix, wiring) = matrix(true);
+        wiring.borrow_mut().pressed[0][0] = true;
+        wiring.borrow_mut().pressed[0][1] = true;
+        wiring.borrow_mut().pressed[1][0] = true;
+        scan(&mut matrix, 0, 10);
+        assert_eq!(pressed(matrix.states()), [(0, 0), (0, 1), (1, 0)]);
+    }
+    
+    #[test]
+    fn keys_report_edges_once() {
+        let (mut matrix, wiring) = matrix(true);
+        let mut keys = MatrixKeys::<ROWS, COLS>::new();
+        wiring.borrow_mut().pressed[1][1] = true;
+        scan(&mut matrix, 0, 10);
+        keys.update(matrix.states());
+        assert_eq!(keys.rising_edge(COLS + 1), true);
+        assert_eq!(keys.state(COLS + 1), true);
+        keys.update(matrix.states());
+        assert_eq!(keys.rising_edge(COLS + 1), false);
+        assert_eq!(keys.state(COLS + 1), true);
+        assert_eq!(keys.state(ROWS * COLS), false);
+    }
+}
@@ -1,56 +1,1029 @@
 //~ use crate::hal::serial::Write;
 //~ use crate::hal::prelude::_embedded_hal_serial_Write as Write;
 use crate::hal::prelude::_embedded_hal_blocking_serial_Write as Write;
+use crate::txqueue::TxQueue;
 
 fn filter_channel(channel: u8) -> u8 {
     (channel.max(1).min(16)) - 1
 }
 
-pub fn note_on<T: Write<u8>>(serial: &mut T, channel: u8, note: u8, velocity: u8) {
-    let note_clip = note.min(127);
-    let velocity_clip = velocity.min(127);
-    //~ let _ = serial.write(0x90 + filter_channel(channel));
-    //~ let _ = serial.write(note_clip);
-    //~ let _ = serial.write(velocity_clip);
-    //~ let _ = serial.flush();
-    let _ = serial.bwrite_all(&[0x90 + filter_channel(channel), note_clip, velocity_clip]);
-}
-
-pub fn note_off<T: Write<u8>>(serial: &mut T, channel: u8, note: u8, velocity: u8) {
-    let note_clip = note.min(127);
-    let velocity_clip = velocity.min(127);
-    //~ let _ = serial.write(0x80 + filter_channel(channel));
-    //~ let _ = serial.write(note_clip);
-    //~ let _ = serial.write(velocity_clip);
-    //~ let _ = serial.flush();
-    let _ = serial.bwrite_all(&[0x80 + filter_channel(channel), note_clip, velocity_clip]);
-}
-
-pub fn cc<T: Write<u8>>(serial: &mut T, channel: u8, number: u8, value: u8) {
-    let number_clip = number.min(127);
-    let value_clip = value.min(127);
-    //~ let _ = serial.write(0xB0 + filter_channel(channel));
-    //~ let _ = serial.write(number_clip);
-    //~ let _ = serial.write(value_clip);
-    //~ let _ = serial.flush();
-    let _ = serial.bwrite_all(&[0xB0 + filter_channel(channel), number_clip, value_clip]);
-}
-
-/*
-pub fn aftertouch<T: Write<u8>>(serial: &mut T, channel: u8, value: u8) {
-    let value_clip = value.min(127);
-    let _ = serial.write(0xD0 + filter_channel(channel));
-    let _ = serial.write(value_clip);
-    let _ = serial.flush();
-}
-
-pub fn pitch_bend<T: Write<u8>>(serial: &mut T, channel: u8, value: u16) {
-    let value_clip = value.min(16383);
-    let lsb = (value_clip & 0x7F) as u8;
-    let msb = (value_clip >> 7) as u8;
-    let _ = serial.write(0xE0 + filter_channel(channel));
-    let _ = serial.write(lsb);
-    let _ = serial.write(msb);
-    let _ = serial.flush();
-}
-*/
+// turns messages into bytes for the serial port. with running status enabled, the status
+// byte is left out when it matches the previous channel message, which saves a third of
+// the bandwidth for runs of notes on one channel
+pub struct MidiEncoder {
+    running_status: bool,
+    last_status: u8,
+}
+
+impl MidiEncoder {
+    pub fn new(running_status: bool) -> Self {
+        MidiEncoder {
+            running_status: running_status,
+            last_status: 0,
+        }
+    }
+    
+    pub fn encode<'a>(&mut self, message: &MidiMessage, buf: &'a mut [u8; 3]) -> &'a [u8] {
+        let len = message.to_bytes(buf);
+        if len == 0 { return &buf[..0] }
+        let status = buf[0];
+        
+        // realtime bytes don't affect running status
+        if status >= 0xF8 { return &buf[..len] }
+        // system common messages cancel it
+        if status >= 0xF0 {
+            self.last_status = 0;
+            return &buf[..len];
+        }
+        
+        if self.running_status == true && status == self.last_status {
+            return &buf[1..len];
+        }
+        self.last_status = status;
+        &buf[..len]
+    }
+    
+    // forget the last status byte so the next message sends it again
+    pub fn reset(&mut self) {
+        self.last_status = 0;
+    }
+    
+    // queue the message for the UART interrupt, returns false if the queue was full
+    pub fn queue<const N: usize>(&mut self, queue: &mut TxQueue<N>, message: &MidiMessage) -> bool {
+        if message.is_realtime() == true {
+            let mut buf = [0u8; 3];
+            message.to_bytes(&mut buf);
+            return queue.push_realtime(buf[0]);
+        }
+        let mut buf = [0u8; 3];
+        let bytes = self.encode(message, &mut buf);
+        let queued = queue.push_message(bytes);
+        // a dropped message may have carried the status byte that the next one relies on
+        if queued == false { self.last_status = 0 }
+        queued
+    }
+    
+    // data must not include the 0xF0 and 0xF7 bytes
+    pub fn queue_sysex<const N: usize>(&mut self, queue: &mut TxQueue<N>, data: &[u8]) -> bool {
+        self.last_status = 0;
+        // F0, the data in pieces of up to 255 bytes and F7, all of it or nothing
+        let pieces = (data.len() + 254) / 255;
+        if queue.fits(data.len() + 2, pieces + 2) == false { return false }
+        queue.push_message(&[0xF0]);
+        for chunk in data.chunks(255) {
+            queue.push_message(chunk);
+        }
+        queue.push_message(&[0xF7])
+    }
+    
+    // queues each controller message of a 14-bit controller or (N)RPN
+    pub fn queue_parameter<const N: usize>(&mut self, queue: &mut TxQueue<N>, message: &ParameterMessage, terminate: bool) -> bool {
+        let mut messages = [MidiMessage::TuneRequest; 7];
+        let count = message.to_messages(terminate, &mut messages);
+        // a receiver can't use half a parameter, so all the messages go in or none
+        if queue.fits(count * 3, count) == false { return false }
+        for m in messages[..count].iter() {
+            self.queue(queue, m);
+        }
+        true
+    }
+    
+    pub fn send<T: Write<u8>>(&mut self, serial: &mut T, message: &MidiMessage) -> Result<(), T::Error> {
+        let mut buf = [0u8; 3];
+        let bytes = self.encode(message, &mut buf);
+        let result = serial.bwrite_all(bytes);
+        // the status byte may not have gone out, so the next message has to send it again
+        if result.is_err() { self.last_status = 0 }
+        result
+    }
+    
+    pub fn send_parameter<T: Write<u8>>(&mut self, serial: &mut T, message: &ParameterMessage, terminate: bool) -> Result<(), T::Error> {
+        let mut messages = [MidiMessage::TuneRequest; 7];
+        let count = message.to_messages(terminate, &mut messages);
+        for m in messages[..count].iter() {
+            self.send(serial, m)?;
+        }
+        Ok(())
+    }
+    
+    // data must not include the 0xF0 and 0xF7 bytes
+    pub fn send_sysex<T: Write<u8>>(&mut self, serial: &mut T, data: &[u8]) -> Result<(), T::Error> {
+        self.last_status = 0;
+        serial.bwrite_all(&[0xF0])?;
+        serial.bwrite_all(data)?;
+        serial.bwrite_all(&[0xF7])
+    }
+}
+
+// channels are 1-16
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MidiMessage {
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    PolyAftertouch { channel: u8, note: u8, pressure: u8 },
+    ControlChange { channel: u8, control: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    ChannelAftertouch { channel: u8, pressure: u8 },
+    PitchBend { channel: u8, value: u16 },
+    // channel mode messages, sent as controllers 120-127
+    AllSoundOff { channel: u8 },
+    ResetAllControllers { channel: u8 },
+    LocalControl { channel: u8, on: bool },
+    AllNotesOff { channel: u8 },
+    OmniOff { channel: u8 },
+    OmniOn { channel: u8 },
+    MonoOn { channel: u8, channels: u8 },  // channels = 0 uses as many as the receiver has voices
+    PolyOn { channel: u8 },
+    // the data bytes are kept in the parser, see MidiParser::sysex
+    SysEx { len: usize, truncated: bool },
+    TimeCodeQuarterFrame { value: u8 },
+    SongPosition { position: u16 },
+    SongSelect { song: u8 },
+    TuneRequest,
+    TimingClock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    SystemReset,
+}
+
+impl MidiMessage {
+    pub fn channel(&self) -> Option<u8> {
+        match *self {
+            MidiMessage::NoteOff { channel, .. } => Some(channel),
+            MidiMessage::NoteOn { channel, .. } => Some(channel),
+            MidiMessage::PolyAftertouch { channel, .. } => Some(channel),
+            MidiMessage::ControlChange { channel, .. } => Some(channel),
+            MidiMessage::ProgramChange { channel, .. } => Some(channel),
+            MidiMessage::ChannelAftertouch { channel, .. } => Some(channel),
+            MidiMessage::PitchBend { channel, .. } => Some(channel),
+            MidiMessage::AllSoundOff { channel } => Some(channel),
+            MidiMessage::ResetAllControllers { channel } => Some(channel),
+            MidiMessage::LocalControl { channel, .. } => Some(channel),
+            MidiMessage::AllNotesOff { channel } => Some(channel),
+            MidiMessage::OmniOff { channel } => Some(channel),
+            MidiMessage::OmniOn { channel } => Some(channel),
+            MidiMessage::MonoOn { channel, .. } => Some(channel),
+            MidiMessage::PolyOn { channel } => Some(channel),
+            _ => None,
+        }
+    }
+    
+    pub fn is_realtime(&self) -> bool {
+        match self {
+            MidiMessage::TimingClock
+            | MidiMessage::Start
+            | MidiMessage::Continue
+            | MidiMessage::Stop
+            | MidiMessage::ActiveSensing
+            | MidiMessage::SystemReset => true,
+            _ => false,
+        }
+    }
+    
+    // writes the complete message with its status byte and returns the length.
+    // SysEx isn't stored in the message, so it returns 0
+    pub fn to_bytes(&self, buf: &mut [u8; 3]) -> usize {
+        match *self {
+            MidiMessage::NoteOff { channel, note, velocity } => {
+                *buf = [0x80 + filter_channel(channel), note.min(127), velocity.min(127)];
+                3
+            }
+            MidiMessage::NoteOn { channel, note, velocity } => {
+                *buf = [0x90 + filter_channel(channel), note.min(127), velocity.min(127)];
+                3
+            }
+            MidiMessage::PolyAftertouch { channel, note, pressure } => {
+                *buf = [0xA0 + filter_channel(channel), note.min(127), pressure.min(127)];
+                3
+            }
+            MidiMessage::ControlChange { channel, control, value } => {
+                *buf = [0xB0 + filter_channel(channel), control.min(127), value.min(127)];
+                3
+            }
+            MidiMessage::ProgramChange { channel, program } => {
+                *buf = [0xC0 + filter_channel(channel), program.min(127), 0];
+                2
+            }
+            MidiMessage::ChannelAftertouch { channel, pressure } => {
+                *buf = [0xD0 + filter_channel(channel), pressure.min(127), 0];
+                2
+            }
+            MidiMessage::PitchBend { channel, value } => {
+                let value_clip = value.min(16383);
+                *buf = [0xE0 + filter_channel(channel), (value_clip & 0x7F) as u8, (value_clip >> 7) as u8];
+                3
+            }
+            MidiMessage::AllSoundOff { channel } => mode_bytes(buf, channel, 120, 0),
+            MidiMessage::ResetAllControllers { channel } => mode_bytes(buf, channel, 121, 0),
+            MidiMessage::LocalControl { channel, on } => mode_bytes(buf, channel, 122, if on == true { 127 } else { 0 }),
+            MidiMessage::AllNotesOff { channel } => mode_bytes(buf, channel, 123, 0),
+            MidiMessage::OmniOff { channel } => mode_bytes(buf, channel, 124, 0),
+            MidiMessage::OmniOn { channel } => mode_bytes(buf, channel, 125, 0),
+            MidiMessage::MonoOn { channel, channels } => mode_bytes(buf, channel, 126, channels.min(16)),
+            MidiMessage::PolyOn { channel } => mode_bytes(buf, channel, 127, 0),
+            MidiMessage::SysEx { .. } => 0,
+            MidiMessage::TimeCodeQuarterFrame { value } => {
+                *buf = [0xF1, value.min(127), 0];
+                2
+            }
+            MidiMessage::SongPosition { position } => {
+                let position_clip = position.min(16383);
+                *buf = [0xF2, (position_clip & 0x7F) as u8, (position_clip >> 7) as u8];
+                3
+            }
+            MidiMessage::SongSelect { song } => {
+                *buf = [0xF3, song.min(127), 0];
+                2
+            }
+            MidiMessage::TuneRequest => { *buf = [0xF6, 0, 0]; 1 }
+            MidiMessage::TimingClock => { *buf = [0xF8, 0, 0]; 1 }
+            MidiMessage::Start => { *buf = [0xFA, 0, 0]; 1 }
+            MidiMessage::Continue => { *buf = [0xFB, 0, 0]; 1 }
+            MidiMessage::Stop => { *buf = [0xFC, 0, 0]; 1 }
+            MidiMessage::ActiveSensing => { *buf = [0xFE, 0, 0]; 1 }
+            MidiMessage::SystemReset => { *buf = [0xFF, 0, 0]; 1 }
+        }
+    }
+    
+    // reads a complete message with its status byte, the reverse of to_bytes. unused data
+    // bytes are ignored, SysEx and the undefined status bytes return None
+    pub fn from_bytes(bytes: &[u8; 3]) -> Option<MidiMessage> {
+        let status = bytes[0];
+        let channel = (status & 0x0F) + 1;
+        let d0 = bytes[1];
+        let d1 = bytes[2];
+        let message = match status & 0xF0 {
+            0x80 => MidiMessage::NoteOff { channel: channel, note: d0, velocity: d1 },
+            // note on with zero velocity is a note off
+            0x90 if d1 == 0 => MidiMessage::NoteOff { channel: channel, note: d0, velocity: 0 },
+            0x90 => MidiMessage::NoteOn { channel: channel, note: d0, velocity: d1 },
+            0xA0 => MidiMessage::PolyAftertouch { channel: channel, note: d0, pressure: d1 },
+            0xB0 if d0 >= 120 => channel_mode(channel, d0, d1),
+            0xB0 => MidiMessage::ControlChange { channel: channel, control: d0, value: d1 },
+            0xC0 => MidiMessage::ProgramChange { channel: channel, program: d0 },
+            0xD0 => MidiMessage::ChannelAftertouch { channel: channel, pressure: d0 },
+            0xE0 => MidiMessage::PitchBend { channel: channel, value: (d0 as u16) | ((d1 as u16) << 7) },
+            _ => match status {
+                0xF1 => MidiMessage::TimeCodeQuarterFrame { value: d0 },
+                0xF2 => MidiMessage::SongPosition { position: (d0 as u16) | ((d1 as u16) << 7) },
+                0xF3 => MidiMessage::SongSelect { song: d0 },
+                0xF6 => MidiMessage::TuneRequest,
+                0xF8 => MidiMessage::TimingClock,
+                0xFA => MidiMessage::Start,
+                0xFB => MidiMessage::Continue,
+                0xFC => MidiMessage::Stop,
+                0xFE => MidiMessage::ActiveSensing,
+                0xFF => MidiMessage::SystemReset,
+                _ => return None,
+            },
+        };
+        Some(message)
+    }
+}
+
+fn mode_bytes(buf: &mut [u8; 3], channel: u8, control: u8, value: u8) -> usize {
+    *buf = [0xB0 + filter_channel(channel), control, value];
+    3
+}
+
+// controllers 120-127 are channel mode messages
+fn channel_mode(channel: u8, control: u8, value: u8) -> MidiMessage {
+    match control {
+        120 => MidiMessage::AllSoundOff { channel: channel },
+        121 => MidiMessage::ResetAllControllers { channel: channel },
+        122 => MidiMessage::LocalControl { channel: channel, on: value >= 64 },
+        123 => MidiMessage::AllNotesOff { channel: channel },
+        124 => MidiMessage::OmniOff { channel: channel },
+        125 => MidiMessage::OmniOn { channel: channel },
+        126 => MidiMessage::MonoOn { channel: channel, channels: value },
+        127 => MidiMessage::PolyOn { channel: channel },
+        _ => MidiMessage::ControlChange { channel: channel, control: control, value: value },
+    }
+}
+
+// number of data bytes that follow a status byte
+fn data_length(status: u8) -> usize {
+    match status {
+        0x80..=0xBF | 0xE0..=0xEF | 0xF2 => 2,
+        0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+        _ => 0,
+    }
+}
+
+// byte-at-a-time MIDI 1.0 parser. handles running status, realtime bytes in the
+// middle of other messages and SysEx up to N data bytes. data bytes that don't
+// belong to any message are dropped and counted as errors
+pub struct MidiParser<const N: usize> {
+    status: u8,        // running status, 0 when there is none
+    data: [u8; 2],
+    count: usize,
+    in_sysex: bool,
+    sysex: [u8; N],
+    sysex_len: usize,
+    sysex_truncated: bool,
+    errors: u32,
+}
+
+impl<const N: usize> MidiParser<N> {
+    pub fn new() -> Self {
+        MidiParser {
+            status: 0,
+            data: [0; 2],
+            count: 0,
+            in_sysex: false,
+            sysex: [0; N],
+            sysex_len: 0,
+            sysex_truncated: false,
+            errors: 0,
+        }
+    }
+    
+    pub fn push(&mut self, byte: u8) -> Option<MidiMessage> {
+        // realtime bytes can arrive anywhere, including inside SysEx, and don't affect
+        // the message in progress
+        if byte >= 0xF8 {
+            return match byte {
+                0xF8 => Some(MidiMessage::TimingClock),
+                0xFA => Some(MidiMessage::Start),
+                0xFB => Some(MidiMessage::Continue),
+                0xFC => Some(MidiMessage::Stop),
+                0xFE => Some(MidiMessage::ActiveSensing),
+                0xFF => Some(MidiMessage::SystemReset),
+                // 0xF9 and 0xFD are undefined
+                _ => None,
+            };
+        }
+        
+        if byte >= 0x80 {
+            return self.push_status(byte);
+        }
+        
+        if self.in_sysex == true {
+            if self.sysex_len < N {
+                self.sysex[self.sysex_len] = byte;
+                self.sysex_len += 1;
+            } else {
+                self.sysex_truncated = true;
+            }
+            return None;
+        }
+        
+        // data byte without a status byte to go with it
+        if self.status == 0 {
+            self.errors = self.errors.wrapping_add(1);
+            return None;
+        }
+        
+        self.data[self.count] = byte;
+        self.count += 1;
+        if self.count < data_length(self.status) { return None }
+        self.count = 0;
+        
+        let message = self.message();
+        // only channel messages use running status
+        if self.status >= 0xF0 { self.status = 0 }
+        message
+    }
+    
+    fn push_status(&mut self, byte: u8) -> Option<MidiMessage> {
+        // a new status byte in the middle of a message, the partial message is dropped
+        if self.count > 0 {
+            self.errors = self.errors.wrapping_add(1);
+            self.count = 0;
+        }
+        
+        if self.in_sysex == true {
+            self.in_sysex = false;
+            if byte == 0xF7 {
+                return Some(MidiMessage::SysEx { len: self.sysex_len, truncated: self.sysex_truncated });
+            }
+            // any other status byte ends SysEx without a complete message
+            self.errors = self.errors.wrapping_add(1);
+        }
+        
+        match byte {
+            0xF0 => {
+                self.status = 0;
+                self.in_sysex = true;
+                self.sysex_len = 0;
+                self.sysex_truncated = false;
+                None
+            }
+            0xF6 => {
+                self.status = 0;
+                Some(MidiMessage::TuneRequest)
+            }
+            // stray SysEx end and the undefined system common bytes
+            0xF4 | 0xF5 | 0xF7 => {
+                self.status = 0;
+                None
+            }
+            _ => {
+                self.status = byte;
+                None
+            }
+        }
+    }
+    
+    fn message(&self) -> Option<MidiMessage> {
+        MidiMessage::from_bytes(&[self.status, self.data[0], self.data[1]])
+    }
+    
+    // data bytes of the last SysEx message, without the 0xF0 and 0xF7 bytes
+    pub fn sysex(&self) -> &[u8] {
+        &self.sysex[..self.sysex_len]
+    }
+    
+    // number of stray data bytes, interrupted messages and unterminated SysEx seen so far
+    pub fn errors(&self) -> u32 {
+        self.errors
+    }
+    
+    pub fn reset(&mut self) {
+        self.status = 0;
+        self.count = 0;
+        self.in_sysex = false;
+        self.sysex_len = 0;
+        self.sysex_truncated = false;
+    }
+}
+
+// registered parameter numbers, see ParameterMessage::pitch_bend_range
+pub const RPN_PITCH_BEND_RANGE: u16 = 0x0000;
+pub const RPN_FINE_TUNING: u16 = 0x0001;
+pub const RPN_COARSE_TUNING: u16 = 0x0002;
+// selecting this parameter deselects the current one, so later data entry is ignored
+pub const RPN_NULL: u16 = 0x3FFF;
+
+const CC_DATA_ENTRY_MSB: u8 = 6;
+const CC_DATA_ENTRY_LSB: u8 = 38;
+const CC_DATA_INCREMENT: u8 = 96;
+const CC_DATA_DECREMENT: u8 = 97;
+const CC_NRPN_LSB: u8 = 98;
+const CC_NRPN_MSB: u8 = 99;
+const CC_RPN_LSB: u8 = 100;
+const CC_RPN_MSB: u8 = 101;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ParameterKind {
+    Nrpn,
+    Rpn,
+}
+
+// high resolution controllers, built from several 7-bit controller messages.
+// channels are 1-16, values and parameter numbers are 14-bit (0-16383)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ParameterMessage {
+    // controllers 0-31 carry the MSB, the LSB goes to the same controller + 32
+    Controller14 { channel: u8, control: u8, value: u16 },
+    Parameter { channel: u8, kind: ParameterKind, parameter: u16, value: u16 },
+    Increment { channel: u8, kind: ParameterKind, parameter: u16, amount: u8 },
+    Decrement { channel: u8, kind: ParameterKind, parameter: u16, amount: u8 },
+}
+
+impl ParameterMessage {
+    // the range is sent as semitones in the MSB and cents in the LSB
+    pub fn pitch_bend_range(channel: u8, semitones: u8, cents: u8) -> Self {
+        ParameterMessage::Parameter {
+            channel: channel,
+            kind: ParameterKind::Rpn,
+            parameter: RPN_PITCH_BEND_RANGE,
+            value: ((semitones.min(127) as u16) << 7) | (cents.min(99) as u16),
+        }
+    }
+    
+    // fills out with the controller messages and returns how many were written.
+    // with terminate set, (N)RPN messages end with the null RPN so a stray data entry
+    // message from another source can't change the parameter afterwards
+    pub fn to_messages(&self, terminate: bool, out: &mut [MidiMessage; 7]) -> usize {
+        let mut count = 0;
+        let mut push = |channel: u8, control: u8, value: u8| {
+            out[count] = MidiMessage::ControlChange { channel: channel, control: control, value: value };
+            count += 1;
+        };
+        match *self {
+            ParameterMessage::Controller14 { channel, control, value } => {
+                let control = control.min(31);
+                push(channel, control, msb(value));
+                push(channel, control + 32, lsb(value));
+                return count;
+            }
+            ParameterMessage::Parameter { channel, kind, parameter, value } => {
+                select(&mut push, channel, kind, parameter);
+                push(channel, CC_DATA_ENTRY_MSB, msb(value));
+                push(channel, CC_DATA_ENTRY_LSB, lsb(value));
+            }
+            ParameterMessage::Increment { channel, kind, parameter, amount } => {
+                select(&mut push, channel, kind, parameter);
+                push(channel, CC_DATA_INCREMENT, amount.min(127));
+            }
+            ParameterMessage::Decrement { channel, kind, parameter, amount } => {
+                select(&mut push, channel, kind, parameter);
+                push(channel, CC_DATA_DECREMENT, amount.min(127));
+            }
+        }
+        if terminate == true {
+            let channel = self.channel();
+            push(channel, CC_RPN_MSB, 127);
+            push(channel, CC_RPN_LSB, 127);
+        }
+        count
+    }
+    
+    pub fn channel(&self) -> u8 {
+        match *self {
+            ParameterMessage::Controller14 { channel, .. } => channel,
+            ParameterMessage::Parameter { channel, .. } => channel,
+            ParameterMessage::Increment { channel, .. } => channel,
+            ParameterMessage::Decrement { channel, .. } => channel,
+        }
+    }
+}
+
+fn msb(value: u16) -> u8 {
+    (value.min(16383) >> 7) as u8
+}
+
+fn lsb(value: u16) -> u8 {
+    (value.min(16383) & 0x7F) as u8
+}
+
+fn select<F: FnMut(u8, u8, u8)>(push: &mut F, channel: u8, kind: ParameterKind, parameter: u16) {
+    match kind {
+        ParameterKind::Nrpn => {
+            push(channel, CC_NRPN_MSB, msb(parameter));
+            push(channel, CC_NRPN_LSB, lsb(parameter));
+        }
+        ParameterKind::Rpn => {
+            push(channel, CC_RPN_MSB, msb(parameter));
+            push(channel, CC_RPN_LSB, lsb(parameter));
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct ParameterState {
+    kind: Option<ParameterKind>,  // None until a parameter is selected, or after the null RPN
+    parameter_msb: u8,
+    parameter_lsb: u8,
+    data_msb: u8,
+    controller_msb: [u8; 32],
+}
+
+// collects the controller messages of each channel back into ParameterMessages.
+// only controllers set in the high_res mask (bit n = controller n) are treated as
+// 14-bit pairs, the rest are left to the caller as plain 7-bit controllers
+pub struct ParameterDecoder {
+    high_res: u32,
+    channels: [ParameterState; 16],
+}
+
+impl ParameterDecoder {
+    pub fn new(high_res: u32) -> Self {
+        ParameterDecoder {
+            high_res: high_res,
+            channels: [ParameterState {
+                kind: None,
+                parameter_msb: 0,
+                parameter_lsb: 0,
+                data_msb: 0,
+                controller_msb: [0; 32],
+            }; 16],
+        }
+    }
+    
+    // an MSB on its own is a complete value with the LSB at 0, a following LSB refines it,
+    // so both halves of a pair produce a message
+    pub fn push(&mut self, message: &MidiMessage) -> Option<ParameterMessage> {
+        let (channel, control, value) = match *message {
+            MidiMessage::ControlChange { channel, control, value } => (channel, control, value),
+            _ => return None,
+        };
+        let state = &mut self.channels[filter_channel(channel) as usize];
+        let parameter = ((state.parameter_msb as u16) << 7) | (state.parameter_lsb as u16);
+        
+        match control {
+            CC_NRPN_MSB | CC_RPN_MSB => {
+                state.kind = Some(if control == CC_NRPN_MSB { ParameterKind::Nrpn } else { ParameterKind::Rpn });
+                state.parameter_msb = value;
+                None
+            }
+            CC_NRPN_LSB | CC_RPN_LSB => {
+                state.kind = Some(if control == CC_NRPN_LSB { ParameterKind::Nrpn } else { ParameterKind::Rpn });
+                state.parameter_lsb = value;
+                // 127/127 is the null parameter for both RPN and NRPN
+                if state.parameter_msb == 127 && value == 127 {
+                    state.kind = None;
+                }
+                None
+            }
+            CC_DATA_ENTRY_MSB => {
+                state.data_msb = value;
+                let kind = state.kind?;
+                Some(ParameterMessage::Parameter { channel: channel, kind: kind, parameter: parameter, value: (value as u16) << 7 })
+            }
+            CC_DATA_ENTRY_LSB => {
+                let kind = state.kind?;
+                let value = ((state.data_msb as u16) << 7) | (value as u16);
+                Some(ParameterMessage::Parameter { channel: channel, kind: kind, parameter: parameter, value: value })
+            }
+            CC_DATA_INCREMENT => {
+                let kind = state.kind?;
+                Some(ParameterMessage::Increment { channel: channel, kind: kind, parameter: parameter, amount: value })
+            }
+            CC_DATA_DECREMENT => {
+                let kind = state.kind?;
+                Some(ParameterMessage::Decrement { channel: channel, kind: kind, parameter: parameter, amount: value })
+            }
+            0..=31 if self.high_res & (1 << control) != 0 => {
+                state.controller_msb[control as usize] = value;
+                Some(ParameterMessage::Controller14 { channel: channel, control: control, value: (value as u16) << 7 })
+            }
+            32..=63 if self.high_res & (1 << (control - 32)) != 0 => {
+                let value = ((state.controller_msb[(control - 32) as usize] as u16) << 7) | (value as u16);
+                Some(ParameterMessage::Controller14 { channel: channel, control: control - 32, value: value })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    
+    fn parse<const N: usize>(parser: &mut MidiParser<N>, bytes: &[u8]) -> Vec<MidiMessage> {
+        bytes.iter().filter_map(|b| parser.push(*b)).collect()
+    }
+    
+    #[test]
+    fn running_status_across_messages() {
+        let mut parser = MidiParser::<16>::new();
+        let messages = parse(&mut parser, &[0x91, 60, 100, 62, 90, 64, 0, 0xB1, 7, 100, 10, 64]);
+        assert_eq!(messages, [
+            MidiMessage::NoteOn { channel: 2, note: 60, velocity: 100 },
+            MidiMessage::NoteOn { channel: 2, note: 62, velocity: 90 },
+            // note on with velocity 0 is a note off
+            MidiMessage::NoteOff { channel: 2, note: 64, velocity: 0 },
+            MidiMessage::ControlChange { channel: 2, control: 7, value: 100 },
+            MidiMessage::ControlChange { channel: 2, control: 10, value: 64 },
+        ]);
+        // one data byte messages run too
+        assert_eq!(parse(&mut parser, &[0xC0, 5, 6]), [
+            MidiMessage::ProgramChange { channel: 1, program: 5 },
+            MidiMessage::ProgramChange { channel: 1, program: 6 },
+        ]);
+        assert_eq!(parser.errors(), 0);
+    }
+    
+    #[test]
+    fn realtime_inside_a_message() {
+        let mut parser = MidiParser::<16>::new();
+        let messages = parse(&mut parser, &[0x90, 0xF8, 60, 0xFA, 100, 0xFC, 61, 0xFE, 0xFF, 101]);
+        assert_eq!(messages, [
+            MidiMessage::TimingClock,
+            MidiMessage::Start,
+            MidiMessage::NoteOn { channel: 1, note: 60, velocity: 100 },
+            MidiMessage::Stop,
+            MidiMessage::ActiveSensing,
+            MidiMessage::SystemReset,
+            MidiMessage::NoteOn { channel: 1, note: 61, velocity: 101 },
+        ]);
+        assert_eq!(parser.errors(), 0);
+    }
+    
+    #[test]
+    fn undefined_realtime_bytes_are_ignored() {
+        let mut parser = MidiParser::<16>::new();
+        assert_eq!(parse(&mut parser, &[0x90, 0xF9, 60, 0xFD, 100]), [MidiMessage::NoteOn { channel: 1, note: 60, velocity: 100 }]);
+        assert_eq!(parser.errors(), 0);
+    }
+    
+    #[test]
+    fn realtime_inside_sysex() {
+        let mut parser = MidiParser::<16>::new();
+        let messages = parse(&mut parser, &[0xF0, 0x7D, 1, 0xF8, 2, 0xFB, 3, 0xF7]);
+        assert_eq!(messages, [
+            MidiMessage::TimingClock,
+            MidiMessage::Continue,
+            MidiMessage::SysEx { len: 4, truncated: false },
+        ]);
+        assert_eq!(parser.sysex(), [0x7D, 1, 2, 3]);
+    }
+    
+    #[test]
+    fn sysex_truncated_at_n() {
+        let mut parser = MidiParser::<4>::new();
+        let messages = parse(&mut parser, &[0xF0, 1, 2, 3, 4, 5, 6, 0xF7]);
+        assert_eq!(messages, [MidiMessage::SysEx { len: 4, truncated: true }]);
+        assert_eq!(parser.sysex(), [1, 2, 3, 4]);
+        // the next one starts over
+        assert_eq!(parse(&mut parser, &[0xF0, 9, 0xF7]), [MidiMessage::SysEx { len: 1, truncated: false }]);
+        assert_eq!(parser.sysex(), [9]);
+        assert_eq!(parser.errors(), 0);
+    }
+    
+    #[test]
+    fn sysex_cancels_running_status() {
+        let mut parser = MidiParser::<16>::new();
+        let messages = parse(&mut parser, &[0x90, 60, 100, 0xF0, 1, 0xF7, 61, 100]);
+        assert_eq!(messages, [
+            MidiMessage::NoteOn { channel: 1, note: 60, velocity: 100 },
+            MidiMessage::SysEx { len: 1, truncated: false },
+        ]);
+        assert_eq!(parser.errors(), 2);
+    }
+    
+    #[test]
+    fn sysex_ended_by_another_status_byte() {
+        let mut parser = MidiParser::<16>::new();
+        let messages = parse(&mut parser, &[0xF0, 1, 2, 0x90, 60, 100]);
+        assert_eq!(messages, [MidiMessage::NoteOn { channel: 1, note: 60, velocity: 100 }]);
+        assert_eq!(parser.errors(), 1);
+    }
+    
+    #[test]
+    fn stray_data_bytes_are_counted() {
+        let mut parser = MidiParser::<16>::new();
+        assert_eq!(parse(&mut parser, &[60, 100]), []);
+        assert_eq!(parser.errors(), 2);
+        // a stray SysEx end clears the running status
+        assert_eq!(parse(&mut parser, &[0x90, 60, 100, 0xF7, 61, 100]).len(), 1);
+        assert_eq!(parser.errors(), 4);
+        // system common messages don't run
+        let messages = parse(&mut parser, &[0xF3, 5, 6]);
+        assert_eq!(messages, [MidiMessage::SongSelect { song: 5 }]);
+        assert_eq!(parser.errors(), 5);
+    }
+    
+    #[test]
+    fn interrupted_message_is_dropped() {
+        let mut parser = MidiParser::<16>::new();
+        let messages = parse(&mut parser, &[0x90, 60, 0x80, 60, 0]);
+        assert_eq!(messages, [MidiMessage::NoteOff { channel: 1, note: 60, velocity: 0 }]);
+        assert_eq!(parser.errors(), 1);
+    }
+    
+    #[test]
+    fn channel_mode_messages() {
+        let mut parser = MidiParser::<16>::new();
+        let messages = parse(&mut parser, &[
+            0xB3, 119, 1, 120, 0, 121, 0, 122, 127, 122, 0, 123, 0, 124, 0, 125, 0, 126, 4, 127, 0,
+        ]);
+        assert_eq!(messages, [
+            MidiMessage::ControlChange { channel: 4, control: 119, value: 1 },
+            MidiMessage::AllSoundOff { channel: 4 },
+            MidiMessage::ResetAllControllers { channel: 4 },
+            MidiMessage::LocalControl { channel: 4, on: true },
+            MidiMessage::LocalControl { channel: 4, on: false },
+            MidiMessage::AllNotesOff { channel: 4 },
+            MidiMessage::OmniOff { channel: 4 },
+            MidiMessage::OmniOn { channel: 4 },
+            MidiMessage::MonoOn { channel: 4, channels: 4 },
+            MidiMessage::PolyOn { channel: 4 },
+        ]);
+    }
+    
+    #[test]
+    fn system_common_messages() {
+        let mut parser = MidiParser::<16>::new();
+        let messages = parse(&mut parser, &[0xF1, 0x35, 0xF2, 0x01, 0x02, 0xF3, 7, 0xF6, 0xF4, 0xF5]);
+        assert_eq!(messages, [
+            MidiMessage::TimeCodeQuarterFrame { value: 0x35 },
+            MidiMessage::SongPosition { position: 0x101 },
+            MidiMessage::SongSelect { song: 7 },
+            MidiMessage::TuneRequest,
+        ]);
+        assert_eq!(parser.errors(), 0);
+    }
+    
+    #[test]
+    fn pitch_bend_is_14_bit() {
+        let mut parser = MidiParser::<16>::new();
+        assert_eq!(parse(&mut parser, &[0xEF, 0x7F, 0x7F, 0x00, 0x40]), [
+            MidiMessage::PitchBend { channel: 16, value: 16383 },
+            MidiMessage::PitchBend { channel: 16, value: 8192 },
+        ]);
+    }
+    
+    #[test]
+    fn reset_drops_a_partial_message() {
+        let mut parser = MidiParser::<16>::new();
+        parse(&mut parser, &[0x90, 60]);
+        parser.reset();
+        assert_eq!(parse(&mut parser, &[100]), []);
+        assert_eq!(parser.errors(), 1);
+    }
+    
+    // a serial port whose next write fails when fail is set
+    struct FakeSerial {
+        written: Vec<u8>,
+        fail: bool,
+    }
+    
+    impl Write<u8> for FakeSerial {
+        type Error = ();
+        
+        fn bwrite_all(&mut self, bytes: &[u8]) -> Result<(), ()> {
+            if self.fail == true {
+                self.fail = false;
+                return Err(());
+            }
+            self.written.extend_from_slice(bytes);
+            Ok(())
+        }
+        
+        fn bflush(&mut self) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+    
+    #[test]
+    fn failed_write_resends_the_status() {
+        let mut encoder = MidiEncoder::new(true);
+        let mut serial = FakeSerial { written: Vec::new(), fail: false };
+        encoder.send(&mut serial, &MidiMessage::NoteOn { channel: 1, note: 60, velocity: 100 }).unwrap();
+        encoder.send(&mut serial, &MidiMessage::NoteOn { channel: 1, note: 61, velocity: 100 }).unwrap();
+        assert_eq!(serial.written, [0x90, 60, 100, 61, 100]);
+        
+        serial.written.clear();
+        serial.fail = true;
+        assert_eq!(encoder.send(&mut serial, &MidiMessage::ControlChange { channel: 1, control: 7, value: 100 }), Err(()));
+        encoder.send(&mut serial, &MidiMessage::ControlChange { channel: 1, control: 7, value: 90 }).unwrap();
+        assert_eq!(serial.written, [0xB0, 7, 90]);
+    }
+    
+    fn drain<const N: usize>(queue: &mut TxQueue<N>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        while let Some(byte) = queue.pop() {
+            bytes.push(byte);
+        }
+        bytes
+    }
+    
+    #[test]
+    fn sysex_is_queued_whole() {
+        let mut encoder = MidiEncoder::new(true);
+        let mut queue = TxQueue::<600>::new();
+        let data: Vec<u8> = (0..300).map(|n| (n % 128) as u8).collect();
+        assert_eq!(encoder.queue_sysex(&mut queue, &data), true);
+        let mut expected = vec![0xF0];
+        expected.extend_from_slice(&data);
+        expected.push(0xF7);
+        assert_eq!(drain(&mut queue), expected);
+        assert_eq!(queue.overflows(), 0);
+    }
+    
+    #[test]
+    fn sysex_that_does_not_fit_queues_nothing() {
+        let mut encoder = MidiEncoder::new(true);
+        // F0, 10 data bytes and F7 behind three length bytes need 15 bytes
+        let mut queue = TxQueue::<14>::new();
+        assert_eq!(encoder.queue_sysex(&mut queue, &[0x7D; 10]), false);
+        assert_eq!(queue.is_empty(), true);
+        assert_eq!(queue.overflows(), 1);
+        
+        let mut queue = TxQueue::<15>::new();
+        assert_eq!(encoder.queue_sysex(&mut queue, &[0x7D; 10]), true);
+        assert_eq!(drain(&mut queue).len(), 12);
+    }
+    
+    #[test]
+    fn parameter_that_does_not_fit_queues_nothing() {
+        let mut encoder = MidiEncoder::new(true);
+        let mut queue = TxQueue::<10>::new();
+        let message = ParameterMessage::Controller14 { channel: 1, control: 7, value: 1000 };
+        assert_eq!(encoder.queue(&mut queue, &MidiMessage::ProgramChange { channel: 1, program: 5 }), true);
+        // 3 bytes are used, the two controllers need up to 8
+        assert_eq!(encoder.queue_parameter(&mut queue, &message, false), false);
+        assert_eq!(drain(&mut queue), [0xC0, 5]);
+        assert_eq!(encoder.queue_parameter(&mut queue, &message, false), true);
+        assert_eq!(drain(&mut queue), [0xB0, 7, 7, 39, 104]);
+    }
+    
+    // the last message the decoder makes from the controllers of a ParameterMessage
+    fn decode(decoder: &mut ParameterDecoder, message: &ParameterMessage, terminate: bool) -> Option<ParameterMessage> {
+        let mut messages = [MidiMessage::TuneRequest; 7];
+        let count = message.to_messages(terminate, &mut messages);
+        let mut decoded = None;
+        for m in messages[..count].iter() {
+            if let Some(d) = decoder.push(m) { decoded = Some(d) }
+        }
+        decoded
+    }
+    
+    #[test]
+    fn parameters_round_trip() {
+        let mut decoder = ParameterDecoder::new(1 << 7);
+        let messages = [
+            ParameterMessage::Controller14 { channel: 1, control: 7, value: 12345 },
+            ParameterMessage::Parameter { channel: 2, kind: ParameterKind::Nrpn, parameter: 1000, value: 16383 },
+            ParameterMessage::Parameter { channel: 16, kind: ParameterKind::Rpn, parameter: 5, value: 0 },
+            ParameterMessage::Increment { channel: 3, kind: ParameterKind::Nrpn, parameter: 300, amount: 1 },
+            ParameterMessage::Decrement { channel: 3, kind: ParameterKind::Rpn, parameter: 0, amount: 10 },
+            ParameterMessage::pitch_bend_range(1, 12, 50),
+        ];
+        for message in messages.iter() {
+            assert_eq!(decode(&mut decoder, message, false), Some(*message));
+        }
+    }
+    
+    #[test]
+    fn pitch_bend_range_is_rpn_0() {
+        let mut messages = [MidiMessage::TuneRequest; 7];
+        let count = ParameterMessage::pitch_bend_range(1, 24, 0).to_messages(true, &mut messages);
+        let controls: Vec<(u8, u8)> = messages[..count].iter().map(|m| match *m {
+            MidiMessage::ControlChange { control, value, .. } => (control, value),
+            _ => panic!(),
+        }).collect();
+        assert_eq!(controls, [(101, 0), (100, 0), (6, 24), (38, 0), (101, 127), (100, 127)]);
+    }
+    
+    #[test]
+    fn msb_alone_is_a_value() {
+        let mut decoder = ParameterDecoder::new(1 << 1);
+        let message = MidiMessage::ControlChange { channel: 1, control: 1, value: 64 };
+        assert_eq!(decoder.push(&message), Some(ParameterMessage::Controller14 { channel: 1, control: 1, value: 64 << 7 }));
+        let message = MidiMessage::ControlChange { channel: 1, control: 33, value: 5 };
+        assert_eq!(decoder.push(&message), Some(ParameterMessage::Controller14 { channel: 1, control: 1, value: (64 << 7) | 5 }));
+    }
+    
+    #[test]
+    fn controllers_outside_the_mask_are_left_alone() {
+        let mut decoder = ParameterDecoder::new(1 << 1);
+        assert_eq!(decoder.push(&MidiMessage::ControlChange { channel: 1, control: 7, value: 100 }), None);
+        assert_eq!(decoder.push(&MidiMessage::ControlChange { channel: 1, control: 39, value: 100 }), None);
+        assert_eq!(decoder.push(&MidiMessage::NoteOn { channel: 1, note: 1, velocity: 100 }), None);
+    }
+    
+    #[test]
+    fn data_entry_needs_a_selected_parameter() {
+        let mut decoder = ParameterDecoder::new(0);
+        assert_eq!(decoder.push(&MidiMessage::ControlChange { channel: 1, control: 6, value: 10 }), None);
+        assert_eq!(decoder.push(&MidiMessage::ControlChange { channel: 1, control: 96, value: 1 }), None);
+    }
+    
+    #[test]
+    fn null_parameter_deselects_rpn_and_nrpn() {
+        for kind in [ParameterKind::Rpn, ParameterKind::Nrpn] {
+            let mut decoder = ParameterDecoder::new(0);
+            let message = ParameterMessage::Parameter { channel: 1, kind: kind, parameter: 42, value: 1 };
+            assert_eq!(decode(&mut decoder, &message, true), Some(message));
+            assert_eq!(decoder.push(&MidiMessage::ControlChange { channel: 1, control: 6, value: 10 }), None);
+            
+            // the null parameter sent as NRPN numbers
+            decode(&mut decoder, &message, false);
+            decoder.push(&MidiMessage::ControlChange { channel: 1, control: 99, value: 127 });
+            decoder.push(&MidiMessage::ControlChange { channel: 1, control: 98, value: 127 });
+            assert_eq!(decoder.push(&MidiMessage::ControlChange { channel: 1, control: 6, value: 10 }), None);
+        }
+    }
+    
+    #[test]
+    fn channels_are_decoded_separately() {
+        let mut decoder = ParameterDecoder::new(0);
+        let message = ParameterMessage::Parameter { channel: 1, kind: ParameterKind::Nrpn, parameter: 42, value: 1 };
+        decode(&mut decoder, &message, false);
+        assert_eq!(decoder.push(&MidiMessage::ControlChange { channel: 2, control: 6, value: 10 }), None);
+        assert_eq!(
+            decoder.push(&MidiMessage::ControlChange { channel: 1, control: 6, value: 10 }),
+            Some(ParameterMessage::Parameter { channel: 1, kind: ParameterKind::Nrpn, parameter: 42, value: 10 << 7 }),
+        );
+    }
+    
+    #[test]
+    fn parses_what_to_bytes_writes() {
+        let messages = [
+            MidiMessage::NoteOff { channel: 1, note: 60, velocity: 64 },
+            MidiMessage::NoteOn { channel: 16, note: 127, velocity: 1 },
+            MidiMessage::PolyAftertouch { channel: 3, note: 10, pressure: 20 },
+            MidiMessage::ControlChange { channel: 4, control: 1, value: 2 },
+            MidiMessage::ProgramChange { channel: 5, program: 6 },
+            MidiMessage::ChannelAftertouch { channel: 6, pressure: 7 },
+            MidiMessage::PitchBend { channel: 7, value: 1234 },
+            MidiMessage::AllNotesOff { channel: 8 },
+            MidiMessage::MonoOn { channel: 9, channels: 0 },
+            MidiMessage::TimeCodeQuarterFrame { value: 0x71 },
+            MidiMessage::SongPosition { position: 16383 },
+            MidiMessage::SongSelect { song: 3 },
+            MidiMessage::TuneRequest,
+            MidiMessage::TimingClock,
+            MidiMessage::Start,
+            MidiMessage::Continue,
+            MidiMessage::Stop,
+            MidiMessage::ActiveSensing,
+            MidiMessage::SystemReset,
+        ];
+        let mut parser = MidiParser::<16>::new();
+        for message in messages.iter() {
+            let mut buf = [0u8; 3];
+            let len = message.to_bytes(&mut buf);
+            assert_eq!(parse(&mut parser, &buf[..len]), [*message]);
+            assert_eq!(MidiMessage::from_bytes(&buf), Some(*message));
+        }
+        assert_eq!(parser.errors(), 0);
+    }
+}
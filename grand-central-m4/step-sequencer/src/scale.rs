@@ -0,0 +1,101 @@
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Scale {
+    Chromatic,
+    Major,
+    Minor,
+    PentatonicMajor,
+    PentatonicMinor,
+    Dorian,
+    Mixolydian,
+    HarmonicMinor,
+}
+
+const SCALES: [Scale; 8] = [
+    Scale::Chromatic,
+    Scale::Major,
+    Scale::Minor,
+    Scale::PentatonicMajor,
+    Scale::PentatonicMinor,
+    Scale::Dorian,
+    Scale::Mixolydian,
+    Scale::HarmonicMinor,
+];
+
+impl Scale {
+    // semitone offsets from the root for each scale degree
+    pub fn intervals(&self) -> &'static [u8] {
+        match self {
+            Scale::Chromatic       => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+            Scale::Major           => &[0, 2, 4, 5, 7, 9, 11],
+            Scale::Minor           => &[0, 2, 3, 5, 7, 8, 10],
+            Scale::PentatonicMajor => &[0, 2, 4, 7, 9],
+            Scale::PentatonicMinor => &[0, 3, 5, 7, 10],
+            Scale::Dorian          => &[0, 2, 3, 5, 7, 9, 10],
+            Scale::Mixolydian      => &[0, 2, 4, 5, 7, 9, 10],
+            Scale::HarmonicMinor   => &[0, 2, 3, 5, 7, 8, 11],
+        }
+    }
+
+    pub fn next(&self) -> Scale {
+        let index = SCALES.iter().position(|s| s == self).unwrap_or(0);
+        SCALES[(index + 1) % SCALES.len()]
+    }
+
+    pub fn prev(&self) -> Scale {
+        let index = SCALES.iter().position(|s| s == self).unwrap_or(0);
+        SCALES[(index + SCALES.len() - 1) % SCALES.len()]
+    }
+
+    // snap a semitone (0..11, relative to the root) down to the nearest tone in the scale
+    pub fn quantize(&self, semitone: u8) -> u8 {
+        let semitone = semitone % 12;
+        let mut out = 0;
+        for interval in self.intervals().iter() {
+            if *interval <= semitone { out = *interval }
+        }
+        out
+    }
+
+    // move a semitone up one scale degree, returns the new semitone and the octave carry
+    pub fn step_up(&self, semitone: u8) -> (u8, i8) {
+        let current = self.quantize(semitone);
+        match self.intervals().iter().find(|i| **i > current) {
+            Some(v) => (*v, 0),
+            None => (self.intervals()[0], 1),
+        }
+    }
+
+    // move a semitone down one scale degree, returns the new semitone and the octave carry
+    pub fn step_down(&self, semitone: u8) -> (u8, i8) {
+        let current = self.quantize(semitone);
+        match self.intervals().iter().rev().find(|i| **i < current) {
+            Some(v) => (*v, 0),
+            None => (self.intervals()[self.intervals().len() - 1], -1),
+        }
+    }
+}
+
+pub struct ScaleSettings {
+    pub scale: Scale,
+    pub root: u8,       // 0..11, C = 0
+    pub transpose: i8,  // semitones, driven by incoming MIDI notes
+}
+
+impl ScaleSettings {
+    pub fn new() -> Self {
+        ScaleSettings {
+            scale: Scale::Minor,
+            root: 0,
+            transpose: 0,
+        }
+    }
+
+    // convert a step's semitone and octave into a MIDI note number
+    pub fn note(&self, semitone: u8, octave: u8) -> u8 {
+        let pitch = (octave as i16) * 12
+            + (self.root as i16)
+            + (self.scale.quantize(semitone) as i16)
+            + (self.transpose as i16);
+        pitch.max(0).min(127) as u8
+    }
+}
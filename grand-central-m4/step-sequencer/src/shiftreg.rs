@@ -0,0 +1,434 @@
+// 74HC165/74HC595 chains, shared with rp-pico/midi-out and the led-lamp projects which
+// include this file with #[path], so it only depends on embedded-hal
+use core::convert::Infallible;
+
+use embedded_hal::blocking::spi::{Transfer, Write};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ShiftError {
+    Spi,
+    Pin,
+}
+
+// a snapshot of parallel inputs, e.g. a chain of 74HC165s
+pub trait InputRegister {
+    // latch the parallel inputs and clock them in
+    fn read(&mut self) -> Result<(), ShiftError>;
+    fn len(&self) -> usize;
+    fn input(&self, index: usize) -> bool;
+}
+
+// a set of parallel outputs, e.g. a chain of 74HC595s
+pub trait OutputRegister {
+    fn len(&self) -> usize;
+    fn set_output(&mut self, index: usize, value: bool);
+    fn output(&self, index: usize) -> bool;
+    // clock the outputs out and latch them
+    fn write(&mut self) -> Result<(), ShiftError>;
+}
+
+// bit order shared by both chains: input/output index 0 is D0/Q0 of the chip nearest
+// the microcontroller, index 8 is D0/Q0 of the next chip and so on. SPI runs MSB first
+
+// a 74HC165 shifts out D7 first and the nearest chip's bits arrive first, so received
+// byte n holds chip n with D0 in bit 0
+pub fn input_bit(bytes: &[u8], index: usize) -> bool {
+    match bytes.get(index / 8) {
+        Some(v) => (v >> (index % 8)) & 1 == 1,
+        None => false,
+    }
+}
+
+pub fn set_bit(bytes: &mut [u8], index: usize, value: bool) {
+    if let Some(v) = bytes.get_mut(index / 8) {
+        if value == true {
+            *v |= 1 << (index % 8);
+        } else {
+            *v &= !(1 << (index % 8));
+        }
+    }
+}
+
+// a 74HC595 chain passes bits along, so the first byte sent ends up in the farthest chip.
+// the output bytes (chip n in byte n) are sent in reverse, with Q7 in the MSB
+pub fn output_order(bytes: &[u8], out: &mut [u8]) {
+    for (o, b) in out.iter_mut().zip(bytes.iter().rev()) {
+        *o = *b;
+    }
+}
+
+// N daisy-chained 74HC165s. the load pin (SH/LD) is pulsed low to capture the inputs,
+// then N bytes are clocked in over SPI (MISO to QH of the nearest chip)
+pub struct InputChain<SPI, L, const N: usize> {
+    spi: SPI,
+    load: L,
+    bytes: [u8; N],
+}
+
+impl<SPI: Transfer<u8>, L: OutputPin, const N: usize> InputChain<SPI, L, N> {
+    pub fn new(spi: SPI, mut load: L) -> Self {
+        let _ = load.set_high();
+        InputChain {
+            spi: spi,
+            load: load,
+            bytes: [0; N],
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8; N] {
+        &self.bytes
+    }
+
+    pub fn release(self) -> (SPI, L) {
+        (self.spi, self.load)
+    }
+}
+
+impl<SPI: Transfer<u8>, L: OutputPin, const N: usize> InputRegister for InputChain<SPI, L, N> {
+    fn read(&mut self) -> Result<(), ShiftError> {
+        self.load.set_low().map_err(|_| ShiftError::Pin)?;
+        self.load.set_high().map_err(|_| ShiftError::Pin)?;
+        let mut buf = [0u8; N];
+        self.spi.transfer(&mut buf).map_err(|_| ShiftError::Spi)?;
+        self.bytes = buf;
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        N * 8
+    }
+
+    fn input(&self, index: usize) -> bool {
+        input_bit(&self.bytes, index)
+    }
+}
+
+// N daisy-chained 74HC595s. N bytes are clocked out over SPI (MOSI to SER of the
+// nearest chip), then the latch pin (RCLK) is pulsed high to update the outputs
+pub struct OutputChain<SPI, L, const N: usize> {
+    spi: SPI,
+    latch: L,
+    bytes: [u8; N],
+}
+
+impl<SPI: Write<u8>, L: OutputPin, const N: usize> OutputChain<SPI, L, N> {
+    pub fn new(spi: SPI, mut latch: L) -> Self {
+        let _ = latch.set_low();
+        OutputChain {
+            spi: spi,
+            latch: latch,
+            bytes: [0; N],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.bytes = [0; N];
+    }
+
+    pub fn release(self) -> (SPI, L) {
+        (self.spi, self.latch)
+    }
+}
+
+impl<SPI: Write<u8>, L: OutputPin, const N: usize> OutputRegister for OutputChain<SPI, L, N> {
+    fn len(&self) -> usize {
+        N * 8
+    }
+
+    fn set_output(&mut self, index: usize, value: bool) {
+        set_bit(&mut self.bytes, index, value);
+    }
+
+    fn output(&self, index: usize) -> bool {
+        input_bit(&self.bytes, index)
+    }
+
+    fn write(&mut self) -> Result<(), ShiftError> {
+        let mut buf = [0u8; N];
+        output_order(&self.bytes, &mut buf);
+        self.spi.write(&buf).map_err(|_| ShiftError::Spi)?;
+        self.latch.set_high().map_err(|_| ShiftError::Pin)?;
+        self.latch.set_low().map_err(|_| ShiftError::Pin)?;
+        Ok(())
+    }
+}
+
+// SPI mode 0, MSB first, on any GPIO pins, for shift register chains on pins without a
+// free SERCOM. a chain that only reads or only writes passes NoPin for the other data pin
+pub struct BitBangSpi<C, O, I> {
+    clock: C,
+    data_out: O,
+    data_in: I,
+}
+
+impl<C: OutputPin, O: OutputPin, I: InputPin> BitBangSpi<C, O, I> {
+    pub fn new(mut clock: C, data_out: O, data_in: I) -> Self {
+        let _ = clock.set_low();
+        BitBangSpi {
+            clock: clock,
+            data_out: data_out,
+            data_in: data_in,
+        }
+    }
+
+    // each bit is set up and sampled before the rising clock edge, which is when both
+    // the 74HC165 and the 74HC595 shift
+    fn transfer_byte(&mut self, byte: u8) -> Result<u8, ShiftError> {
+        let mut received = 0;
+        for bit in (0..8).rev() {
+            if (byte >> bit) & 1 == 1 {
+                self.data_out.set_high().map_err(|_| ShiftError::Pin)?;
+            } else {
+                self.data_out.set_low().map_err(|_| ShiftError::Pin)?;
+            }
+            if self.data_in.is_high().map_err(|_| ShiftError::Pin)? == true {
+                received |= 1 << bit;
+            }
+            self.clock.set_high().map_err(|_| ShiftError::Pin)?;
+            self.clock.set_low().map_err(|_| ShiftError::Pin)?;
+        }
+        Ok(received)
+    }
+}
+
+impl<C: OutputPin, O: OutputPin, I: InputPin> Transfer<u8> for BitBangSpi<C, O, I> {
+    type Error = ShiftError;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], ShiftError> {
+        for word in words.iter_mut() {
+            *word = self.transfer_byte(*word)?;
+        }
+        Ok(words)
+    }
+}
+
+impl<C: OutputPin, O: OutputPin, I: InputPin> Write<u8> for BitBangSpi<C, O, I> {
+    type Error = ShiftError;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), ShiftError> {
+        for word in words.iter() {
+            self.transfer_byte(*word)?;
+        }
+        Ok(())
+    }
+}
+
+// an unconnected data pin, writes do nothing and reads are low
+pub struct NoPin;
+
+impl OutputPin for NoPin {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+impl InputPin for NoPin {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Infallible> {
+        Ok(false)
+    }
+
+    fn is_low(&self) -> Result<bool, Infallible> {
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    // stands in for the SPI bus, records what is written and answers transfers
+    struct FakeSpi {
+        written: Vec<u8>,
+        reply: Vec<u8>,
+    }
+
+    impl Transfer<u8> for FakeSpi {
+        type Error = Infallible;
+
+        fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Infallible> {
+            words.copy_from_slice(&self.reply[..words.len()]);
+            Ok(words)
+        }
+    }
+
+    impl Write<u8> for FakeSpi {
+        type Error = Infallible;
+
+        fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+            self.written.extend_from_slice(words);
+            Ok(())
+        }
+    }
+
+    // records every level it is set to
+    #[derive(Clone)]
+    struct FakePin(Rc<RefCell<Vec<bool>>>);
+
+    impl OutputPin for FakePin {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.0.borrow_mut().push(false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.0.borrow_mut().push(true);
+            Ok(())
+        }
+    }
+
+    fn fake_pin() -> (FakePin, Rc<RefCell<Vec<bool>>>) {
+        let levels = Rc::new(RefCell::new(Vec::new()));
+        (FakePin(levels.clone()), levels)
+    }
+
+    #[test]
+    fn input_bit_order() {
+        // chip 0 has D0 and D7 high, chip 1 has D1 high
+        let bytes = [0b1000_0001, 0b0000_0010];
+        let high: Vec<usize> = (0..16).filter(|i| input_bit(&bytes, *i)).collect();
+        assert_eq!(high, [0, 7, 9]);
+        assert_eq!(input_bit(&bytes, 16), false);
+    }
+
+    #[test]
+    fn set_bit_order() {
+        let mut bytes = [0u8; 2];
+        set_bit(&mut bytes, 0, true);
+        set_bit(&mut bytes, 7, true);
+        set_bit(&mut bytes, 9, true);
+        assert_eq!(bytes, [0b1000_0001, 0b0000_0010]);
+        set_bit(&mut bytes, 7, false);
+        set_bit(&mut bytes, 16, true);
+        assert_eq!(bytes, [0b0000_0001, 0b0000_0010]);
+    }
+
+    #[test]
+    fn input_chain_loads_and_reads() {
+        let (load, load_levels) = fake_pin();
+        let spi = FakeSpi { written: Vec::new(), reply: vec![0x01, 0x80, 0x40] };
+        let mut chain = InputChain::<_, _, 3>::new(spi, load);
+        chain.read().unwrap();
+        // high after new, then pulsed low to capture the inputs
+        assert_eq!(*load_levels.borrow(), [true, false, true]);
+        assert_eq!(chain.len(), 24);
+        let high: Vec<usize> = (0..24).filter(|i| chain.input(*i)).collect();
+        assert_eq!(high, [0, 15, 22]);
+    }
+
+    #[test]
+    fn output_chain_sends_the_farthest_chip_first() {
+        let (latch, latch_levels) = fake_pin();
+        let spi = FakeSpi { written: Vec::new(), reply: Vec::new() };
+        let mut chain = OutputChain::<_, _, 3>::new(spi, latch);
+        chain.set_output(0, true);
+        chain.set_output(9, true);
+        chain.set_output(23, true);
+        assert_eq!(chain.output(9), true);
+        assert_eq!(chain.output(10), false);
+        chain.write().unwrap();
+        let (spi, _) = chain.release();
+        assert_eq!(spi.written, [0x80, 0x02, 0x01]);
+        // low after new, then pulsed high once the bits are in
+        assert_eq!(*latch_levels.borrow(), [false, true, false]);
+    }
+
+    // a clock, data out and data in wired to a shift register model: data out is taken
+    // and the next input bit comes up on each rising clock edge
+    struct Bus {
+        clock: bool,
+        data_out: bool,
+        shifted_out: Vec<bool>,
+        data_in: VecDeque<bool>,
+    }
+
+    struct BusPin(Rc<RefCell<Bus>>, bool);  // true for the clock
+
+    impl OutputPin for BusPin {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            let mut bus = self.0.borrow_mut();
+            if self.1 == true { bus.clock = false } else { bus.data_out = false }
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            let mut bus = self.0.borrow_mut();
+            if self.1 == true {
+                if bus.clock == false {
+                    let bit = bus.data_out;
+                    bus.shifted_out.push(bit);
+                    bus.data_in.pop_front();
+                }
+                bus.clock = true;
+            } else {
+                bus.data_out = true;
+            }
+            Ok(())
+        }
+    }
+
+    impl InputPin for BusPin {
+        type Error = Infallible;
+
+        fn is_high(&self) -> Result<bool, Infallible> {
+            Ok(self.0.borrow().data_in.front() == Some(&true))
+        }
+
+        fn is_low(&self) -> Result<bool, Infallible> {
+            Ok(self.0.borrow().data_in.front() != Some(&true))
+        }
+    }
+
+    fn bits(bytes: &[u8]) -> Vec<bool> {
+        bytes.iter().flat_map(|b| (0..8).rev().map(move |i| (b >> i) & 1 == 1)).collect()
+    }
+
+    #[test]
+    fn bit_bang_is_msb_first() {
+        let bus = Rc::new(RefCell::new(Bus {
+            clock: false,
+            data_out: false,
+            shifted_out: Vec::new(),
+            data_in: bits(&[0xC3, 0x5A]).into_iter().collect(),
+        }));
+        let mut spi = BitBangSpi::new(BusPin(bus.clone(), true), BusPin(bus.clone(), false), BusPin(bus.clone(), false));
+        let mut words = [0xA5, 0x01];
+        spi.transfer(&mut words).unwrap();
+        assert_eq!(words, [0xC3, 0x5A]);
+        assert_eq!(bus.borrow().shifted_out, bits(&[0xA5, 0x01]));
+        assert_eq!(bus.borrow().clock, false);
+    }
+
+    #[test]
+    fn bit_bang_chains() {
+        // two 74HC165s read over bit banged SPI, the nearest chip's D7 comes out first
+        let bus = Rc::new(RefCell::new(Bus {
+            clock: false,
+            data_out: false,
+            shifted_out: Vec::new(),
+            data_in: bits(&[0b0000_0100, 0b1000_0000]).into_iter().collect(),
+        }));
+        let spi = BitBangSpi::new(BusPin(bus.clone(), true), NoPin, BusPin(bus.clone(), false));
+        let (load, _) = fake_pin();
+        let mut chain = InputChain::<_, _, 2>::new(spi, load);
+        chain.read().unwrap();
+        let high: Vec<usize> = (0..16).filter(|i| chain.input(*i)).collect();
+        assert_eq!(high, [2, 15]);
+    }
+}
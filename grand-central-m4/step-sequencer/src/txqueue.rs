@@ -0,0 +1,105 @@
+const REALTIME_SIZE: usize = 8;
+
+// fixed capacity transmit queue for a MIDI UART. the main loop pushes whole messages,
+// the UART interrupt pops one byte at a time. each message is stored behind a length
+// byte, so realtime bytes can be sent between two messages even when running status
+// leaves out the status byte
+pub struct TxQueue<const N: usize> {
+    buffer: [u8; N],
+    head: usize,
+    len: usize,
+    remaining: usize,  // bytes left of the message currently being sent
+    realtime: [u8; REALTIME_SIZE],
+    realtime_head: usize,
+    realtime_len: usize,
+    overflows: u32,
+}
+
+impl<const N: usize> TxQueue<N> {
+    pub const fn new() -> Self {
+        TxQueue {
+            buffer: [0; N],
+            head: 0,
+            len: 0,
+            remaining: 0,
+            realtime: [0; REALTIME_SIZE],
+            realtime_head: 0,
+            realtime_len: 0,
+            overflows: 0,
+        }
+    }
+    
+    // queues the whole message or none of it, returns false and counts an overflow
+    // when there isn't room. messages are limited to 255 bytes
+    pub fn push_message(&mut self, bytes: &[u8]) -> bool {
+        if bytes.len() == 0 { return true }
+        if bytes.len() > 255 || self.len + bytes.len() + 1 > N {
+            self.overflows = self.overflows.wrapping_add(1);
+            return false;
+        }
+        self.push_byte(bytes.len() as u8);
+        for byte in bytes.iter() {
+            self.push_byte(*byte);
+        }
+        true
+    }
+    
+    // true when `messages` messages of `bytes` bytes in total fit behind their length bytes,
+    // otherwise counts an overflow. for a group of messages that must go out whole
+    pub fn fits(&mut self, bytes: usize, messages: usize) -> bool {
+        if self.len + bytes + messages > N {
+            self.overflows = self.overflows.wrapping_add(1);
+            return false;
+        }
+        true
+    }
+    
+    // realtime bytes skip ahead of any queued messages
+    pub fn push_realtime(&mut self, byte: u8) -> bool {
+        if self.realtime_len >= REALTIME_SIZE {
+            self.overflows = self.overflows.wrapping_add(1);
+            return false;
+        }
+        self.realtime[(self.realtime_head + self.realtime_len) % REALTIME_SIZE] = byte;
+        self.realtime_len += 1;
+        true
+    }
+    
+    // next byte for the UART. pending realtime bytes go out as soon as the message
+    // being sent is complete
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.remaining == 0 {
+            if self.realtime_len > 0 {
+                let byte = self.realtime[self.realtime_head];
+                self.realtime_head = (self.realtime_head + 1) % REALTIME_SIZE;
+                self.realtime_len -= 1;
+                return Some(byte);
+            }
+            if self.len == 0 { return None }
+            self.remaining = self.pop_byte() as usize;
+        }
+        self.remaining -= 1;
+        Some(self.pop_byte())
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.len == 0 && self.realtime_len == 0
+    }
+    
+    // number of messages dropped because the queue was full
+    pub fn overflows(&self) -> u32 {
+        self.overflows
+    }
+    
+    fn push_byte(&mut self, byte: u8) {
+        self.buffer[(self.head + self.len) % N] = byte;
+        self.len += 1;
+    }
+    
+    fn pop_byte(&mut self) -> u8 {
+        let byte = self.buffer[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        byte
+    }
+}
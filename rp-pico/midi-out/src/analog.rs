@@ -0,0 +1,129 @@
+use crate::buffer::RingBuffer;
+
+// the RP2040 ADC is 12-bit
+pub const ADC_MAX: u16 = 4095;
+// number of samples averaged for each reading
+const SMOOTHING: usize = 8;
+// the 8 channel multiplexer is optional, while any input uses it ADC2 (gpio28) reads its
+// common pin, otherwise ADC2 can have a pot of its own
+pub const MUX_CHANNELS: u8 = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AnalogSource {
+    Adc(u8),  // ADC0 (gpio26), ADC1 (gpio27) or ADC2 (gpio28)
+    Mux(u8),  // multiplexer input 0-7
+}
+
+// a pot or fader sending a controller, channels are 1-16. high resolution controllers
+// must be 0-31, the LSB is sent on the same controller + 32
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AnalogMapping {
+    pub source: AnalogSource,
+    pub channel: u8,
+    pub control: u8,
+    pub high_res: bool,
+}
+
+// ADC0-2 can be used directly, high resolution controllers must be 0-31
+// and 120-127 are channel mode messages
+pub const fn mapping_valid(mapping: &AnalogMapping) -> bool {
+    let source = match mapping.source {
+        AnalogSource::Adc(n) => n <= 2,
+        AnalogSource::Mux(n) => n < MUX_CHANNELS,
+    };
+    let control = if mapping.high_res == true { mapping.control <= 31 } else { mapping.control <= 119 };
+    source && control && mapping.channel >= 1 && mapping.channel <= 16
+}
+
+// true when any input is on the multiplexer, main only drives its select pins then
+pub const fn uses_mux(inputs: &[AnalogMapping]) -> bool {
+    let mut i = 0;
+    while i < inputs.len() {
+        if let AnalogSource::Mux(_) = inputs[i].source { return true }
+        i += 1;
+    }
+    false
+}
+
+// checks an analog mapping table at compile time, like controls::validate
+pub const fn validate(inputs: &[AnalogMapping]) -> bool {
+    let mux = uses_mux(inputs);
+    let mut i = 0;
+    while i < inputs.len() {
+        let input = &inputs[i];
+        assert!(mapping_valid(input), "analog input value out of range");
+        if let AnalogSource::Adc(n) = input.source {
+            assert!(n != 2 || mux == false, "ADC2 is the multiplexer common pin while an input uses the multiplexer");
+        }
+        let mut j = i + 1;
+        while j < inputs.len() {
+            let same = match (inputs[j].source, input.source) {
+                (AnalogSource::Adc(a), AnalogSource::Adc(b)) => a == b,
+                (AnalogSource::Mux(a), AnalogSource::Mux(b)) => a == b,
+                _ => false,
+            };
+            assert!(same == false, "analog source mapped to more than one input");
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+// smooths the raw ADC readings and only reports a value once the reading has moved
+// further than the deadband from the last reported reading, so a pot sitting between
+// two values or picking up noise doesn't keep sending
+pub struct AnalogControl {
+    pub mapping: AnalogMapping,
+    buffer: RingBuffer<u16, SMOOTHING>,
+    samples: usize,
+    deadband: u16,
+    last_raw: Option<u16>,
+    last_value: Option<u16>,
+}
+
+impl AnalogControl {
+    pub fn new(mapping: AnalogMapping, deadband: u16) -> Self {
+        AnalogControl {
+            mapping: mapping,
+            buffer: RingBuffer::<u16, SMOOTHING>::new(),
+            samples: 0,
+            deadband: deadband,
+            last_raw: None,
+            last_value: None,
+        }
+    }
+    
+    // the controller value, 0-127 or 0-16383 for high resolution
+    pub fn value(&self) -> Option<u16> {
+        self.last_value
+    }
+    
+    // add a raw reading, returns the new controller value when it changed
+    pub fn update(&mut self, raw: u16) -> Option<u16> {
+        self.buffer.push(raw.min(ADC_MAX));
+        // wait until the buffer is full so the first value isn't averaged with zeros
+        if self.samples < SMOOTHING {
+            self.samples += 1;
+            if self.samples < SMOOTHING { return None }
+        }
+        
+        let mut smoothed = self.buffer.mean();
+        // snap to the ends so the full range can be reached through the deadband
+        if smoothed <= self.deadband { smoothed = 0 }
+        if smoothed >= ADC_MAX - self.deadband { smoothed = ADC_MAX }
+        
+        if let Some(last) = self.last_raw {
+            let moved = if smoothed > last { smoothed - last } else { last - smoothed };
+            let at_end = smoothed == 0 || smoothed == ADC_MAX;
+            if moved <= self.deadband && (at_end == false || moved == 0) { return None }
+        }
+        self.last_raw = Some(smoothed);
+        
+        let max = if self.mapping.high_res == true { 16383 } else { 127 };
+        let value = ((smoothed as u32 * max + ADC_MAX as u32 / 2) / ADC_MAX as u32) as u16;
+        if self.last_value == Some(value) { return None }
+        self.last_value = Some(value);
+        Some(value)
+    }
+}
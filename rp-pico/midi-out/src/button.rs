@@ -1,10 +1,65 @@
-use rp2040_hal::gpio::PinId;
-use rp2040_hal::gpio::{Input, Pin, PullUp};
 use embedded_hal::digital::v2::InputPin;
+use crate::shiftreg::InputRegister;
 //~ use atsamd_hal::prelude::_atsamd_hal_embedded_hal_digital_v2_InputPin;
 
-pub struct Button<P: PinId> {
-    pin: Pin<P, Input<PullUp>>,
+// any active-low input pin, usually a pull-up input or a type-erased DynPin
+pub struct Button<P: InputPin> {
+    pin: P,
+    debouncer: Debouncer,
+    events: ButtonEvents,
+}
+
+impl<P: InputPin> Button<P> {
+    pub fn new(pin: P, debounce_time: u32) -> Self {
+        Button {
+            pin: pin,
+            debouncer: Debouncer::new(debounce_time),
+            events: ButtonEvents::disabled(),
+        }
+    }
+    
+    pub fn with_events(mut self, timing: EventTiming) -> Self {
+        self.events = ButtonEvents::new(timing);
+        self
+    }
+    
+    pub fn poll(&mut self, millis: u32) {
+        let current_state = self.pin.is_low().unwrap_or(false);
+        self.debouncer.update(current_state, millis);
+        self.events.update(self.debouncer.state, millis);
+    }
+    
+    pub fn next_event(&mut self) -> Option<ButtonEvent> {
+        self.events.next_event()
+    }
+    
+    pub fn press_duration(&self, millis: u32) -> u32 {
+        self.events.press_duration(self.debouncer.state, millis)
+    }
+    
+    pub fn state(&self) -> bool {
+        self.debouncer.state()
+    }
+    
+    pub fn last_state(&self) -> bool {
+        self.debouncer.last_state()
+    }
+    
+    pub fn toggle_state(&self) -> bool {
+        self.debouncer.toggle_state()
+    }
+    
+    pub fn rising_edge(&self) -> bool {
+        self.debouncer.rising_edge()
+    }
+    
+    pub fn falling_edge(&self) -> bool {
+        self.debouncer.falling_edge()
+    }
+}
+
+// the debounce state machine, separate from the pin so it can be fed from other inputs
+pub struct Debouncer {
     debounce_time: u32,
     debounce_max: u32,
     debounce_state: DebounceState,
@@ -15,10 +70,9 @@ pub struct Button<P: PinId> {
     last_off: u32,
 }
 
-impl<P: PinId> Button<P> {
-    pub fn new(pin: Pin<P, Input<PullUp>>, debounce_time: u32) -> Self {
-        Button {
-            pin: pin,
+impl Debouncer {
+    pub fn new(debounce_time: u32) -> Self {
+        Debouncer {
             debounce_time: debounce_time,
             debounce_max: 20,
             debounce_state: DebounceState::Off,
@@ -30,8 +84,7 @@ impl<P: PinId> Button<P> {
         }
     }
     
-    pub fn poll(&mut self, millis: u32) {
-        let current_state = self.pin.is_low().unwrap();
+    pub fn update(&mut self, current_state: bool, millis: u32) {
         let last_on_delta = sub_handle_overflow(millis, self.last_on);
         let last_off_delta = sub_handle_overflow(millis, self.last_off);
         self.last_state = self.state;
@@ -88,6 +141,245 @@ impl<P: PinId> Button<P> {
     pub fn toggle_state(&self) -> bool {
         self.toggle_state
     }
+    
+    pub fn rising_edge(&self) -> bool {
+        if self.state == true && self.last_state == false {
+            true
+        } else {
+            false
+        }
+    }
+    
+    pub fn falling_edge(&self) -> bool {
+        if self.state == false && self.last_state == true {
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// debounced buttons read through an InputRegister, such as a chain of 74HC165s.
+// the register is read first, then update passes every input through its own debouncer
+pub struct ShiftButtons<const N: usize> {
+    keys: [Debouncer; N],
+    active_low: bool,  // pull-up inputs read low while pressed
+}
+
+impl<const N: usize> ShiftButtons<N> {
+    pub fn new(debounce_time: u32, active_low: bool) -> Self {
+        ShiftButtons {
+            keys: [(); N].map(|_| Debouncer::new(debounce_time)),
+            active_low: active_low,
+        }
+    }
+    
+    pub fn update<R: InputRegister>(&mut self, register: &R, millis: u32) {
+        for (index, key) in self.keys.iter_mut().enumerate() {
+            let current_state = register.input(index) != self.active_low;
+            key.update(current_state, millis);
+        }
+    }
+    
+    pub fn key(&self, index: usize) -> Option<&Debouncer> {
+        self.keys.get(index)
+    }
+    
+    pub fn state(&self, index: usize) -> bool {
+        self.keys.get(index).map_or(false, |k| k.state())
+    }
+    
+    pub fn toggle_state(&self, index: usize) -> bool {
+        self.keys.get(index).map_or(false, |k| k.toggle_state())
+    }
+    
+    pub fn rising_edge(&self, index: usize) -> bool {
+        self.keys.get(index).map_or(false, |k| k.rising_edge())
+    }
+    
+    pub fn falling_edge(&self, index: usize) -> bool {
+        self.keys.get(index).map_or(false, |k| k.falling_edge())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ButtonEvent {
+    Press,        // debounced rising edge
+    Release,      // debounced falling edge
+    ShortPress,   // released before the long press time
+    LongPress,    // held for the long press time
+    DoublePress,  // pressed again within the double press window after a short press
+    Repeat,       // fired repeatedly while held, after the repeat delay
+}
+
+// times use the same unit as the value passed to poll, a value of 0 disables the event
+#[derive(Debug, Copy, Clone)]
+pub struct EventTiming {
+    pub long_press: u32,
+    pub double_press: u32,
+    pub repeat_delay: u32,
+    pub repeat_rate: u32,
+}
+
+impl EventTiming {
+    pub fn disabled() -> Self {
+        EventTiming {
+            long_press: 0,
+            double_press: 0,
+            repeat_delay: 0,
+            repeat_rate: 0,
+        }
+    }
+}
+
+const EVENT_QUEUE_SIZE: usize = 8;
+
+// fixed size FIFO, new events are dropped when it is full
+pub struct EventQueue {
+    events: [ButtonEvent; EVENT_QUEUE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl EventQueue {
+    pub fn new() -> Self {
+        EventQueue {
+            events: [ButtonEvent::Press; EVENT_QUEUE_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+    
+    pub fn push(&mut self, event: ButtonEvent) {
+        if self.len >= EVENT_QUEUE_SIZE { return }
+        self.events[(self.head + self.len) % EVENT_QUEUE_SIZE] = event;
+        self.len += 1;
+    }
+    
+    pub fn pop(&mut self) -> Option<ButtonEvent> {
+        if self.len == 0 { return None }
+        let event = self.events[self.head];
+        self.head = (self.head + 1) % EVENT_QUEUE_SIZE;
+        self.len -= 1;
+        Some(event)
+    }
+    
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+}
+
+// turns the debounced state into timed events. it only sees a bool and a time, so it
+// can be driven by any input source
+pub struct ButtonEvents {
+    timing: EventTiming,
+    queue: EventQueue,
+    queue_events: bool,  // false when nothing reads the events
+    last_state: bool,
+    press_time: u32,
+    release_time: u32,
+    last_repeat: u32,
+    long_sent: bool,
+    repeating: bool,
+    double_armed: bool,  // the last press was short, the next one may be a double press
+    double_sent: bool,   // the current press is the second half of a double press
+}
+
+impl ButtonEvents {
+    pub fn new(timing: EventTiming) -> Self {
+        ButtonEvents {
+            timing: timing,
+            queue: EventQueue::new(),
+            queue_events: true,
+            last_state: false,
+            press_time: 0,
+            release_time: 0,
+            last_repeat: 0,
+            long_sent: false,
+            repeating: false,
+            double_armed: false,
+            double_sent: false,
+        }
+    }
+    
+    // only keeps the press time for press_duration, without queueing events that would
+    // never be read
+    pub fn disabled() -> Self {
+        let mut events = ButtonEvents::new(EventTiming::disabled());
+        events.queue_events = false;
+        events
+    }
+    
+    pub fn update(&mut self, state: bool, millis: u32) {
+        if state == true && self.last_state == false {
+            self.push(ButtonEvent::Press);
+            self.double_sent = false;
+            if self.double_armed == true && self.timing.double_press > 0
+                && sub_handle_overflow(millis, self.release_time) <= self.timing.double_press {
+                self.push(ButtonEvent::DoublePress);
+                self.double_sent = true;
+            }
+            self.press_time = millis;
+            self.long_sent = false;
+            self.repeating = false;
+        }
+        
+        if state == true {
+            let held = sub_handle_overflow(millis, self.press_time);
+            if self.timing.long_press > 0 && self.long_sent == false && held >= self.timing.long_press {
+                self.push(ButtonEvent::LongPress);
+                self.long_sent = true;
+            }
+            if self.timing.repeat_delay > 0 && self.timing.repeat_rate > 0 {
+                if self.repeating == false && held >= self.timing.repeat_delay {
+                    self.push(ButtonEvent::Repeat);
+                    self.repeating = true;
+                    self.last_repeat = millis;
+                } else if self.repeating == true && sub_handle_overflow(millis, self.last_repeat) >= self.timing.repeat_rate {
+                    self.push(ButtonEvent::Repeat);
+                    self.last_repeat = self.last_repeat.wrapping_add(self.timing.repeat_rate);
+                }
+            }
+        }
+        
+        if state == false && self.last_state == true {
+            self.push(ButtonEvent::Release);
+            if self.long_sent == false {
+                self.push(ButtonEvent::ShortPress);
+                // a third press starts over instead of being another double press
+                self.double_armed = !self.double_sent;
+            } else {
+                self.double_armed = false;
+            }
+            self.release_time = millis;
+        }
+        
+        self.last_state = state;
+    }
+    
+    pub fn next_event(&mut self) -> Option<ButtonEvent> {
+        self.queue.pop()
+    }
+    
+    pub fn clear(&mut self) {
+        self.queue.clear();
+    }
+    
+    fn push(&mut self, event: ButtonEvent) {
+        if self.queue_events == true {
+            self.queue.push(event);
+        }
+    }
+    
+    // how long the button has been held, or 0 when it is released
+    pub fn press_duration(&self, state: bool, millis: u32) -> u32 {
+        if state == true {
+            sub_handle_overflow(millis, self.press_time)
+        } else {
+            0
+        }
+    }
 }
 
 fn sub_handle_overflow(millis: u32, last_time: u32) -> u32 {
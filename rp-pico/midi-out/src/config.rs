@@ -0,0 +1,650 @@
+use crate::analog::{self, AnalogMapping};
+use crate::controls::{self, Action, InputMapping};
+use crate::flash::PAGE_SIZE;
+use crate::router::{Filter, Route, Transform};
+use crate::velocity::VelocityCurve;
+
+// SysEx messages for this board start with the non-commercial manufacturer id and a
+// device id: F0 7D 4D <command> <data...> F7
+pub const SYSEX_ID: u8 = 0x7D;
+pub const DEVICE_ID: u8 = 0x4D;
+
+// requests
+const GET_INFO: u8 = 0x01;       // -> INFO version inputs analog_inputs routes
+const GET_INPUT: u8 = 0x02;      // index -> INPUT index channel kind a b c
+const SET_INPUT: u8 = 0x03;      // index channel kind a b c -> ACK
+const GET_ANALOG: u8 = 0x04;     // index -> ANALOG index channel control high_res
+const SET_ANALOG: u8 = 0x05;     // index channel control high_res -> ACK
+const SAVE: u8 = 0x06;           // write the settings to flash -> ACK
+const LOAD_DEFAULTS: u8 = 0x07;  // back to the tables in main, not saved until SAVE -> ACK
+const GET_ROUTE: u8 = 0x08;      // index -> ROUTE index route
+const SET_ROUTE: u8 = 0x09;      // index route -> ACK
+// replies
+const INFO: u8 = 0x41;
+const INPUT: u8 = 0x42;
+const ANALOG: u8 = 0x44;
+const ROUTE: u8 = 0x48;
+const ACK: u8 = 0x7F;            // command status
+
+const STATUS_OK: u8 = 0;
+const STATUS_BAD_INDEX: u8 = 1;
+const STATUS_BAD_VALUE: u8 = 2;
+const STATUS_BAD_LENGTH: u8 = 3;
+const STATUS_UNKNOWN: u8 = 4;
+
+// stored settings start with this header, a different version or input count on
+// the board falls back to the defaults
+const MAGIC: [u8; 4] = *b"MOCF";
+pub const VERSION: u8 = 2;
+const HEADER_SIZE: usize = 11;
+const INPUT_SIZE: usize = 5;
+const ANALOG_SIZE: usize = 3;
+const ROUTE_SIZE: usize = 17;
+
+pub const REPLY_SIZE: usize = 24;
+
+// which message each input sends and the routes between the MIDI ports. the gpio or
+// ADC of each input comes from the defaults and can't be changed at runtime, only what
+// it sends
+pub struct Config<const I: usize, const A: usize, const R: usize> {
+    pub inputs: [InputMapping; I],
+    pub analog: [AnalogMapping; A],
+    pub routes: [Route; R],
+    defaults: ([InputMapping; I], [AnalogMapping; A], [Route; R]),
+}
+
+// what a config SysEx message asks the caller to do after the reply is sent
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Response {
+    pub len: usize,
+    pub changed: bool,  // the mappings changed and should be applied to the inputs
+    pub save: bool,     // the settings should be written to flash
+}
+
+impl<const I: usize, const A: usize, const R: usize> Config<I, A, R> {
+    // the whole config has to fit in one flash page
+    const FITS: () = assert!(
+        HEADER_SIZE + I * INPUT_SIZE + A * ANALOG_SIZE + R * ROUTE_SIZE <= PAGE_SIZE && I <= 127 && A <= 127 && R <= 127
+    );
+
+    pub fn new(inputs: [InputMapping; I], analog: [AnalogMapping; A], routes: [Route; R]) -> Self {
+        let _ = Self::FITS;
+        Config {
+            inputs: inputs,
+            analog: analog,
+            routes: routes,
+            defaults: (inputs, analog, routes),
+        }
+    }
+
+    pub fn load_defaults(&mut self) {
+        self.inputs = self.defaults.0;
+        self.analog = self.defaults.1;
+        self.routes = self.defaults.2;
+    }
+
+    // header: magic (4), version, input count, analog count, route count, payload length (2), checksum
+    pub fn to_bytes(&self, page: &mut [u8; PAGE_SIZE]) {
+        *page = [0xFF; PAGE_SIZE];
+        let mut pos = HEADER_SIZE;
+        for input in self.inputs.iter() {
+            page[pos] = input.channel;
+            page[pos + 1..pos + 5].copy_from_slice(&action_to_bytes(&input.action));
+            pos += INPUT_SIZE;
+        }
+        for input in self.analog.iter() {
+            page[pos..pos + 3].copy_from_slice(&[input.channel, input.control, input.high_res as u8]);
+            pos += ANALOG_SIZE;
+        }
+        for route in self.routes.iter() {
+            page[pos..pos + ROUTE_SIZE].copy_from_slice(&route_to_bytes(route));
+            pos += ROUTE_SIZE;
+        }
+        let len = (pos - HEADER_SIZE) as u16;
+        let sum = checksum(&page[HEADER_SIZE..pos]);
+        page[0..4].copy_from_slice(&MAGIC);
+        page[4] = VERSION;
+        page[5] = I as u8;
+        page[6] = A as u8;
+        page[7] = R as u8;
+        page[8..10].copy_from_slice(&len.to_le_bytes());
+        page[10] = sum;
+    }
+
+    // reads settings written by to_bytes, anything that doesn't check out leaves the
+    // config unchanged and returns false
+    pub fn load(&mut self, page: &[u8]) -> bool {
+        if page.len() < HEADER_SIZE || page[0..4] != MAGIC || page[4] != VERSION { return false }
+        if page[5] as usize != I || page[6] as usize != A || page[7] as usize != R { return false }
+        let len = u16::from_le_bytes([page[8], page[9]]) as usize;
+        if len != I * INPUT_SIZE + A * ANALOG_SIZE + R * ROUTE_SIZE || page.len() < HEADER_SIZE + len { return false }
+        if checksum(&page[HEADER_SIZE..HEADER_SIZE + len]) != page[10] { return false }
+
+        let mut inputs = self.defaults.0;
+        let mut analog = self.defaults.1;
+        let mut routes = self.defaults.2;
+        let mut pos = HEADER_SIZE;
+        for input in inputs.iter_mut() {
+            match input_from_bytes(input, &page[pos..pos + INPUT_SIZE]) {
+                Some(mapping) => *input = mapping,
+                None => return false,
+            }
+            pos += INPUT_SIZE;
+        }
+        for input in analog.iter_mut() {
+            match analog_from_bytes(input, &page[pos..pos + ANALOG_SIZE]) {
+                Some(mapping) => *input = mapping,
+                None => return false,
+            }
+            pos += ANALOG_SIZE;
+        }
+        for route in routes.iter_mut() {
+            match route_from_bytes(&page[pos..pos + ROUTE_SIZE]) {
+                Some(loaded) => *route = loaded,
+                None => return false,
+            }
+            pos += ROUTE_SIZE;
+        }
+        self.inputs = inputs;
+        self.analog = analog;
+        self.routes = routes;
+        true
+    }
+
+    // handles a config SysEx message (data without F0/F7), the reply goes into reply
+    // and should be sent back to where the request came from
+    pub fn handle_sysex(&mut self, data: &[u8], reply: &mut [u8; REPLY_SIZE]) -> Response {
+        let mut response = Response { len: 0, changed: false, save: false };
+        if is_config_sysex(data) == false || data.len() < 3 { return response }
+        reply[0] = SYSEX_ID;
+        reply[1] = DEVICE_ID;
+        let command = data[2];
+        let args = &data[3..];
+
+        let status = match command {
+            GET_INFO => {
+                reply[2..7].copy_from_slice(&[INFO, VERSION, I as u8, A as u8, R as u8]);
+                response.len = 7;
+                return response;
+            }
+            GET_INPUT => match args.first().and_then(|i| self.inputs.get(*i as usize)) {
+                Some(input) => {
+                    reply[2] = INPUT;
+                    reply[3] = args[0];
+                    reply[4] = input.channel;
+                    reply[5..9].copy_from_slice(&action_to_bytes(&input.action));
+                    response.len = 9;
+                    return response;
+                }
+                None => STATUS_BAD_INDEX,
+            },
+            GET_ANALOG => match args.first().and_then(|i| self.analog.get(*i as usize)) {
+                Some(input) => {
+                    reply[2..7].copy_from_slice(&[ANALOG, args[0], input.channel, input.control, input.high_res as u8]);
+                    response.len = 7;
+                    return response;
+                }
+                None => STATUS_BAD_INDEX,
+            },
+            SET_INPUT if args.len() != 1 + INPUT_SIZE => STATUS_BAD_LENGTH,
+            SET_INPUT => match self.inputs.get(args[0] as usize) {
+                Some(input) => match input_from_bytes(input, &args[1..]) {
+                    Some(mapping) => {
+                        self.inputs[args[0] as usize] = mapping;
+                        response.changed = true;
+                        STATUS_OK
+                    }
+                    None => STATUS_BAD_VALUE,
+                },
+                None => STATUS_BAD_INDEX,
+            },
+            SET_ANALOG if args.len() != 1 + ANALOG_SIZE => STATUS_BAD_LENGTH,
+            SET_ANALOG => match self.analog.get(args[0] as usize) {
+                Some(input) => match analog_from_bytes(input, &args[1..]) {
+                    Some(mapping) => {
+                        self.analog[args[0] as usize] = mapping;
+                        response.changed = true;
+                        STATUS_OK
+                    }
+                    None => STATUS_BAD_VALUE,
+                },
+                None => STATUS_BAD_INDEX,
+            },
+            GET_ROUTE => match args.first().and_then(|i| self.routes.get(*i as usize)) {
+                Some(route) => {
+                    reply[2] = ROUTE;
+                    reply[3] = args[0];
+                    reply[4..4 + ROUTE_SIZE].copy_from_slice(&route_to_bytes(route));
+                    response.len = 4 + ROUTE_SIZE;
+                    return response;
+                }
+                None => STATUS_BAD_INDEX,
+            },
+            SET_ROUTE if args.len() != 1 + ROUTE_SIZE => STATUS_BAD_LENGTH,
+            SET_ROUTE if args[0] as usize >= R => STATUS_BAD_INDEX,
+            SET_ROUTE => match route_from_bytes(&args[1..]) {
+                Some(route) => {
+                    self.routes[args[0] as usize] = route;
+                    response.changed = true;
+                    STATUS_OK
+                }
+                None => STATUS_BAD_VALUE,
+            },
+            SAVE => {
+                response.save = true;
+                STATUS_OK
+            }
+            LOAD_DEFAULTS => {
+                self.load_defaults();
+                response.changed = true;
+                STATUS_OK
+            }
+            _ => STATUS_UNKNOWN,
+        };
+        reply[2..5].copy_from_slice(&[ACK, command, status]);
+        response.len = 5;
+        response
+    }
+}
+
+pub fn is_config_sysex(data: &[u8]) -> bool {
+    data.len() >= 2 && data[0] == SYSEX_ID && data[1] == DEVICE_ID
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+// kind, then up to 3 data bytes
+fn action_to_bytes(action: &Action) -> [u8; 4] {
+    match *action {
+        Action::Note { note, velocity } => [0, note, velocity, 0],
+        Action::ControlMomentary { control, on, off } => [1, control, on, off],
+        Action::ControlToggle { control, on, off } => [2, control, on, off],
+        Action::ProgramChange { program } => [3, program, 0, 0],
+        Action::Start => [4, 0, 0, 0],
+        Action::Stop => [5, 0, 0, 0],
+        Action::Continue => [6, 0, 0, 0],
+    }
+}
+
+// channel, kind and 3 data bytes onto the gpio of an existing input
+fn input_from_bytes(input: &InputMapping, bytes: &[u8]) -> Option<InputMapping> {
+    if bytes.len() < INPUT_SIZE { return None }
+    let (a, b, c) = (bytes[2], bytes[3], bytes[4]);
+    let action = match bytes[1] {
+        0 => Action::Note { note: a, velocity: b },
+        1 => Action::ControlMomentary { control: a, on: b, off: c },
+        2 => Action::ControlToggle { control: a, on: b, off: c },
+        3 => Action::ProgramChange { program: a },
+        4 => Action::Start,
+        5 => Action::Stop,
+        6 => Action::Continue,
+        _ => return None,
+    };
+    let mapping = InputMapping { gpio: input.gpio, channel: bytes[0], action: action };
+    if mapping.channel < 1 || mapping.channel > 16 || controls::action_valid(&action) == false { return None }
+    Some(mapping)
+}
+
+// channel, control and high resolution flag onto the source of an existing analog input
+fn analog_from_bytes(input: &AnalogMapping, bytes: &[u8]) -> Option<AnalogMapping> {
+    if bytes.len() < ANALOG_SIZE || bytes[2] > 1 { return None }
+    let mapping = AnalogMapping { source: input.source, channel: bytes[0], control: bytes[1], high_res: bytes[2] == 1 };
+    if analog::mapping_valid(&mapping) == false { return None }
+    Some(mapping)
+}
+
+// enabled, input, outputs, channel mask (3 bytes of 7 bits), type mask (2), lowest note,
+// highest note, channel, transpose + 64, curve, fixed velocity, velocity scale, CC from, CC to
+fn route_to_bytes(route: &Route) -> [u8; ROUTE_SIZE] {
+    let filter = &route.filter;
+    let transform = &route.transform;
+    let (curve, fixed) = match transform.velocity_curve {
+        VelocityCurve::Linear => (0, 0),
+        VelocityCurve::Log => (1, 0),
+        VelocityCurve::Exp => (2, 0),
+        VelocityCurve::Fixed(velocity) => (3, velocity),
+    };
+    [
+        route.enabled as u8,
+        route.input,
+        route.outputs,
+        (filter.channels & 0x7F) as u8,
+        ((filter.channels >> 7) & 0x7F) as u8,
+        (filter.channels >> 14) as u8,
+        (filter.types & 0x7F) as u8,
+        (filter.types >> 7) as u8,
+        filter.note_low,
+        filter.note_high,
+        transform.channel,
+        (transform.transpose as i16 + 64) as u8,
+        curve,
+        fixed,
+        transform.velocity_scale,
+        transform.cc_from,
+        transform.cc_to,
+    ]
+}
+
+fn route_from_bytes(bytes: &[u8]) -> Option<Route> {
+    if bytes.len() < ROUTE_SIZE || bytes.iter().any(|b| *b > 127) || bytes[0] > 1 { return None }
+    let velocity_curve = match bytes[12] {
+        0 => VelocityCurve::Linear,
+        1 => VelocityCurve::Log,
+        2 => VelocityCurve::Exp,
+        3 => VelocityCurve::Fixed(bytes[13]),
+        _ => return None,
+    };
+    let route = Route {
+        enabled: bytes[0] == 1,
+        input: bytes[1],
+        outputs: bytes[2],
+        filter: Filter {
+            channels: (bytes[3] as u16) | ((bytes[4] as u16) << 7) | ((bytes[5] as u16) << 14),
+            types: (bytes[6] as u16) | ((bytes[7] as u16) << 7),
+            note_low: bytes[8],
+            note_high: bytes[9],
+        },
+        transform: Transform {
+            channel: bytes[10],
+            transpose: (bytes[11] as i16 - 64) as i8,
+            velocity_curve: velocity_curve,
+            velocity_scale: bytes[14],
+            cc_from: bytes[15],
+            cc_to: bytes[16],
+        },
+    };
+    if route.valid() == false { return None }
+    Some(route)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::{DIN1, DIN2, TYPE_CONTROL, TYPE_NOTE, USB};
+    use std::vec::Vec;
+
+    fn config() -> Config<8, 4, 8> {
+        Config::new(crate::INPUTS, crate::ANALOG_INPUTS, crate::ROUTES)
+    }
+
+    // a config that differs from the defaults in every table
+    fn changed() -> Config<8, 4, 8> {
+        let mut config = config();
+        config.inputs[0].channel = 10;
+        config.inputs[0].action = Action::ControlToggle { control: 20, on: 100, off: 5 };
+        config.inputs[7].action = Action::ProgramChange { program: 42 };
+        config.analog[1].control = 11;
+        config.analog[1].high_res = true;
+        config.routes[3] = Route {
+            enabled: true,
+            input: DIN2,
+            outputs: (1 << DIN1) | (1 << USB),
+            filter: Filter { channels: 0x8001, types: TYPE_NOTE | TYPE_CONTROL, note_low: 36, note_high: 96 },
+            transform: Transform {
+                channel: 3,
+                transpose: -12,
+                velocity_curve: VelocityCurve::Fixed(90),
+                velocity_scale: 80,
+                cc_from: 1,
+                cc_to: 74,
+            },
+        };
+        config
+    }
+
+    fn page(config: &Config<8, 4, 8>) -> [u8; PAGE_SIZE] {
+        let mut page = [0u8; PAGE_SIZE];
+        config.to_bytes(&mut page);
+        page
+    }
+
+    // the stored checksum matching the payload again after it was changed on purpose
+    fn fix_checksum(page: &mut [u8; PAGE_SIZE]) {
+        let len = u16::from_le_bytes([page[8], page[9]]) as usize;
+        page[10] = checksum(&page[HEADER_SIZE..HEADER_SIZE + len]);
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        let saved = changed();
+        let mut loaded = config();
+        assert_eq!(loaded.load(&page(&saved)), true);
+        assert_eq!(loaded.inputs, saved.inputs);
+        assert_eq!(loaded.analog, saved.analog);
+        assert_eq!(loaded.routes, saved.routes);
+    }
+
+    #[test]
+    fn defaults_round_trip() {
+        let mut loaded = changed();
+        assert_eq!(loaded.load(&page(&config())), true);
+        assert_eq!(loaded.inputs, crate::INPUTS);
+        assert_eq!(loaded.analog, crate::ANALOG_INPUTS);
+        assert_eq!(loaded.routes, crate::ROUTES);
+    }
+
+    #[test]
+    fn erased_flash_is_rejected() {
+        let mut loaded = config();
+        assert_eq!(loaded.load(&[0xFF; PAGE_SIZE]), false);
+        assert_eq!(loaded.load(&[]), false);
+        assert_eq!(loaded.inputs, crate::INPUTS);
+    }
+
+    #[test]
+    fn bad_checksum_is_rejected() {
+        let mut bytes = page(&changed());
+        bytes[HEADER_SIZE + 2] ^= 0x01;
+        let mut loaded = config();
+        assert_eq!(loaded.load(&bytes), false);
+        assert_eq!(loaded.inputs, crate::INPUTS);
+
+        let mut bytes = page(&changed());
+        bytes[10] = bytes[10].wrapping_add(1);
+        assert_eq!(loaded.load(&bytes), false);
+    }
+
+    #[test]
+    fn other_version_is_rejected() {
+        let mut bytes = page(&changed());
+        bytes[4] = VERSION - 1;
+        let mut loaded = config();
+        assert_eq!(loaded.load(&bytes), false);
+        bytes[0] = b'X';
+        bytes[4] = VERSION;
+        assert_eq!(loaded.load(&bytes), false);
+        assert_eq!(loaded.routes, crate::ROUTES);
+    }
+
+    #[test]
+    fn other_counts_are_rejected() {
+        let mut loaded = config();
+        // written by a firmware with a different number of inputs, analog inputs or routes
+        for index in 5..8 {
+            let mut bytes = page(&changed());
+            bytes[index] += 1;
+            assert_eq!(loaded.load(&bytes), false);
+        }
+        let mut bytes = page(&changed());
+        bytes[8] += 1;
+        assert_eq!(loaded.load(&bytes), false);
+        // a page cut short before the end of the payload
+        let bytes = page(&changed());
+        assert_eq!(loaded.load(&bytes[..HEADER_SIZE + 10]), false);
+        assert_eq!(loaded.inputs, crate::INPUTS);
+    }
+
+    #[test]
+    fn values_out_of_range_are_rejected() {
+        let mut loaded = config();
+        // input channel 0, unknown input kind, analog high_res 2 and a route to port 3
+        for pos in [HEADER_SIZE, HEADER_SIZE + 1, HEADER_SIZE + 8 * INPUT_SIZE + 2, HEADER_SIZE + 8 * INPUT_SIZE + 4 * ANALOG_SIZE + 2] {
+            let mut bytes = page(&changed());
+            bytes[pos] = if pos == HEADER_SIZE { 0 } else { 9 };
+            fix_checksum(&mut bytes);
+            assert_eq!(loaded.load(&bytes), false);
+        }
+        assert_eq!(loaded.inputs, crate::INPUTS);
+        assert_eq!(loaded.routes, crate::ROUTES);
+    }
+
+    // a config SysEx message from the 7D 4D header on, the reply is cut to its length
+    fn sysex(config: &mut Config<8, 4, 8>, command: &[u8]) -> (Response, Vec<u8>) {
+        let mut data = Vec::from([SYSEX_ID, DEVICE_ID]);
+        data.extend_from_slice(command);
+        let mut reply = [0u8; REPLY_SIZE];
+        let response = config.handle_sysex(&data, &mut reply);
+        (response, reply[..response.len].to_vec())
+    }
+
+    fn ack(command: u8, status: u8) -> Vec<u8> {
+        Vec::from([SYSEX_ID, DEVICE_ID, ACK, command, status])
+    }
+
+    // a SET that is turned down, checked to answer with status and leave the config alone
+    fn rejected(config: &mut Config<8, 4, 8>, command: &[u8], status: u8) {
+        let (inputs, analog, routes) = (config.inputs, config.analog, config.routes);
+        let (response, reply) = sysex(config, command);
+        assert_eq!(reply, ack(command[0], status), "{:?}", command);
+        assert_eq!(response.changed, false);
+        assert_eq!(response.save, false);
+        assert_eq!(config.inputs, inputs);
+        assert_eq!(config.analog, analog);
+        assert_eq!(config.routes, routes);
+    }
+
+    #[test]
+    fn get_info_reports_the_table_sizes() {
+        let (response, reply) = sysex(&mut config(), &[GET_INFO]);
+        assert_eq!(reply, [SYSEX_ID, DEVICE_ID, INFO, VERSION, 8, 4, 8]);
+        assert_eq!(response.changed, false);
+        assert_eq!(response.save, false);
+    }
+
+    #[test]
+    fn set_input_round_trips_through_get_input() {
+        let mut config = config();
+        let (response, reply) = sysex(&mut config, &[SET_INPUT, 2, 5, 2, 20, 100, 5]);
+        assert_eq!(reply, ack(SET_INPUT, STATUS_OK));
+        assert_eq!(response, Response { len: 5, changed: true, save: false });
+        let (_, reply) = sysex(&mut config, &[GET_INPUT, 2]);
+        assert_eq!(reply, [SYSEX_ID, DEVICE_ID, INPUT, 2, 5, 2, 20, 100, 5]);
+        // the gpio stays the one from the defaults
+        assert_eq!(config.inputs[2], InputMapping {
+            gpio: crate::INPUTS[2].gpio,
+            channel: 5,
+            action: Action::ControlToggle { control: 20, on: 100, off: 5 },
+        });
+        assert_eq!(config.inputs[3], crate::INPUTS[3]);
+    }
+
+    #[test]
+    fn set_analog_round_trips_through_get_analog() {
+        let mut config = config();
+        let (response, reply) = sysex(&mut config, &[SET_ANALOG, 1, 2, 11, 1]);
+        assert_eq!(reply, ack(SET_ANALOG, STATUS_OK));
+        assert_eq!(response.changed, true);
+        let (_, reply) = sysex(&mut config, &[GET_ANALOG, 1]);
+        assert_eq!(reply, [SYSEX_ID, DEVICE_ID, ANALOG, 1, 2, 11, 1]);
+        assert_eq!(config.analog[1].source, crate::ANALOG_INPUTS[1].source);
+    }
+
+    #[test]
+    fn set_route_round_trips_through_get_route() {
+        let mut config = config();
+        let route = changed().routes[3];
+        let mut command = Vec::from([SET_ROUTE, 5]);
+        command.extend_from_slice(&route_to_bytes(&route));
+        let (response, reply) = sysex(&mut config, &command);
+        assert_eq!(reply, ack(SET_ROUTE, STATUS_OK));
+        assert_eq!(response.changed, true);
+        assert_eq!(config.routes[5], route);
+        let (response, reply) = sysex(&mut config, &[GET_ROUTE, 5]);
+        assert_eq!(response.len, 4 + ROUTE_SIZE);
+        assert_eq!(reply[..4], [SYSEX_ID, DEVICE_ID, ROUTE, 5]);
+        assert_eq!(reply[4..], command[2..]);
+    }
+
+    #[test]
+    fn save_only_asks_for_a_write() {
+        let mut config = changed();
+        let (response, reply) = sysex(&mut config, &[SAVE]);
+        assert_eq!(reply, ack(SAVE, STATUS_OK));
+        assert_eq!(response, Response { len: 5, changed: false, save: true });
+        assert_eq!(config.inputs, changed().inputs);
+    }
+
+    #[test]
+    fn load_defaults_restores_every_table() {
+        let mut config = changed();
+        let (response, reply) = sysex(&mut config, &[LOAD_DEFAULTS]);
+        assert_eq!(reply, ack(LOAD_DEFAULTS, STATUS_OK));
+        assert_eq!(response, Response { len: 5, changed: true, save: false });
+        assert_eq!(config.inputs, crate::INPUTS);
+        assert_eq!(config.analog, crate::ANALOG_INPUTS);
+        assert_eq!(config.routes, crate::ROUTES);
+    }
+
+    #[test]
+    fn bad_lengths_are_rejected() {
+        let mut config = config();
+        rejected(&mut config, &[SET_INPUT, 2, 5, 2, 20, 100], STATUS_BAD_LENGTH);
+        rejected(&mut config, &[SET_INPUT, 2, 5, 2, 20, 100, 5, 0], STATUS_BAD_LENGTH);
+        rejected(&mut config, &[SET_ANALOG, 1, 2, 11], STATUS_BAD_LENGTH);
+        rejected(&mut config, &[SET_ANALOG], STATUS_BAD_LENGTH);
+        let mut command = Vec::from([SET_ROUTE, 5]);
+        command.extend_from_slice(&route_to_bytes(&changed().routes[3]));
+        rejected(&mut config, &command[..command.len() - 1], STATUS_BAD_LENGTH);
+    }
+
+    #[test]
+    fn bad_indices_are_rejected() {
+        let mut config = config();
+        rejected(&mut config, &[SET_INPUT, 8, 5, 2, 20, 100, 5], STATUS_BAD_INDEX);
+        rejected(&mut config, &[SET_ANALOG, 4, 2, 11, 1], STATUS_BAD_INDEX);
+        let mut command = Vec::from([SET_ROUTE, 8]);
+        command.extend_from_slice(&route_to_bytes(&changed().routes[3]));
+        rejected(&mut config, &command, STATUS_BAD_INDEX);
+        for get in [GET_INPUT, GET_ANALOG, GET_ROUTE] {
+            rejected(&mut config, &[get, 8], STATUS_BAD_INDEX);
+            rejected(&mut config, &[get], STATUS_BAD_INDEX);
+        }
+    }
+
+    #[test]
+    fn bad_values_are_rejected() {
+        let mut config = config();
+        // channel 0 and 17, an unknown kind, a note with velocity 0 and a mode message controller
+        rejected(&mut config, &[SET_INPUT, 0, 0, 0, 60, 100, 0], STATUS_BAD_VALUE);
+        rejected(&mut config, &[SET_INPUT, 0, 17, 0, 60, 100, 0], STATUS_BAD_VALUE);
+        rejected(&mut config, &[SET_INPUT, 0, 1, 7, 0, 0, 0], STATUS_BAD_VALUE);
+        rejected(&mut config, &[SET_INPUT, 0, 1, 0, 60, 0, 0], STATUS_BAD_VALUE);
+        rejected(&mut config, &[SET_INPUT, 0, 1, 1, 120, 127, 0], STATUS_BAD_VALUE);
+        // high_res 2, a 14-bit controller above 31 and channel 0
+        rejected(&mut config, &[SET_ANALOG, 1, 2, 11, 2], STATUS_BAD_VALUE);
+        rejected(&mut config, &[SET_ANALOG, 1, 2, 40, 1], STATUS_BAD_VALUE);
+        rejected(&mut config, &[SET_ANALOG, 1, 0, 11, 0], STATUS_BAD_VALUE);
+        // a data byte above 127, enabled 2, an unknown curve and a route from port 3
+        for (pos, value) in [(5, 0x80), (0, 2), (12, 4), (1, 3)] {
+            let mut command = Vec::from([SET_ROUTE, 5]);
+            command.extend_from_slice(&route_to_bytes(&changed().routes[3]));
+            command[2 + pos] = value;
+            rejected(&mut config, &command, STATUS_BAD_VALUE);
+        }
+    }
+
+    #[test]
+    fn other_messages_are_not_answered() {
+        let mut config = config();
+        let (response, reply) = sysex(&mut config, &[0x7E]);
+        assert_eq!(reply, ack(0x7E, STATUS_UNKNOWN));
+        assert_eq!(response.changed, false);
+        // too short, or for another device
+        let mut reply = [0u8; REPLY_SIZE];
+        assert_eq!(config.handle_sysex(&[SYSEX_ID, DEVICE_ID], &mut reply).len, 0);
+        assert_eq!(config.handle_sysex(&[SYSEX_ID, 0x4E, GET_INFO], &mut reply).len, 0);
+        assert_eq!(config.handle_sysex(&[0x43, DEVICE_ID, GET_INFO], &mut reply).len, 0);
+    }
+}
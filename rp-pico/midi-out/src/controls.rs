@@ -0,0 +1,211 @@
+use rp2040_hal::gpio::dynpin::DynPin;
+
+use crate::button::Button;
+use crate::midi::MidiMessage;
+
+// what an input sends. data values are 0-127
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Action {
+    // note on while held, note off on release
+    Note { note: u8, velocity: u8 },
+    // sends on while held and off on release
+    ControlMomentary { control: u8, on: u8, off: u8 },
+    // each press switches between on and off
+    ControlToggle { control: u8, on: u8, off: u8 },
+    ProgramChange { program: u8 },
+    Start,
+    Stop,
+    Continue,
+}
+
+// one button on a gpio, channels are 1-16
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct InputMapping {
+    pub gpio: u8,
+    pub channel: u8,
+    pub action: Action,
+}
+
+impl InputMapping {
+    // the message for a press (pressed = true) or release, toggle_state is the button's
+    // state after the press
+    pub fn message(&self, pressed: bool, toggle_state: bool) -> Option<MidiMessage> {
+        let channel = self.channel;
+        match self.action {
+            Action::Note { note, velocity } => {
+                let velocity = if pressed == true { velocity } else { 0 };
+                Some(MidiMessage::NoteOn { channel: channel, note: note, velocity: velocity })
+            }
+            Action::ControlMomentary { control, on, off } => {
+                let value = if pressed == true { on } else { off };
+                Some(MidiMessage::ControlChange { channel: channel, control: control, value: value })
+            }
+            Action::ControlToggle { control, on, off } => {
+                if pressed == false { return None }
+                let value = if toggle_state == true { on } else { off };
+                Some(MidiMessage::ControlChange { channel: channel, control: control, value: value })
+            }
+            Action::ProgramChange { program } => {
+                if pressed == false { return None }
+                Some(MidiMessage::ProgramChange { channel: channel, program: program })
+            }
+            Action::Start => if pressed == true { Some(MidiMessage::Start) } else { None },
+            Action::Stop => if pressed == true { Some(MidiMessage::Stop) } else { None },
+            Action::Continue => if pressed == true { Some(MidiMessage::Continue) } else { None },
+        }
+    }
+}
+
+// gpios that main hands out to inputs. gpio0/1 are UART0, gpio3/4 the encoder, gpio8/9
+// are UART1, gpio20-22 select the analog multiplexer input, gpio23-25 are used by the
+// Pico itself and gpio26-28 are the ADC inputs
+pub const fn gpio_available(gpio: u8) -> bool {
+    matches!(gpio, 2 | 5..=7 | 10..=19)
+}
+
+// data values are 0-127, notes need a velocity of at least 1 (0 would be a note off)
+// and controllers 120-127 are channel mode messages
+pub const fn action_valid(action: &Action) -> bool {
+    match *action {
+        Action::Note { note, velocity } => note <= 127 && velocity >= 1 && velocity <= 127,
+        Action::ControlMomentary { control, on, off } | Action::ControlToggle { control, on, off } => {
+            control <= 119 && on <= 127 && off <= 127
+        }
+        Action::ProgramChange { program } => program <= 127,
+        Action::Start | Action::Stop | Action::Continue => true,
+    }
+}
+
+// the first problem with a mapping table, if any
+pub const fn check(inputs: &[InputMapping]) -> Result<(), &'static str> {
+    let mut i = 0;
+    while i < inputs.len() {
+        let input = &inputs[i];
+        if gpio_available(input.gpio) == false { return Err("input mapped to a gpio that isn't available") }
+        if input.channel < 1 || input.channel > 16 { return Err("input channel must be 1-16") }
+        if action_valid(&input.action) == false { return Err("input action value out of range") }
+        let mut j = i + 1;
+        while j < inputs.len() {
+            if inputs[j].gpio == input.gpio { return Err("gpio mapped to more than one input") }
+            j += 1;
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+// checks a mapping table at compile time, use as const _: () = assert!(validate(&TABLE))
+pub const fn validate(inputs: &[InputMapping]) -> bool {
+    match check(inputs) {
+        Ok(()) => true,
+        Err(message) => panic!("{}", message),
+    }
+}
+
+pub struct Control {
+    pub mapping: InputMapping,
+    pub button: Button<DynPin>,
+}
+
+impl Control {
+    pub fn new(mapping: InputMapping, pin: DynPin, debounce_time: u32) -> Self {
+        Control {
+            mapping: mapping,
+            button: Button::new(pin, debounce_time),
+        }
+    }
+
+    // polls the button and returns the message for a press or release
+    pub fn poll(&mut self, millis: u32) -> Option<MidiMessage> {
+        self.button.poll(millis);
+        if self.button.rising_edge() == true {
+            self.mapping.message(true, self.button.toggle_state())
+        } else if self.button.falling_edge() == true {
+            self.mapping.message(false, self.button.toggle_state())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(gpio: u8, channel: u8, action: Action) -> InputMapping {
+        InputMapping { gpio: gpio, channel: channel, action: action }
+    }
+
+    #[test]
+    fn firmware_table_is_valid() {
+        assert_eq!(check(&crate::INPUTS), Ok(()));
+    }
+
+    #[test]
+    fn every_action_kind_is_accepted() {
+        let inputs = [
+            input(2, 1, Action::Note { note: 0, velocity: 1 }),
+            input(5, 16, Action::Note { note: 127, velocity: 127 }),
+            input(6, 2, Action::ControlMomentary { control: 119, on: 127, off: 0 }),
+            input(7, 3, Action::ControlToggle { control: 0, on: 0, off: 127 }),
+            input(10, 4, Action::ProgramChange { program: 127 }),
+            input(11, 5, Action::Start),
+            input(12, 6, Action::Stop),
+            input(19, 7, Action::Continue),
+        ];
+        assert_eq!(check(&inputs), Ok(()));
+    }
+
+    #[test]
+    fn duplicate_gpio_is_rejected() {
+        let inputs = [
+            input(2, 1, Action::Start),
+            input(5, 1, Action::Stop),
+            input(2, 1, Action::Continue),
+        ];
+        assert_eq!(check(&inputs), Err("gpio mapped to more than one input"));
+    }
+
+    #[test]
+    fn reserved_gpios_are_rejected() {
+        // UARTs, encoder, multiplexer select, the Pico's own pins and the ADC inputs
+        for gpio in [0, 1, 3, 4, 8, 9, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30] {
+            assert_eq!(check(&[input(gpio, 1, Action::Start)]), Err("input mapped to a gpio that isn't available"));
+        }
+    }
+
+    #[test]
+    fn channel_out_of_range_is_rejected() {
+        for channel in [0, 17, 255] {
+            assert_eq!(check(&[input(2, channel, Action::Start)]), Err("input channel must be 1-16"));
+        }
+    }
+
+    #[test]
+    fn action_values_out_of_range_are_rejected() {
+        let actions = [
+            Action::Note { note: 128, velocity: 100 },
+            Action::Note { note: 60, velocity: 0 },
+            Action::Note { note: 60, velocity: 128 },
+            Action::ControlMomentary { control: 120, on: 127, off: 0 },
+            Action::ControlMomentary { control: 1, on: 128, off: 0 },
+            Action::ControlToggle { control: 1, on: 127, off: 128 },
+            Action::ProgramChange { program: 128 },
+        ];
+        for action in actions.iter() {
+            assert_eq!(check(&[input(2, 1, *action)]), Err("input action value out of range"));
+        }
+    }
+
+    #[test]
+    fn messages_for_press_and_release() {
+        let note = input(2, 10, Action::Note { note: 36, velocity: 100 });
+        assert_eq!(note.message(true, true), Some(MidiMessage::NoteOn { channel: 10, note: 36, velocity: 100 }));
+        assert_eq!(note.message(false, true), Some(MidiMessage::NoteOn { channel: 10, note: 36, velocity: 0 }));
+        let toggle = input(2, 1, Action::ControlToggle { control: 80, on: 127, off: 0 });
+        assert_eq!(toggle.message(true, true), Some(MidiMessage::ControlChange { channel: 1, control: 80, value: 127 }));
+        assert_eq!(toggle.message(true, false), Some(MidiMessage::ControlChange { channel: 1, control: 80, value: 0 }));
+        assert_eq!(toggle.message(false, false), None);
+        assert_eq!(input(2, 1, Action::Stop).message(true, true), Some(MidiMessage::Stop));
+    }
+}
@@ -0,0 +1,79 @@
+// writes to the last 4K sector of the Pico's 2MB flash, which memory.x keeps out of the
+// firmware. while the flash is being erased or programmed nothing can run from it, so
+// the write runs from RAM with interrupts disabled, using the flash functions in the
+// boot ROM
+use core::mem::transmute;
+
+pub const FLASH_SIZE: u32 = 2048 * 1024;
+pub const SECTOR_SIZE: u32 = 4096;
+pub const PAGE_SIZE: usize = 256;
+// offset from the start of flash, the RP2040 maps flash for reading at 0x10000000
+pub const SETTINGS_OFFSET: u32 = FLASH_SIZE - SECTOR_SIZE;
+const XIP_BASE: u32 = 0x1000_0000;
+// the second stage bootloader at the start of flash sets up fast XIP reads again
+// after the ROM functions leave the flash in a slow but safe mode
+const BOOT2_SIZE: usize = 256;
+
+// 64K block erase command, the ROM falls back to sector erases for a single sector
+const BLOCK_SIZE: u32 = 65536;
+const BLOCK_CMD: u8 = 0xD8;
+
+// the settings sector as it is mapped into memory
+pub fn settings() -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts((XIP_BASE + SETTINGS_OFFSET) as *const u8, PAGE_SIZE) }
+}
+
+// looks up a boot ROM function by its two letter tag
+fn rom_function(tag: &[u8; 2]) -> usize {
+    unsafe {
+        let table = *(0x0000_0014 as *const u16) as *const u16;
+        let lookup: extern "C" fn(*const u16, u32) -> usize = transmute(*(0x0000_0018 as *const u16) as usize);
+        lookup(table, u16::from_le_bytes(*tag) as u32)
+    }
+}
+
+struct RomFunctions {
+    connect_internal_flash: extern "C" fn(),
+    flash_exit_xip: extern "C" fn(),
+    flash_range_erase: extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: extern "C" fn(),
+}
+
+// erases the settings sector and writes one page to the start of it. the UART and USB
+// interrupts can't run until it returns, the caller picks a quiet moment
+pub fn write_settings(page: &[u8; PAGE_SIZE]) {
+    // everything that is read from flash has to be fetched before the flash goes away
+    let functions = unsafe {
+        RomFunctions {
+            connect_internal_flash: transmute(rom_function(b"IF")),
+            flash_exit_xip: transmute(rom_function(b"EX")),
+            flash_range_erase: transmute(rom_function(b"RE")),
+            flash_range_program: transmute(rom_function(b"RP")),
+            flash_flush_cache: transmute(rom_function(b"FC")),
+        }
+    };
+    let mut boot2 = [0u32; BOOT2_SIZE / 4];
+    unsafe {
+        core::ptr::copy_nonoverlapping(XIP_BASE as *const u32, boot2.as_mut_ptr(), BOOT2_SIZE / 4);
+    }
+    let mut data = [0u8; PAGE_SIZE];
+    data.copy_from_slice(page);
+
+    cortex_m::interrupt::free(|_| unsafe {
+        write_from_ram(&functions, &boot2, &data);
+    });
+}
+
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn write_from_ram(functions: &RomFunctions, boot2: &[u32; BOOT2_SIZE / 4], data: &[u8; PAGE_SIZE]) {
+    (functions.connect_internal_flash)();
+    (functions.flash_exit_xip)();
+    (functions.flash_range_erase)(SETTINGS_OFFSET, SECTOR_SIZE as usize, BLOCK_SIZE, BLOCK_CMD);
+    (functions.flash_range_program)(SETTINGS_OFFSET, data.as_ptr(), PAGE_SIZE);
+    (functions.flash_flush_cache)();
+    // run the copy of boot2 to restore fast XIP, the +1 marks it as thumb code
+    let boot2_entry: extern "C" fn() = transmute(boot2.as_ptr() as usize + 1);
+    boot2_entry();
+}
@@ -0,0 +1,140 @@
+use crate::controls::{gpio_available, Action, Control, InputMapping};
+use crate::midi::MidiMessage;
+use crate::velocity::KeyMapping;
+
+// velocity for a note learned from a note off, which doesn't carry a usable one
+const DEFAULT_VELOCITY: u8 = 100;
+const ARMED_BLINK_MS: u32 = 100;
+const CONFIRM_BLINK_MS: u32 = 100;
+const CONFIRM_BLINKS: u32 = 3;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum LearnState {
+    Idle,
+    // the input was pressed with the learn button held and waits for a message
+    Armed(usize),
+    // the input learned a message, its release belongs to the old mapping and is skipped
+    Learned(usize),
+}
+
+// MIDI learn: pressing an input while the learn button is held arms it, and the next
+// message that comes into the board becomes what the input sends. letting go of the
+// input before a message arrives leaves the mapping as it was. inputs don't send anything
+// while the learn button is held, so a held sustain pedal can't start learning by itself
+pub struct Learn {
+    state: LearnState,
+    button_held: bool,
+    confirm_start: Option<u32>,
+}
+
+impl Learn {
+    pub fn new() -> Self {
+        Learn {
+            state: LearnState::Idle,
+            button_held: false,
+            confirm_start: None,
+        }
+    }
+
+    // call after polling the learn button and the inputs
+    pub fn update<const N: usize>(&mut self, button_held: bool, controls: &[Control; N]) {
+        self.button_held = button_held;
+        match self.state {
+            LearnState::Idle => {
+                if button_held == false { return }
+                for (index, control) in controls.iter().enumerate() {
+                    if control.button.rising_edge() == true {
+                        self.state = LearnState::Armed(index);
+                        break;
+                    }
+                }
+            }
+            LearnState::Armed(index) | LearnState::Learned(index) => {
+                if controls[index].button.state() == false {
+                    self.state = LearnState::Idle;
+                }
+            }
+        }
+    }
+
+    pub fn armed(&self) -> Option<usize> {
+        match self.state {
+            LearnState::Armed(index) => Some(index),
+            _ => None,
+        }
+    }
+
+    // offers an incoming message to the armed input. returns the input's index and its
+    // new mapping when the message could be learned
+    pub fn learn(&mut self, controls: &[Control], message: &MidiMessage, millis: u32) -> Option<(usize, InputMapping)> {
+        let index = self.armed()?;
+        let mapping = learned_mapping(&controls[index].mapping, message)?;
+        self.state = LearnState::Learned(index);
+        self.confirm_start = Some(millis);
+        Some((index, mapping))
+    }
+
+    // false while the learn button is held and for the release of the armed input, which
+    // belongs to the gesture and not to the music
+    pub fn send_allowed(&self, index: usize) -> bool {
+        self.button_held == false && self.state != LearnState::Armed(index) && self.state != LearnState::Learned(index)
+    }
+
+    // the LED blinks quickly while an input is armed and flashes a few times once it
+    // has learned, None leaves the LED to the inputs
+    pub fn led(&mut self, millis: u32) -> Option<bool> {
+        if let Some(start) = self.confirm_start {
+            let elapsed = millis.wrapping_sub(start);
+            if elapsed < CONFIRM_BLINK_MS * 2 * CONFIRM_BLINKS {
+                return Some((elapsed / CONFIRM_BLINK_MS) % 2 == 0);
+            }
+            self.confirm_start = None;
+        }
+        match self.state {
+            LearnState::Armed(_) => Some((millis / ARMED_BLINK_MS) % 2 == 0),
+            _ => None,
+        }
+    }
+}
+
+// the mapping that sends a message like the one received, on the same gpio. controllers
+// stay toggles if the input already was one, otherwise they become momentary
+pub fn learned_mapping(current: &InputMapping, message: &MidiMessage) -> Option<InputMapping> {
+    let (channel, action) = match *message {
+        MidiMessage::NoteOn { channel, note, velocity } => (channel, Action::Note { note: note, velocity: velocity.max(1) }),
+        MidiMessage::NoteOff { channel, note, .. } => {
+            let velocity = match current.action {
+                Action::Note { velocity, .. } => velocity,
+                _ => DEFAULT_VELOCITY,
+            };
+            (channel, Action::Note { note: note, velocity: velocity })
+        }
+        MidiMessage::ControlChange { channel, control, .. } => match current.action {
+            Action::ControlToggle { on, off, .. } => (channel, Action::ControlToggle { control: control, on: on, off: off }),
+            Action::ControlMomentary { on, off, .. } => (channel, Action::ControlMomentary { control: control, on: on, off: off }),
+            _ => (channel, Action::ControlMomentary { control: control, on: 127, off: 0 }),
+        },
+        MidiMessage::ProgramChange { channel, program } => (channel, Action::ProgramChange { program: program }),
+        MidiMessage::Start => (current.channel, Action::Start),
+        MidiMessage::Stop => (current.channel, Action::Stop),
+        MidiMessage::Continue => (current.channel, Action::Continue),
+        _ => return None,
+    };
+    Some(InputMapping { gpio: current.gpio, channel: channel, action: action })
+}
+
+// checks the learn button's gpio at compile time, it can't be used by an input or a key
+pub const fn validate(gpio: u8, inputs: &[InputMapping], keys: &[KeyMapping]) -> bool {
+    assert!(gpio_available(gpio), "learn button on a gpio that isn't available");
+    let mut i = 0;
+    while i < inputs.len() {
+        assert!(inputs[i].gpio != gpio, "gpio mapped to both the learn button and an input");
+        i += 1;
+    }
+    let mut i = 0;
+    while i < keys.len() {
+        assert!(keys[i].first_gpio != gpio && keys[i].second_gpio != gpio, "gpio mapped to both the learn button and a key");
+        i += 1;
+    }
+    true
+}
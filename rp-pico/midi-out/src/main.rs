@@ -1,31 +1,153 @@
-#![no_std]
-#![no_main]
+// host tests build with std and the test harness's main
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 
+pub mod analog;
+pub mod buffer;
 pub mod button;
+pub mod config;
+pub mod controls;
+pub mod encoder;
+pub mod flash;
+pub mod learn;
 pub mod midi;
+pub mod monitor;
+pub mod router;
+// shift register chains, shared with the step sequencer
+#[path = "../../../grand-central-m4/step-sequencer/src/shiftreg.rs"]
+pub mod shiftreg;
+pub mod txqueue;
+pub mod ump;
+pub mod usbmidi;
+pub mod velocity;
 
-use core::cell::Cell;
+use core::cell::{Cell, RefCell};
 
 use cortex_m_rt::entry;
 
 use cortex_m::interrupt as cortex_interrupt;
 use cortex_m::interrupt::Mutex;
 
+use embedded_hal::adc::OneShot;
 use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::serial::Read;
 
 use embedded_time::duration::Extensions;
 use embedded_time::rate::Baud;
 
+#[cfg(not(test))]
 use panic_halt as _;
 
 use rp_pico::hal::pac;
 use rp_pico::hal;
 use rp_pico::hal::pac::interrupt;
 
+use rp2040_hal::clocks::Clock;
 use rp2040_hal::gpio::FunctionUart;
+use rp2040_hal::gpio::dynpin::DynPin;
 use rp2040_hal::timer::{Alarm0, Timer};
 use rp2040_hal::uart::{DataBits, StopBits, UartConfig};
 
+use analog::{AnalogMapping, AnalogSource};
+use controls::{Action, InputMapping};
+use router::{Route, DIN1, DIN2, USB};
+use txqueue::TxQueue;
+use velocity::{KeyMapping, PadMapping, VelocityCurve};
+
+use usb_device::prelude::*;
+use usb_device::class_prelude::{UsbBus, UsbBusAllocator};
+use usbd_serial::SerialPort;
+
+// both DIN ports run at the MIDI baud rate. UartConfig isn't Copy, a const gives each
+// UART its own instance of the one setting
+const MIDI_UART_CONFIG: UartConfig = UartConfig {
+    baudrate: Baud(31250),
+    data_bits: DataBits::Eight,
+    stop_bits: StopBits::One,
+    parity: None,
+};
+
+// the encoder on gpio3/gpio4 sends this controller number
+const ENCODER_CC: u8 = 1;
+
+// every button input and the message it sends. any gpio from controls::gpio_available
+// can be used, the table is checked when the firmware is compiled
+const INPUTS: [InputMapping; 8] = [
+    InputMapping { gpio: 2, channel: 1, action: Action::Note { note: 35, velocity: 63 } },
+    InputMapping { gpio: 5, channel: 1, action: Action::Note { note: 38, velocity: 63 } },
+    InputMapping { gpio: 6, channel: 1, action: Action::ControlMomentary { control: 64, on: 127, off: 0 } },
+    InputMapping { gpio: 7, channel: 1, action: Action::ControlToggle { control: 80, on: 127, off: 0 } },
+    InputMapping { gpio: 14, channel: 1, action: Action::ProgramChange { program: 0 } },
+    InputMapping { gpio: 15, channel: 1, action: Action::Start },
+    InputMapping { gpio: 10, channel: 1, action: Action::Stop },
+    InputMapping { gpio: 11, channel: 1, action: Action::Continue },
+];
+const _: () = assert!(controls::validate(&INPUTS));
+const DEBOUNCE_MS: u32 = 1;
+// hold this button and press an input to teach the input a new message
+const LEARN_GPIO: u8 = 16;
+
+// pots and faders on ADC0 (gpio26), ADC1 (gpio27) and either ADC2 (gpio28) or an 8 channel
+// multiplexer (4051) with its common pin on ADC2 and its select pins on gpio20-22
+const ANALOG_INPUTS: [AnalogMapping; 4] = [
+    AnalogMapping { source: AnalogSource::Adc(0), channel: 1, control: 7, high_res: false },
+    AnalogMapping { source: AnalogSource::Mux(2), channel: 1, control: 10, high_res: false },
+    AnalogMapping { source: AnalogSource::Mux(0), channel: 1, control: 74, high_res: false },
+    AnalogMapping { source: AnalogSource::Mux(1), channel: 1, control: 16, high_res: true },
+];
+const _: () = assert!(analog::validate(&ANALOG_INPUTS));
+const USE_MUX: bool = analog::uses_mux(&ANALOG_INPUTS);
+// change in raw 12-bit ADC counts needed before a new value is sent
+const ANALOG_DEADBAND: u16 = 16;
+const ANALOG_DEADBAND_HIGH_RES: u16 = 6;
+
+// velocity sensitive keys with two contacts, the velocity comes from the time between them
+const VELOCITY_KEYS: [KeyMapping; 1] = [
+    KeyMapping { first_gpio: 12, second_gpio: 13, channel: 1, note: 60, curve: VelocityCurve::Linear },
+];
+const KEY_FAST_US: u32 = 1000;      // contact time of the hardest hit, velocity 127
+const KEY_SLOW_US: u32 = 30000;     // contact time of the softest hit, velocity 1
+const KEY_RELEASE_US: u32 = 5000;   // both contacts open this long before a key can play again
+// piezo pads on ADC0 or ADC1, the velocity comes from the peak of the hit
+const PIEZO_PADS: [PadMapping; 1] = [
+    PadMapping { adc: 1, channel: 10, note: 38, curve: VelocityCurve::Log },
+];
+const PAD_THRESHOLD: u16 = 200;     // ADC counts above the noise floor that start a hit
+const PAD_SCAN_US: u32 = 2000;      // time to look for the peak after the threshold is crossed
+const PAD_MASK_US: u32 = 30000;     // ignore the ringing of the piezo after a hit
+const _: () = assert!(velocity::validate(&VELOCITY_KEYS, &PIEZO_PADS, &INPUTS, &ANALOG_INPUTS));
+const _: () = assert!(learn::validate(LEARN_GPIO, &INPUTS, &VELOCITY_KEYS));
+
+// routes between the MIDI ports: DIN1 (UART0, gpio0/1), DIN2 (UART1, gpio8/9) and USB.
+// the inputs, pots, keys and pads of the board itself always go to DIN1 and USB
+const ROUTES: [Route; 8] = [
+    Route::thru(DIN1, 1 << USB),
+    Route::thru(USB, 1 << DIN1),
+    Route::thru(DIN2, 1 << USB),
+    Route::disabled(),
+    Route::disabled(),
+    Route::disabled(),
+    Route::disabled(),
+    Route::disabled(),
+];
+
+// bytes of MIDI output waiting for each UART
+const TX_QUEUE_SIZE: usize = 256;
+// longest SysEx message passed between the ports
+const SYSEX_SIZE: usize = 128;
+// a SysEx is queued whole, F0 and F7 and the data each take a length byte in the queue
+const _: () = assert!(TX_QUEUE_SIZE >= SYSEX_SIZE + 2 + 3);
+// and buffered whole for USB, three bytes in each 4 byte event
+const _: () = assert!((SYSEX_SIZE + 2 + 2) / 3 * 4 <= usbmidi::TX_SIZE);
+// MIDI bytes read from one port per loop
+const INPUT_BYTES: usize = 64;
+// text of the USB serial MIDI monitor waiting for the host
+const MONITOR_SIZE: usize = 1024;
+// writing the settings stops everything for about 50ms, so a save waits until nothing is
+// arriving on the DIN inputs and the outputs have gone out, but not longer than this
+const SAVE_WAIT_MS: u32 = 500;
+
 // global millisecond timer variable
 static MILLIS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
 
@@ -34,7 +156,12 @@ static MILLIS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
 static mut MILLIS_TIMER: Option<Timer> = None;
 static mut MILLIS_ALARM: Option<Alarm0> = None;
 
-#[entry]
+// MIDI output for DIN1 and DIN2 is queued here and moved into the UART fifos by the
+// UART0_IRQ and UART1_IRQ interrupts, so the main loop doesn't wait for each message
+// to go out at 31250 baud
+static MIDI_TX: Mutex<RefCell<[TxQueue<TX_QUEUE_SIZE>; 2]>> = Mutex::new(RefCell::new([TxQueue::new(), TxQueue::new()]));
+
+#[cfg_attr(not(test), entry)]
 fn main() -> ! {
     let mut pac = pac::Peripherals::take().unwrap();
     let mut watchdog = hal::Watchdog::new(pac.WATCHDOG);
@@ -78,23 +205,117 @@ fn main() -> ! {
         &mut pac.RESETS,
     );
 
-    let uart_config = UartConfig {
-        baudrate: Baud(31250),
-        data_bits: DataBits::Eight,
-        stop_bits: StopBits::One,
-        parity: None,
-    };
-    
-    let mut uart = hal::uart::UartPeripheral::new(pac.UART0, &mut pac.RESETS)
-        .enable(uart_config, clocks.peripheral_clock.into())
+    let peripheral_freq = clocks.peripheral_clock.freq();
+    let mut uart0 = hal::uart::UartPeripheral::new(pac.UART0, &mut pac.RESETS)
+        .enable(MIDI_UART_CONFIG, peripheral_freq)
         .unwrap();
+    let mut uart1 = hal::uart::UartPeripheral::new(pac.UART1, &mut pac.RESETS)
+        .enable(MIDI_UART_CONFIG, peripheral_freq)
+        .unwrap();
+    unsafe {
+        pac::NVIC::unmask(hal::pac::Interrupt::UART0_IRQ);
+        pac::NVIC::unmask(hal::pac::Interrupt::UART1_IRQ);
+    }
     
     let _tx_pin = pins.gpio0.into_mode::<FunctionUart>();
     let _rx_pin = pins.gpio1.into_mode::<FunctionUart>();
+    // DIN2 on the pins that used to carry two of the buttons, see the README
+    let _tx2_pin = pins.gpio8.into_mode::<FunctionUart>();
+    let _rx2_pin = pins.gpio9.into_mode::<FunctionUart>();
 
     let mut led_pin = pins.led.into_push_pull_output();
     
-    let mut button1 = button::Button::new(pins.gpio2.into_pull_up_input(), 1);
+    // one encoder per DIN output, each keeps its own running status
+    let mut midi_out = [midi::MidiEncoder::new(false), midi::MidiEncoder::new(false)];
+    // one parser per input port, indexed by DIN1, DIN2 and USB
+    let mut parsers = [
+        midi::MidiParser::<SYSEX_SIZE>::new(),
+        midi::MidiParser::<SYSEX_SIZE>::new(),
+        midi::MidiParser::<SYSEX_SIZE>::new(),
+    ];
+    
+    // the board shows up as a class compliant USB MIDI interface, what goes between it
+    // and the DIN ports is decided by the routes. next to it is a USB serial port that
+    // prints everything arriving on the DIN inputs while a terminal has it open
+    let usb_bus = UsbBusAllocator::new(hal::usb::UsbBus::new(
+        pac.USBCTRL_REGS,
+        pac.USBCTRL_DPRAM,
+        clocks.usb_clock,
+        true,
+        &mut pac.RESETS,
+    ));
+    let mut usb_midi = usbmidi::MidiClass::new(&usb_bus);
+    let mut serial = SerialPort::new(&usb_bus);
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x05e4))
+        .manufacturer("Eric Kloeckner")
+        .product("MIDI Out")
+        .serial_number("midi-out")
+        .composite_with_iads()
+        .build();
+    let mut monitor = monitor::Monitor::<MONITOR_SIZE>::new();
+    // messages the USB MIDI port couldn't take, shown on the monitor with the DIN queue overflows
+    let mut usb_dropped: u32 = 0;
+    
+    // every gpio in controls::gpio_available, as pull-up inputs waiting to be mapped
+    const NO_PIN: Option<DynPin> = None;
+    let mut free_pins = [NO_PIN; 29];
+    free_pins[2] = Some(pins.gpio2.into_pull_up_input().into());
+    free_pins[5] = Some(pins.gpio5.into_pull_up_input().into());
+    free_pins[6] = Some(pins.gpio6.into_pull_up_input().into());
+    free_pins[7] = Some(pins.gpio7.into_pull_up_input().into());
+    free_pins[10] = Some(pins.gpio10.into_pull_up_input().into());
+    free_pins[11] = Some(pins.gpio11.into_pull_up_input().into());
+    free_pins[12] = Some(pins.gpio12.into_pull_up_input().into());
+    free_pins[13] = Some(pins.gpio13.into_pull_up_input().into());
+    free_pins[14] = Some(pins.gpio14.into_pull_up_input().into());
+    free_pins[15] = Some(pins.gpio15.into_pull_up_input().into());
+    free_pins[16] = Some(pins.gpio16.into_pull_up_input().into());
+    free_pins[17] = Some(pins.gpio17.into_pull_up_input().into());
+    free_pins[18] = Some(pins.gpio18.into_pull_up_input().into());
+    free_pins[19] = Some(pins.gpio19.into_pull_up_input().into());
+    // validate() makes sure every mapped gpio is in free_pins exactly once
+    let mut controls = INPUTS.map(|input| {
+        controls::Control::new(input, free_pins[input.gpio as usize].take().unwrap(), DEBOUNCE_MS)
+    });
+    
+    let mut encoder = encoder::Encoder::new(pins.gpio3.into_pull_up_input(), pins.gpio4.into_pull_up_input())
+        .with_acceleration(encoder::Acceleration { threshold: 40, max_multiplier: 8 });
+    let mut encoder_value: i32 = 0;
+    
+    let mut adc = hal::adc::Adc::new(pac.ADC, &mut pac.RESETS);
+    let mut adc_pin0 = pins.gpio26.into_floating_input();
+    let mut adc_pin1 = pins.gpio27.into_floating_input();
+    let mut adc_pin2 = pins.gpio28.into_floating_input();
+    let mut mux_s0 = pins.gpio20.into_push_pull_output();
+    let mut mux_s1 = pins.gpio21.into_push_pull_output();
+    let mut mux_s2 = pins.gpio22.into_push_pull_output();
+    let mut mux_input: u8 = 0;
+    let mut pots = ANALOG_INPUTS.map(|mapping| {
+        let deadband = if mapping.high_res == true { ANALOG_DEADBAND_HIGH_RES } else { ANALOG_DEADBAND };
+        analog::AnalogControl::new(mapping, deadband)
+    });
+    let mut last_analog: u32 = 0;
+    
+    // the tables above are the defaults, settings saved over SysEx replace them
+    let mut config = config::Config::new(INPUTS, ANALOG_INPUTS, ROUTES);
+    config.load(flash::settings());
+    apply_config(&config, &mut controls, &mut pots);
+    let mut reply = [0u8; config::REPLY_SIZE];
+    let mut learn = learn::Learn::new();
+    let mut learn_button = button::Button::new(free_pins[LEARN_GPIO as usize].take().unwrap(), DEBOUNCE_MS);
+    // when the settings were changed by SysEx or MIDI learn and should be saved
+    let mut save_requested: Option<u32> = None;
+    
+    let mut keys = VELOCITY_KEYS.map(|mapping| {
+        let first = free_pins[mapping.first_gpio as usize].take().unwrap();
+        let second = free_pins[mapping.second_gpio as usize].take().unwrap();
+        let key = velocity::DualSwitchKey::new(KEY_FAST_US, KEY_SLOW_US, KEY_RELEASE_US, mapping.curve);
+        velocity::VelocityKey::new(mapping, first, second, key)
+    });
+    let mut pads = PIEZO_PADS.map(|mapping| {
+        let detector = velocity::PiezoDetector::new(PAD_THRESHOLD, PAD_SCAN_US, PAD_MASK_US, mapping.curve);
+        velocity::Pad::new(mapping, detector)
+    });
     
     // the local millisecond counter variable
     let mut millis: u32 = 0;
@@ -107,21 +328,351 @@ fn main() -> ! {
             millis = MILLIS.borrow(cs).get();
         });
         
-        button1.poll(millis);
-        if button1.state() == true {
-            if button1.last_state() == false {
-                midi::note_on(&mut uart, 1, 35, 63);
-                led_pin.set_high().unwrap();
+        let usb_ready = usb_dev.poll(&mut [&mut usb_midi, &mut serial]);
+        if usb_ready == true {
+            // the monitor only talks, anything typed into it is thrown away
+            let mut serial_buf = [0u8; 64];
+            serial.read(&mut serial_buf).ok();
+        }
+        for input in [DIN1, DIN2, USB] {
+            let mut bytes = [0u8; INPUT_BYTES];
+            let mut count = 0;
+            match input {
+                DIN1 => while count < INPUT_BYTES {
+                    match uart0.read() {
+                        Ok(byte) => { bytes[count] = byte; count += 1; }
+                        Err(_) => break,
+                    }
+                },
+                DIN2 => while count < INPUT_BYTES {
+                    match uart1.read() {
+                        Ok(byte) => { bytes[count] = byte; count += 1; }
+                        Err(_) => break,
+                    }
+                },
+                _ => if usb_ready == true {
+                    let mut usb_buf = [0u8; 64];
+                    if let Ok(len) = usb_midi.read(&mut usb_buf) {
+                        for event in usb_buf[..len].chunks(4) {
+                            for byte in usbmidi::event_bytes(event).iter() {
+                                bytes[count] = *byte;
+                                count += 1;
+                            }
+                        }
+                    }
+                },
+            }
+            
+            let parser = &mut parsers[input as usize];
+            for byte in bytes[..count].iter() {
+                let parsed = parser.push(*byte);
+                if let Some(message) = parsed {
+                    monitor.message(input, &message, parser.sysex(), millis, micros());
+                }
+                match parsed {
+                    Some(midi::MidiMessage::SysEx { .. }) => {
+                        if config::is_config_sysex(parser.sysex()) == true {
+                            // config replies go back to the port the request came from
+                            let response = config.handle_sysex(parser.sysex(), &mut reply);
+                            if response.changed == true { apply_config(&config, &mut controls, &mut pots) }
+                            if response.save == true { save_requested = save_requested.or(Some(millis)) }
+                            send_sysex_to(1 << input, &reply[..response.len], &mut midi_out, &mut usb_midi, &mut usb_dropped);
+                        } else {
+                            let outputs = router::sysex_outputs(&config.routes, input);
+                            send_sysex_to(outputs, parser.sysex(), &mut midi_out, &mut usb_midi, &mut usb_dropped);
+                        }
+                    }
+                    Some(message) => {
+                        if learn_message(&mut learn, &mut config, &mut controls, &mut pots, &message, millis) == true {
+                            save_requested = save_requested.or(Some(millis));
+                        } else {
+                            route_message(&config.routes, input, &message, &mut midi_out, &mut usb_midi, &mut usb_dropped);
+                        }
+                    }
+                    None => (),
+                }
             }
+            monitor.parse_errors(input, parser.errors(), millis);
+        }
+        
+        let mut any_held = false;
+        for (index, control) in controls.iter_mut().enumerate() {
+            if let Some(message) = control.poll(millis) {
+                if learn.send_allowed(index) == true {
+                    send_local(&message, &mut midi_out, &mut usb_midi, &mut usb_dropped);
+                }
+            }
+            if control.button.state() == true { any_held = true }
+        }
+        learn_button.poll(millis);
+        learn.update(learn_button.state(), &controls);
+        let led_on = match learn.led(millis) {
+            Some(on) => on,
+            None => any_held,
+        };
+        if led_on == true {
+            led_pin.set_high().unwrap();
         } else {
-            if button1.last_state() == true {
-                midi::note_on(&mut uart, 1, 35, 0);
-                led_pin.set_low().unwrap();
+            led_pin.set_low().unwrap();
+        }
+        
+        // keys and pads need much finer timing than the millisecond counter
+        let now = micros();
+        for key in keys.iter_mut() {
+            if let Some(message) = key.poll(now) {
+                send_local(&message, &mut midi_out, &mut usb_midi, &mut usb_dropped);
+            }
+        }
+        for pad in pads.iter_mut() {
+            let raw: u16 = if pad.mapping.adc == 0 {
+                adc.read(&mut adc_pin0).unwrap_or(0)
+            } else {
+                adc.read(&mut adc_pin1).unwrap_or(0)
+            };
+            if let Some(message) = pad.update(raw, now) {
+                send_local(&message, &mut midi_out, &mut usb_midi, &mut usb_dropped);
+            }
+        }
+        
+        encoder.poll(millis);
+        let delta = encoder.take_delta();
+        if delta != 0 {
+            let value = (encoder_value + delta).max(0).min(127);
+            if value != encoder_value {
+                encoder_value = value;
+                let message = midi::MidiMessage::ControlChange { channel: 1, control: ENCODER_CC, value: encoder_value as u8 };
+                send_local(&message, &mut midi_out, &mut usb_midi, &mut usb_dropped);
+            }
+        }
+        
+        // sample the analog inputs once per millisecond
+        if millis != last_analog {
+            last_analog = millis;
+            let adc0: u16 = adc.read(&mut adc_pin0).unwrap_or(0);
+            let adc1: u16 = adc.read(&mut adc_pin1).unwrap_or(0);
+            // the selected multiplexer input when the multiplexer is used
+            let adc2: u16 = adc.read(&mut adc_pin2).unwrap_or(0);
+            for pot in pots.iter_mut() {
+                let raw = match pot.mapping.source {
+                    AnalogSource::Adc(0) => adc0,
+                    AnalogSource::Adc(1) => adc1,
+                    AnalogSource::Adc(_) => adc2,
+                    AnalogSource::Mux(n) if n == mux_input => adc2,
+                    AnalogSource::Mux(_) => continue,
+                };
+                if let Some(value) = pot.update(raw) {
+                    let mapping = pot.mapping;
+                    if mapping.high_res == true {
+                        let message = midi::ParameterMessage::Controller14 { channel: mapping.channel, control: mapping.control, value: value };
+                        send_local_parameter(&message, &mut midi_out, &mut usb_midi, &mut usb_dropped);
+                    } else {
+                        let message = midi::MidiMessage::ControlChange { channel: mapping.channel, control: mapping.control, value: value as u8 };
+                        send_local(&message, &mut midi_out, &mut usb_midi, &mut usb_dropped);
+                    }
+                }
+            }
+            // select the next multiplexer input now, so it has until the next sample to settle
+            if USE_MUX == true {
+                mux_input = (mux_input + 1) % analog::MUX_CHANNELS;
+                if mux_input & 1 != 0 { mux_s0.set_high().unwrap() } else { mux_s0.set_low().unwrap() }
+                if mux_input & 2 != 0 { mux_s1.set_high().unwrap() } else { mux_s1.set_low().unwrap() }
+                if mux_input & 4 != 0 { mux_s2.set_high().unwrap() } else { mux_s2.set_low().unwrap() }
+            }
+        }
+        
+        if usb_midi.flush().is_err() {
+            usb_dropped += usb_midi.clear() as u32;
+        }
+        
+        // the input fifos only hold 32 bytes, anything arriving on the DIN inputs while the
+        // flash is erased may be lost. USB just waits, the host retries until the board answers
+        if let Some(requested) = save_requested {
+            if din_idle() == true || millis.wrapping_sub(requested) >= SAVE_WAIT_MS {
+                save_config(&config);
+                save_requested = None;
+            }
+        }
+        
+        let overflows = cortex_interrupt::free(|cs| {
+            let queues = MIDI_TX.borrow(cs).borrow();
+            [queues[0].overflows(), queues[1].overflows()]
+        });
+        monitor.dropped_output(DIN1, overflows[DIN1 as usize], millis);
+        monitor.dropped_output(DIN2, overflows[DIN2 as usize], millis);
+        monitor.dropped_output(USB, usb_dropped, millis);
+        monitor.poll(millis, micros());
+        if serial.dtr() == false {
+            monitor.clear();
+        } else if monitor.pending().len() > 0 {
+            if let Ok(count) = serial.write(monitor.pending()) {
+                monitor.consume(count);
             }
         }
     }
 }
 
+fn save_config<const I: usize, const A: usize, const R: usize>(config: &config::Config<I, A, R>) {
+    let mut page = [0u8; flash::PAGE_SIZE];
+    config.to_bytes(&mut page);
+    flash::write_settings(&page);
+}
+
+// offers an incoming message to MIDI learn. when an armed input learns it, the new
+// mapping is applied and true is returned. the input was armed with the learn button
+// held, so the old mapping never sent a press and gets no release either
+fn learn_message<const I: usize, const A: usize, const R: usize>(
+    learn: &mut learn::Learn,
+    config: &mut config::Config<I, A, R>,
+    controls: &mut [controls::Control; I],
+    pots: &mut [analog::AnalogControl; A],
+    message: &midi::MidiMessage,
+    millis: u32,
+) -> bool {
+    let (index, mapping) = match learn.learn(controls, message, millis) {
+        Some(v) => v,
+        None => return false,
+    };
+    config.inputs[index] = mapping;
+    apply_config(config, controls, pots);
+    true
+}
+
+fn apply_config<const I: usize, const A: usize, const R: usize>(
+    config: &config::Config<I, A, R>,
+    controls: &mut [controls::Control; I],
+    pots: &mut [analog::AnalogControl; A],
+) {
+    for (control, mapping) in controls.iter_mut().zip(config.inputs.iter()) {
+        control.mapping = *mapping;
+    }
+    for (pot, mapping) in pots.iter_mut().zip(config.analog.iter()) {
+        if pot.mapping != *mapping {
+            let deadband = if mapping.high_res == true { ANALOG_DEADBAND_HIGH_RES } else { ANALOG_DEADBAND };
+            *pot = analog::AnalogControl::new(*mapping, deadband);
+        }
+    }
+}
+
+// the low word of the free running 1MHz timer, read without the Timer object which
+// belongs to the TIMER_IRQ_0 interrupt
+fn micros() -> u32 {
+    unsafe { (*pac::TIMER::ptr()).timerawl.read().bits() }
+}
+
+// runs a message from a MIDI input through the routes and sends the results,
+// messages the USB port couldn't take are counted in usb_dropped
+fn route_message<B: UsbBus, const R: usize>(
+    routes: &[Route; R],
+    input: u8,
+    message: &midi::MidiMessage,
+    midi_out: &mut [midi::MidiEncoder; 2],
+    usb_midi: &mut usbmidi::MidiClass<B>,
+    usb_dropped: &mut u32,
+) {
+    let mut out = [(0, midi::MidiMessage::TuneRequest); R];
+    let count = router::route(routes, input, message, &mut out);
+    for (outputs, routed) in out[..count].iter() {
+        for port in [DIN1, DIN2] {
+            if outputs & (1 << port) != 0 { send_midi(midi_out, port, routed) }
+        }
+        if outputs & (1 << USB) != 0 && usb_midi.send(routed).is_err() {
+            *usb_dropped += 1;
+        }
+    }
+}
+
+fn send_sysex_to<B: UsbBus>(outputs: u8, data: &[u8], midi_out: &mut [midi::MidiEncoder; 2], usb_midi: &mut usbmidi::MidiClass<B>, usb_dropped: &mut u32) {
+    for port in [DIN1, DIN2] {
+        if outputs & (1 << port) != 0 { send_midi_sysex(midi_out, port, data) }
+    }
+    if outputs & (1 << USB) != 0 && usb_midi.send_sysex(data).is_err() {
+        *usb_dropped += 1;
+    }
+}
+
+// the inputs, pots, keys and pads of the board itself go to DIN1 and USB
+fn send_local<B: UsbBus>(message: &midi::MidiMessage, midi_out: &mut [midi::MidiEncoder; 2], usb_midi: &mut usbmidi::MidiClass<B>, usb_dropped: &mut u32) {
+    send_midi(midi_out, DIN1, message);
+    if usb_midi.send(message).is_err() { *usb_dropped += 1 }
+}
+
+fn send_local_parameter<B: UsbBus>(message: &midi::ParameterMessage, midi_out: &mut [midi::MidiEncoder; 2], usb_midi: &mut usbmidi::MidiClass<B>, usb_dropped: &mut u32) {
+    send_parameter(midi_out, DIN1, message);
+    if usb_midi.send_parameter(message, false).is_err() { *usb_dropped += 1 }
+}
+
+// queue a message for DIN1 or DIN2 and start moving it into the UART fifo, messages
+// that don't fit are counted by the queue's overflows
+fn send_midi(encoders: &mut [midi::MidiEncoder; 2], port: u8, message: &midi::MidiMessage) {
+    cortex_interrupt::free(|cs| {
+        let mut queues = MIDI_TX.borrow(cs).borrow_mut();
+        let queue = &mut queues[port as usize];
+        encoders[port as usize].queue(queue, message);
+        fill_uart(port, queue);
+    });
+}
+
+fn send_parameter(encoders: &mut [midi::MidiEncoder; 2], port: u8, message: &midi::ParameterMessage) {
+    cortex_interrupt::free(|cs| {
+        let mut queues = MIDI_TX.borrow(cs).borrow_mut();
+        let queue = &mut queues[port as usize];
+        encoders[port as usize].queue_parameter(queue, message, false);
+        fill_uart(port, queue);
+    });
+}
+
+fn send_midi_sysex(encoders: &mut [midi::MidiEncoder; 2], port: u8, data: &[u8]) {
+    cortex_interrupt::free(|cs| {
+        let mut queues = MIDI_TX.borrow(cs).borrow_mut();
+        let queue = &mut queues[port as usize];
+        encoders[port as usize].queue_sysex(queue, data);
+        fill_uart(port, queue);
+    });
+}
+
+// nothing waiting in either DIN input fifo and nothing left in the output queues, the
+// time to stop everything for a flash write
+fn din_idle() -> bool {
+    let uarts = unsafe { [&*pac::UART0::ptr(), &*pac::UART1::ptr()] };
+    let rx_empty = uarts.iter().all(|uart| uart.uartfr.read().rxfe().bit_is_set());
+    let tx_empty = cortex_interrupt::free(|cs| MIDI_TX.borrow(cs).borrow().iter().all(|queue| queue.is_empty()));
+    rx_empty == true && tx_empty == true
+}
+
+// copy queued bytes into the UART fifo until it is full, the tx interrupt stays
+// enabled while there is anything left so the rest follows when the fifo drains.
+// both UARTs have the same registers
+fn fill_uart(port: u8, queue: &mut TxQueue<TX_QUEUE_SIZE>) {
+    let uart = unsafe { if port == DIN1 { &*pac::UART0::ptr() } else { &*pac::UART1::ptr() } };
+    while uart.uartfr.read().txff().bit_is_clear() {
+        match queue.pop() {
+            Some(byte) => uart.uartdr.write(|w| unsafe { w.data().bits(byte) }),
+            None => break,
+        }
+    }
+    if queue.is_empty() == true {
+        uart.uartimsc.modify(|_, w| w.txim().clear_bit());
+    } else {
+        uart.uartimsc.modify(|_, w| w.txim().set_bit());
+    }
+}
+
+#[allow(non_snake_case)]
+#[interrupt]
+fn UART0_IRQ() {
+    let uart = unsafe { &*pac::UART0::ptr() };
+    uart.uarticr.write(|w| w.txic().set_bit());
+    cortex_interrupt::free(|cs| fill_uart(DIN1, &mut MIDI_TX.borrow(cs).borrow_mut()[DIN1 as usize]));
+}
+
+#[allow(non_snake_case)]
+#[interrupt]
+fn UART1_IRQ() {
+    let uart = unsafe { &*pac::UART1::ptr() };
+    uart.uarticr.write(|w| w.txic().set_bit());
+    cortex_interrupt::free(|cs| fill_uart(DIN2, &mut MIDI_TX.borrow(cs).borrow_mut()[DIN2 as usize]));
+}
+
 #[allow(non_snake_case)]
 #[interrupt]
 unsafe fn TIMER_IRQ_0() {
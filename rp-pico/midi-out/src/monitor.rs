@@ -0,0 +1,525 @@
+use core::fmt::{self, Write};
+
+use crate::buffer::RingBuffer;
+use crate::midi::{MidiMessage, ParameterDecoder, ParameterKind, ParameterMessage};
+use crate::router::{DIN1, DIN2};
+
+const LINE_SIZE: usize = 96;
+// clock ticks averaged for the tempo, one beat
+const CLOCK_TICKS: usize = 24;
+// no clock for this long means it stopped
+const CLOCK_TIMEOUT_US: u32 = 500_000;
+// the tempo is printed again when it moves this many tenths of a bpm
+const CLOCK_CHANGE: u32 = 5;
+const SYSEX_BYTES_PER_LINE: usize = 16;
+
+// one line of text, anything past LINE_SIZE is cut off
+pub struct Line {
+    buf: [u8; LINE_SIZE],
+    len: usize,
+}
+
+impl Line {
+    pub fn new() -> Self {
+        Line { buf: [0; LINE_SIZE], len: 0 }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let count = s.len().min(LINE_SIZE - self.len);
+        self.buf[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+// tempo of an incoming MIDI clock, from the mean time between the last 24 ticks. it is
+// checked once per beat so a tempo change doesn't print a line for every tick
+pub struct ClockEstimator {
+    intervals: RingBuffer<u32, CLOCK_TICKS>,
+    count: usize,
+    beat_ticks: usize,
+    last: Option<u32>,
+    reported: u32,  // tenths of a bpm, 0 before the first estimate
+}
+
+impl ClockEstimator {
+    pub fn new() -> Self {
+        ClockEstimator {
+            intervals: RingBuffer::<u32, CLOCK_TICKS>::new(),
+            count: 0,
+            beat_ticks: 0,
+            last: None,
+            reported: 0,
+        }
+    }
+
+    // call for every clock tick, returns the tempo in tenths of a bpm when it is known
+    // for the first time or has changed
+    pub fn tick(&mut self, micros: u32) -> Option<u32> {
+        if let Some(last) = self.last {
+            let interval = micros.wrapping_sub(last);
+            if interval > CLOCK_TIMEOUT_US {
+                self.count = 0;
+                self.beat_ticks = 0;
+            } else {
+                self.intervals.push(interval);
+                self.count = (self.count + 1).min(CLOCK_TICKS);
+            }
+        }
+        self.last = Some(micros);
+        self.beat_ticks = (self.beat_ticks + 1) % CLOCK_TICKS;
+        if self.count < CLOCK_TICKS || self.beat_ticks != 0 { return None }
+        let mean = self.intervals.mean();
+        if mean == 0 { return None }
+        // 60 s per minute / (24 ticks per beat * mean), in tenths
+        let tenths = 25_000_000 / mean;
+        if self.reported != 0 && tenths.abs_diff(self.reported) < CLOCK_CHANGE { return None }
+        self.reported = tenths;
+        Some(tenths)
+    }
+
+    // true once when a clock that had a tempo stops
+    pub fn stopped(&mut self, micros: u32) -> bool {
+        let last = match self.last {
+            Some(last) => last,
+            None => return false,
+        };
+        if micros.wrapping_sub(last) <= CLOCK_TIMEOUT_US { return false }
+        let was_running = self.reported != 0;
+        self.last = None;
+        self.count = 0;
+        self.beat_ticks = 0;
+        self.reported = 0;
+        was_running
+    }
+}
+
+// turns the MIDI coming into the DIN inputs into readable lines like
+// "    12.345 din1 ch10 NoteOn 36 v=100". (N)RPN controllers get an extra line with the
+// parameter once it is complete. the text is queued here until the USB serial port
+// takes it, lines that don't fit are dropped and counted
+pub struct Monitor<const N: usize> {
+    buf: [u8; N],
+    head: usize,
+    len: usize,
+    dropped: u32,
+    clocks: [ClockEstimator; 2],
+    errors: [u32; 2],
+    outputs: [u32; 3],
+    parameters: [ParameterDecoder; 2],
+}
+
+impl<const N: usize> Monitor<N> {
+    pub fn new() -> Self {
+        Monitor {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+            dropped: 0,
+            clocks: [ClockEstimator::new(), ClockEstimator::new()],
+            errors: [0; 2],
+            outputs: [0; 3],
+            // nothing is known about which controllers are 14-bit pairs, so only (N)RPN
+            parameters: [ParameterDecoder::new(0), ParameterDecoder::new(0)],
+        }
+    }
+
+    // a message from a DIN input, sysex is the parser's SysEx data
+    pub fn message(&mut self, port: u8, message: &MidiMessage, sysex: &[u8], millis: u32, micros: u32) {
+        if port != DIN1 && port != DIN2 { return }
+        if *message == MidiMessage::TimingClock {
+            if let Some(tenths) = self.clocks[port as usize].tick(micros) {
+                let mut line = line_start(input_name(port), millis);
+                write!(line, "Clock {}.{} bpm", tenths / 10, tenths % 10).ok();
+                self.push_line(&line);
+            }
+            return;
+        }
+        let mut line = line_start(input_name(port), millis);
+        write_message(&mut line, message).ok();
+        self.push_line(&line);
+        if let Some(parameter) = self.parameters[port as usize].push(message) {
+            let mut line = line_start(input_name(port), millis);
+            write_parameter(&mut line, &parameter).ok();
+            self.push_line(&line);
+        }
+        if let MidiMessage::SysEx { .. } = message {
+            for chunk in sysex.chunks(SYSEX_BYTES_PER_LINE) {
+                let mut line = Line::new();
+                line.write_str("           ").ok();
+                for byte in chunk.iter() {
+                    write!(line, " {:02X}", byte).ok();
+                }
+                self.push_line(&line);
+            }
+        }
+    }
+
+    // count is MidiParser::errors of the DIN input, a line is printed when it goes up
+    pub fn parse_errors(&mut self, port: u8, count: u32, millis: u32) {
+        if port != DIN1 && port != DIN2 { return }
+        let last = self.errors[port as usize];
+        if count == last { return }
+        self.errors[port as usize] = count;
+        let mut line = line_start(input_name(port), millis);
+        write!(line, "{} stray bytes or broken messages", count.wrapping_sub(last)).ok();
+        self.push_line(&line);
+    }
+
+    // count is the number of messages the output couldn't take, a line is printed when it goes up
+    pub fn dropped_output(&mut self, port: u8, count: u32, millis: u32) {
+        let last = self.outputs[port as usize];
+        if count == last { return }
+        self.outputs[port as usize] = count;
+        let mut line = line_start(output_name(port), millis);
+        write!(line, "{} messages dropped, output full", count.wrapping_sub(last)).ok();
+        self.push_line(&line);
+    }
+
+    // call every loop, notices when a clock stops
+    pub fn poll(&mut self, millis: u32, micros: u32) {
+        for port in [DIN1, DIN2] {
+            if self.clocks[port as usize].stopped(micros) == true {
+                let mut line = line_start(input_name(port), millis);
+                line.write_str("Clock stopped").ok();
+                self.push_line(&line);
+            }
+        }
+    }
+
+    // the next bytes of text to send, call consume with the number actually sent
+    pub fn pending(&self) -> &[u8] {
+        let end = (self.head + self.len).min(N);
+        &self.buf[self.head..end]
+    }
+
+    pub fn consume(&mut self, count: usize) {
+        let count = count.min(self.len);
+        self.head = (self.head + count) % N;
+        self.len -= count;
+    }
+
+    // drops all queued text, for when nothing is listening on the serial port
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+        self.dropped = 0;
+    }
+
+    fn push_line(&mut self, line: &Line) {
+        if self.dropped > 0 {
+            let mut note = Line::new();
+            write!(note, "({} lines dropped)", self.dropped).ok();
+            if self.push_bytes(note.as_bytes()) == false {
+                self.dropped += 1;
+                return;
+            }
+            self.dropped = 0;
+        }
+        if self.push_bytes(line.as_bytes()) == false {
+            self.dropped += 1;
+        }
+    }
+
+    // a whole line and its line ending or nothing
+    fn push_bytes(&mut self, bytes: &[u8]) -> bool {
+        if self.len + bytes.len() + 2 > N { return false }
+        for byte in bytes.iter().chain(b"\r\n".iter()) {
+            self.buf[(self.head + self.len) % N] = *byte;
+            self.len += 1;
+        }
+        true
+    }
+}
+
+// timestamp in seconds and the port
+fn line_start(name: &str, millis: u32) -> Line {
+    let mut line = Line::new();
+    write!(line, "{:>6}.{:03} {} ", millis / 1000, millis % 1000, name).ok();
+    line
+}
+
+fn input_name(port: u8) -> &'static str {
+    match port {
+        DIN1 => "din1",
+        DIN2 => "din2",
+        _ => "usb",
+    }
+}
+
+fn output_name(port: u8) -> &'static str {
+    match port {
+        DIN1 => "out1",
+        DIN2 => "out2",
+        _ => "usbout",
+    }
+}
+
+fn write_parameter(line: &mut Line, message: &ParameterMessage) -> fmt::Result {
+    let name = |kind: ParameterKind| match kind {
+        ParameterKind::Nrpn => "NRPN",
+        ParameterKind::Rpn => "RPN",
+    };
+    match *message {
+        ParameterMessage::Controller14 { channel, control, value } => write!(line, "ch{} CC14 {} = {}", channel, control, value),
+        ParameterMessage::Parameter { channel, kind, parameter, value } => write!(line, "ch{} {} {} = {}", channel, name(kind), parameter, value),
+        ParameterMessage::Increment { channel, kind, parameter, amount } => write!(line, "ch{} {} {} +{}", channel, name(kind), parameter, amount),
+        ParameterMessage::Decrement { channel, kind, parameter, amount } => write!(line, "ch{} {} {} -{}", channel, name(kind), parameter, amount),
+    }
+}
+
+fn write_message(line: &mut Line, message: &MidiMessage) -> fmt::Result {
+    match *message {
+        MidiMessage::NoteOff { channel, note, velocity } => write!(line, "ch{} NoteOff {} v={}", channel, note, velocity),
+        MidiMessage::NoteOn { channel, note, velocity } => write!(line, "ch{} NoteOn {} v={}", channel, note, velocity),
+        MidiMessage::PolyAftertouch { channel, note, pressure } => write!(line, "ch{} PolyAftertouch {} p={}", channel, note, pressure),
+        MidiMessage::ControlChange { channel, control, value } => write!(line, "ch{} CC {} = {}", channel, control, value),
+        MidiMessage::ProgramChange { channel, program } => write!(line, "ch{} ProgramChange {}", channel, program),
+        MidiMessage::ChannelAftertouch { channel, pressure } => write!(line, "ch{} Aftertouch p={}", channel, pressure),
+        MidiMessage::PitchBend { channel, value } => write!(line, "ch{} PitchBend {}", channel, value as i32 - 8192),
+        MidiMessage::AllSoundOff { channel } => write!(line, "ch{} AllSoundOff", channel),
+        MidiMessage::ResetAllControllers { channel } => write!(line, "ch{} ResetAllControllers", channel),
+        MidiMessage::LocalControl { channel, on } => write!(line, "ch{} LocalControl {}", channel, if on == true { "on" } else { "off" }),
+        MidiMessage::AllNotesOff { channel } => write!(line, "ch{} AllNotesOff", channel),
+        MidiMessage::OmniOff { channel } => write!(line, "ch{} OmniOff", channel),
+        MidiMessage::OmniOn { channel } => write!(line, "ch{} OmniOn", channel),
+        MidiMessage::MonoOn { channel, channels } => write!(line, "ch{} MonoOn {}", channel, channels),
+        MidiMessage::PolyOn { channel } => write!(line, "ch{} PolyOn", channel),
+        MidiMessage::SysEx { len, truncated } => {
+            write!(line, "SysEx {} bytes", len)?;
+            if truncated == true { line.write_str(" (truncated)")?; }
+            Ok(())
+        }
+        MidiMessage::TimeCodeQuarterFrame { value } => write!(line, "TimeCode {} = {}", value >> 4, value & 0x0F),
+        MidiMessage::SongPosition { position } => write!(line, "SongPosition {}", position),
+        MidiMessage::SongSelect { song } => write!(line, "SongSelect {}", song),
+        MidiMessage::TuneRequest => line.write_str("TuneRequest"),
+        MidiMessage::TimingClock => line.write_str("Clock"),
+        MidiMessage::Start => line.write_str("Start"),
+        MidiMessage::Continue => line.write_str("Continue"),
+        MidiMessage::Stop => line.write_str("Stop"),
+        MidiMessage::ActiveSensing => line.write_str("ActiveSensing"),
+        MidiMessage::SystemReset => line.write_str("SystemReset"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::USB;
+    use std::string::String;
+    use std::vec::Vec;
+
+    // 100 bpm, 24 ticks per beat
+    const TICK_US: u32 = 25_000;
+
+    // ticks every interval from start, the tick count and tempo of every report
+    fn ticks(clock: &mut ClockEstimator, start: u32, interval: u32, count: u32) -> Vec<(u32, u32)> {
+        let mut reports = Vec::new();
+        for tick in 0..count {
+            if let Some(tenths) = clock.tick(start + tick * interval) {
+                reports.push((tick + 1, tenths));
+            }
+        }
+        reports
+    }
+
+    // everything queued, taken in the pieces pending hands out
+    fn drain<const N: usize>(monitor: &mut Monitor<N>) -> String {
+        let mut out = Vec::new();
+        loop {
+            let count = {
+                let pending = monitor.pending();
+                out.extend_from_slice(pending);
+                pending.len()
+            };
+            if count == 0 { break }
+            monitor.consume(count);
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    fn line(text: &str) -> Line {
+        let mut line = Line::new();
+        line.write_str(text).unwrap();
+        line
+    }
+
+    #[test]
+    fn clock_tempo_is_reported_once_per_change() {
+        let mut clock = ClockEstimator::new();
+        // a full beat of intervals is needed, then it is checked on the next beat
+        assert_eq!(ticks(&mut clock, 0, TICK_US, 48), [(48, 1000)]);
+        // 120 bpm is 20833.3us per tick
+        let mut clock = ClockEstimator::new();
+        assert_eq!(ticks(&mut clock, 0, 20_833, 48), [(48, 1200)]);
+        // the same tempo isn't printed again
+        assert_eq!(ticks(&mut clock, 48 * 20_833, 20_833, 96), []);
+    }
+
+    #[test]
+    fn clock_changes_below_the_threshold_are_ignored() {
+        let mut clock = ClockEstimator::new();
+        assert_eq!(ticks(&mut clock, 0, TICK_US, 48), [(48, 1000)]);
+        // 100.4 bpm
+        let start = 48 * TICK_US;
+        assert_eq!(ticks(&mut clock, start, 24_900, 48), []);
+        // 100.5 bpm, reported once a whole beat was at the new tempo
+        let start = start + 48 * 24_900;
+        assert_eq!(ticks(&mut clock, start, 24_875, 48), [(48, 1005)]);
+    }
+
+    #[test]
+    fn clock_stops_after_the_timeout() {
+        let mut clock = ClockEstimator::new();
+        assert_eq!(clock.stopped(10 * CLOCK_TIMEOUT_US), false);
+        ticks(&mut clock, 0, TICK_US, 48);
+        let last = 47 * TICK_US;
+        assert_eq!(clock.stopped(last + CLOCK_TIMEOUT_US), false);
+        assert_eq!(clock.stopped(last + CLOCK_TIMEOUT_US + 1), true);
+        // only once, and the same tempo is printed again when the clock comes back
+        assert_eq!(clock.stopped(last + 2 * CLOCK_TIMEOUT_US), false);
+        let start = last + 3 * CLOCK_TIMEOUT_US;
+        assert_eq!(ticks(&mut clock, start, TICK_US, 48), [(48, 1000)]);
+    }
+
+    #[test]
+    fn clock_gap_starts_the_estimate_again() {
+        let mut clock = ClockEstimator::new();
+        assert_eq!(ticks(&mut clock, 0, TICK_US, 24), []);
+        // the intervals before the gap don't count towards the beat
+        let start = 23 * TICK_US + CLOCK_TIMEOUT_US + 1;
+        assert_eq!(ticks(&mut clock, start, TICK_US, 48), [(48, 1000)]);
+        // a clock that never had a tempo stops without a line
+        assert_eq!(clock.stopped(start + 47 * TICK_US + CLOCK_TIMEOUT_US + 1), true);
+        ticks(&mut clock, 0, TICK_US, 10);
+        assert_eq!(clock.stopped(9 * TICK_US + CLOCK_TIMEOUT_US + 1), false);
+    }
+
+    #[test]
+    fn queue_wraps_around() {
+        let mut monitor = Monitor::<16>::new();
+        assert_eq!(monitor.push_bytes(b"abcdef"), true);
+        assert_eq!(monitor.pending(), b"abcdef\r\n");
+        monitor.consume(5);
+        assert_eq!(monitor.pending(), b"f\r\n");
+        // 12 more bytes fit, the last 4 go to the start of the buffer
+        assert_eq!(monitor.push_bytes(b"0123456789"), true);
+        assert_eq!(monitor.pending(), b"f\r\n01234567");
+        monitor.consume(11);
+        assert_eq!(monitor.pending(), b"89\r\n");
+        // consuming more than is queued empties it
+        monitor.consume(100);
+        assert_eq!(monitor.pending(), b"");
+    }
+
+    #[test]
+    fn lines_are_queued_whole_or_not_at_all() {
+        let mut monitor = Monitor::<16>::new();
+        assert_eq!(monitor.push_bytes(b"0123456789"), true);
+        assert_eq!(monitor.push_bytes(b"abc"), false);
+        assert_eq!(monitor.push_bytes(b"ab"), true);
+        assert_eq!(drain(&mut monitor), "0123456789\r\nab\r\n");
+    }
+
+    #[test]
+    fn dropped_lines_are_counted() {
+        let mut monitor = Monitor::<32>::new();
+        monitor.push_line(&line("0123456789"));
+        monitor.push_line(&line("0123456789"));
+        monitor.push_line(&line("lost line"));
+        // there isn't room for the note either, so this line is lost too
+        monitor.push_line(&line("x"));
+        assert_eq!(drain(&mut monitor), "0123456789\r\n0123456789\r\n");
+        // the note goes out ahead of the next line that fits
+        monitor.push_line(&line("y"));
+        assert_eq!(drain(&mut monitor), "(2 lines dropped)\r\ny\r\n");
+        monitor.push_line(&line("z"));
+        assert_eq!(drain(&mut monitor), "z\r\n");
+    }
+
+    #[test]
+    fn line_after_the_note_can_still_be_dropped() {
+        let mut monitor = Monitor::<32>::new();
+        monitor.push_line(&line("0123456789"));
+        monitor.push_line(&line("0123456789"));
+        monitor.push_line(&line("lost line"));
+        monitor.consume(12);
+        // 20 bytes free, enough for the note but not the line after it
+        monitor.push_line(&line("lost again"));
+        assert_eq!(drain(&mut monitor), "0123456789\r\n(1 lines dropped)\r\n");
+        monitor.push_line(&line("y"));
+        assert_eq!(drain(&mut monitor), "(1 lines dropped)\r\ny\r\n");
+    }
+
+    #[test]
+    fn clear_forgets_dropped_lines() {
+        let mut monitor = Monitor::<32>::new();
+        monitor.push_line(&line("0123456789"));
+        monitor.push_line(&line("0123456789"));
+        monitor.push_line(&line("lost line"));
+        monitor.clear();
+        assert_eq!(monitor.pending(), b"");
+        monitor.push_line(&line("z"));
+        assert_eq!(drain(&mut monitor), "z\r\n");
+    }
+
+    #[test]
+    fn messages_are_labelled_with_time_and_input() {
+        let mut monitor = Monitor::<256>::new();
+        monitor.message(DIN1, &MidiMessage::NoteOn { channel: 10, note: 36, velocity: 100 }, &[], 12_345, 0);
+        monitor.message(DIN2, &MidiMessage::PitchBend { channel: 1, value: 0 }, &[], 1_234_567, 0);
+        // only the DIN inputs are monitored
+        monitor.message(USB, &MidiMessage::Start, &[], 0, 0);
+        assert_eq!(drain(&mut monitor), "    12.345 din1 ch10 NoteOn 36 v=100\r\n  1234.567 din2 ch1 PitchBend -8192\r\n");
+    }
+
+    #[test]
+    fn sysex_is_dumped_in_hex() {
+        let mut monitor = Monitor::<256>::new();
+        let data: Vec<u8> = (0..20).map(|i| 0x70 + i).collect();
+        monitor.message(DIN1, &MidiMessage::SysEx { len: 20, truncated: false }, &data, 1_000, 0);
+        assert_eq!(drain(&mut monitor), concat!(
+            "     1.000 din1 SysEx 20 bytes\r\n",
+            "            70 71 72 73 74 75 76 77 78 79 7A 7B 7C 7D 7E 7F\r\n",
+            "            80 81 82 83\r\n",
+        ));
+        monitor.message(DIN2, &MidiMessage::SysEx { len: 64, truncated: true }, &[0x7D], 2_000, 0);
+        assert_eq!(drain(&mut monitor), "     2.000 din2 SysEx 64 bytes (truncated)\r\n            7D\r\n");
+    }
+
+    #[test]
+    fn clock_is_printed_as_a_tempo() {
+        let mut monitor = Monitor::<256>::new();
+        for tick in 0..48 {
+            monitor.message(DIN2, &MidiMessage::TimingClock, &[], 500, tick * TICK_US);
+        }
+        monitor.poll(1_000, 47 * TICK_US + CLOCK_TIMEOUT_US);
+        assert_eq!(drain(&mut monitor), "     0.500 din2 Clock 100.0 bpm\r\n");
+        monitor.poll(2_000, 47 * TICK_US + CLOCK_TIMEOUT_US + 1);
+        assert_eq!(drain(&mut monitor), "     2.000 din2 Clock stopped\r\n");
+    }
+
+    #[test]
+    fn dropped_output_is_labelled_with_the_output() {
+        let mut monitor = Monitor::<256>::new();
+        monitor.dropped_output(DIN1, 0, 0);
+        monitor.dropped_output(DIN1, 2, 1_000);
+        monitor.dropped_output(DIN1, 2, 2_000);
+        monitor.dropped_output(DIN2, 5, 3_000);
+        monitor.dropped_output(USB, 1, 4_000);
+        monitor.dropped_output(DIN1, 3, 5_000);
+        assert_eq!(drain(&mut monitor), concat!(
+            "     1.000 out1 2 messages dropped, output full\r\n",
+            "     3.000 out2 5 messages dropped, output full\r\n",
+            "     4.000 usbout 1 messages dropped, output full\r\n",
+            "     5.000 out1 1 messages dropped, output full\r\n",
+        ));
+    }
+}
@@ -0,0 +1,415 @@
+use crate::midi::MidiMessage;
+use crate::velocity::{VelocityCurve, AMOUNT_MAX};
+
+// ports are numbered for the route inputs and bits of the output masks
+pub const DIN1: u8 = 0;   // UART0, gpio0 TX / gpio1 RX
+pub const DIN2: u8 = 1;   // UART1, gpio8 TX / gpio9 RX
+pub const USB: u8 = 2;
+pub const PORTS: u8 = 3;
+
+// message type bits for Filter::types
+pub const TYPE_NOTE: u16 = 1 << 0;            // note on and note off
+pub const TYPE_POLY_AFTERTOUCH: u16 = 1 << 1;
+pub const TYPE_CONTROL: u16 = 1 << 2;         // controllers and channel mode messages
+pub const TYPE_PROGRAM: u16 = 1 << 3;
+pub const TYPE_AFTERTOUCH: u16 = 1 << 4;
+pub const TYPE_PITCH_BEND: u16 = 1 << 5;
+pub const TYPE_SYSEX: u16 = 1 << 6;
+pub const TYPE_SYSTEM: u16 = 1 << 7;          // time code, song position, song select, tune request
+pub const TYPE_REALTIME: u16 = 1 << 8;        // clock, start, stop, continue, active sensing, reset
+pub const TYPE_ALL: u16 = 0x01FF;
+
+// no CC remap
+pub const CC_NONE: u8 = 127;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Filter {
+    pub channels: u16,  // bit 0 = channel 1, only applies to channel messages
+    pub types: u16,
+    pub note_low: u8,   // notes and poly aftertouch outside of note_low-note_high are dropped
+    pub note_high: u8,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transform {
+    pub channel: u8,          // 0 keeps the channel, 1-16 moves channel messages there
+    pub transpose: i8,        // -64 to 63, notes moved out of 0-127 are dropped
+    pub velocity_curve: VelocityCurve,
+    pub velocity_scale: u8,   // 64 = unchanged, note on velocities are multiplied by scale / 64
+    pub cc_from: u8,          // controller cc_from becomes cc_to, CC_NONE turns it off
+    pub cc_to: u8,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Route {
+    pub enabled: bool,
+    pub input: u8,
+    pub outputs: u8,  // bit n = port n
+    pub filter: Filter,
+    pub transform: Transform,
+}
+
+impl Filter {
+    pub const fn all() -> Self {
+        Filter { channels: 0xFFFF, types: TYPE_ALL, note_low: 0, note_high: 127 }
+    }
+}
+
+impl Transform {
+    pub const fn none() -> Self {
+        Transform {
+            channel: 0,
+            transpose: 0,
+            velocity_curve: VelocityCurve::Linear,
+            velocity_scale: 64,
+            cc_from: CC_NONE,
+            cc_to: CC_NONE,
+        }
+    }
+}
+
+impl Route {
+    // everything from input to outputs, unchanged
+    pub const fn thru(input: u8, outputs: u8) -> Self {
+        Route { enabled: true, input: input, outputs: outputs, filter: Filter::all(), transform: Transform::none() }
+    }
+
+    pub const fn disabled() -> Self {
+        Route { enabled: false, input: 0, outputs: 0, filter: Filter::all(), transform: Transform::none() }
+    }
+
+    pub const fn valid(&self) -> bool {
+        self.input < PORTS
+            && self.outputs < (1 << PORTS)
+            && self.filter.note_low <= 127
+            && self.filter.note_high <= 127
+            && self.filter.types & !TYPE_ALL == 0
+            && self.transform.channel <= 16
+            && self.transform.transpose >= -64
+            && self.transform.transpose <= 63
+            && self.transform.velocity_scale <= 127
+            && self.transform.cc_from <= 127
+            // a controller can't be remapped onto the channel mode messages
+            && (self.transform.cc_from == CC_NONE || self.transform.cc_to <= 119)
+            && match self.transform.velocity_curve {
+                VelocityCurve::Fixed(velocity) => velocity >= 1 && velocity <= 127,
+                _ => true,
+            }
+    }
+
+    // the message this route sends for a message from its input, None if it is filtered out
+    pub fn apply(&self, input: u8, message: &MidiMessage) -> Option<MidiMessage> {
+        if self.enabled == false || self.input != input || self.outputs == 0 { return None }
+        if self.filter.types & message_type(message) == 0 { return None }
+        if let Some(channel) = message.channel() {
+            if self.filter.channels & (1 << (channel.max(1).min(16) - 1)) == 0 { return None }
+        }
+        let mut out = *message;
+        match &mut out {
+            MidiMessage::NoteOn { note, velocity, .. } => {
+                *note = self.note(*note)?;
+                *velocity = self.velocity(*velocity);
+            }
+            MidiMessage::NoteOff { note, .. } | MidiMessage::PolyAftertouch { note, .. } => {
+                *note = self.note(*note)?;
+            }
+            MidiMessage::ControlChange { control, .. } => {
+                if self.transform.cc_from != CC_NONE && *control == self.transform.cc_from {
+                    *control = self.transform.cc_to;
+                }
+            }
+            _ => (),
+        }
+        if self.transform.channel != 0 {
+            set_channel(&mut out, self.transform.channel);
+        }
+        Some(out)
+    }
+
+    fn note(&self, note: u8) -> Option<u8> {
+        if note < self.filter.note_low || note > self.filter.note_high { return None }
+        let note = note as i16 + self.transform.transpose as i16;
+        if note < 0 || note > 127 { return None }
+        Some(note as u8)
+    }
+
+    fn velocity(&self, velocity: u8) -> u8 {
+        // a note on with velocity 0 is a note off and stays that way
+        if velocity == 0 { return 0 }
+        let amount = (velocity.min(127) as u32 * AMOUNT_MAX + 63) / 127;
+        let curved = self.transform.velocity_curve.velocity(amount) as u32;
+        (curved * self.transform.velocity_scale as u32 / 64).max(1).min(127) as u8
+    }
+}
+
+pub fn message_type(message: &MidiMessage) -> u16 {
+    match message {
+        MidiMessage::NoteOn { .. } | MidiMessage::NoteOff { .. } => TYPE_NOTE,
+        MidiMessage::PolyAftertouch { .. } => TYPE_POLY_AFTERTOUCH,
+        MidiMessage::ProgramChange { .. } => TYPE_PROGRAM,
+        MidiMessage::ChannelAftertouch { .. } => TYPE_AFTERTOUCH,
+        MidiMessage::PitchBend { .. } => TYPE_PITCH_BEND,
+        MidiMessage::SysEx { .. } => TYPE_SYSEX,
+        MidiMessage::TimeCodeQuarterFrame { .. }
+        | MidiMessage::SongPosition { .. }
+        | MidiMessage::SongSelect { .. }
+        | MidiMessage::TuneRequest => TYPE_SYSTEM,
+        _ if message.is_realtime() == true => TYPE_REALTIME,
+        _ => TYPE_CONTROL,
+    }
+}
+
+fn set_channel(message: &mut MidiMessage, new_channel: u8) {
+    match message {
+        MidiMessage::NoteOff { channel, .. }
+        | MidiMessage::NoteOn { channel, .. }
+        | MidiMessage::PolyAftertouch { channel, .. }
+        | MidiMessage::ControlChange { channel, .. }
+        | MidiMessage::ProgramChange { channel, .. }
+        | MidiMessage::ChannelAftertouch { channel, .. }
+        | MidiMessage::PitchBend { channel, .. }
+        | MidiMessage::AllSoundOff { channel }
+        | MidiMessage::ResetAllControllers { channel }
+        | MidiMessage::LocalControl { channel, .. }
+        | MidiMessage::AllNotesOff { channel }
+        | MidiMessage::OmniOff { channel }
+        | MidiMessage::OmniOn { channel }
+        | MidiMessage::MonoOn { channel, .. }
+        | MidiMessage::PolyOn { channel } => *channel = new_channel,
+        _ => (),
+    }
+}
+
+// runs a message from input through every route, out gets the message each matching
+// route sends and its output mask. returns the number of results
+pub fn route<const R: usize>(routes: &[Route; R], input: u8, message: &MidiMessage, out: &mut [(u8, MidiMessage); R]) -> usize {
+    let mut count = 0;
+    for route in routes.iter() {
+        if let Some(routed) = route.apply(input, message) {
+            out[count] = (route.outputs, routed);
+            count += 1;
+        }
+    }
+    count
+}
+
+// SysEx can't be transformed, it goes to every output of the routes that let it through
+pub fn sysex_outputs(routes: &[Route], input: u8) -> u8 {
+    let mut outputs = 0;
+    for route in routes.iter() {
+        if route.enabled == true && route.input == input && route.filter.types & TYPE_SYSEX != 0 {
+            outputs |= route.outputs;
+        }
+    }
+    outputs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::{MidiEncoder, MidiParser};
+    use crate::txqueue::TxQueue;
+    use std::vec::Vec;
+
+    // the bytes each port sends for bytes arriving on input, through a parser, the routes
+    // and one encoder per output like the main loop. the queues are emptied after every
+    // byte, so realtime messages don't jump ahead
+    fn run<const R: usize>(routes: &[Route; R], input: u8, bytes: &[u8]) -> [Vec<u8>; 3] {
+        let mut parser = MidiParser::<32>::new();
+        let mut encoders = [MidiEncoder::new(false), MidiEncoder::new(false), MidiEncoder::new(false)];
+        let mut queues = [TxQueue::<256>::new(), TxQueue::<256>::new(), TxQueue::<256>::new()];
+        let mut sent = [Vec::new(), Vec::new(), Vec::new()];
+        for byte in bytes.iter() {
+            match parser.push(*byte) {
+                Some(MidiMessage::SysEx { .. }) => {
+                    let outputs = sysex_outputs(routes, input);
+                    for port in 0..PORTS {
+                        if outputs & (1 << port) != 0 {
+                            encoders[port as usize].queue_sysex(&mut queues[port as usize], parser.sysex());
+                        }
+                    }
+                }
+                Some(message) => {
+                    let mut out = [(0, MidiMessage::TuneRequest); R];
+                    let count = route(routes, input, &message, &mut out);
+                    for (outputs, routed) in out[..count].iter() {
+                        for port in 0..PORTS {
+                            if outputs & (1 << port) != 0 {
+                                encoders[port as usize].queue(&mut queues[port as usize], routed);
+                            }
+                        }
+                    }
+                }
+                None => (),
+            }
+            for (queue, bytes) in queues.iter_mut().zip(sent.iter_mut()) {
+                bytes.extend(core::iter::from_fn(|| queue.pop()));
+            }
+        }
+        sent
+    }
+
+    fn to_usb(filter: Filter, transform: Transform) -> [Route; 1] {
+        [Route { enabled: true, input: DIN1, outputs: 1 << USB, filter: filter, transform: transform }]
+    }
+
+    const NOTES: [u8; 9] = [0x90, 60, 100, 0x80, 60, 64, 0xA0, 60, 30];
+
+    #[test]
+    fn thru_passes_everything_unchanged() {
+        let bytes = [
+            0x90, 60, 100, 0x81, 61, 0, 0xA2, 62, 30, 0xB3, 7, 90, 0xC4, 5, 0xD5, 40, 0xE6, 0, 64,
+            0xF0, 0x7D, 1, 2, 0xF7, 0xF1, 0x12, 0xF2, 0, 8, 0xF3, 3, 0xF6, 0xF8, 0xFA, 0xFC,
+        ];
+        let out = run(&[Route::thru(DIN1, 1 << USB)], DIN1, &bytes);
+        assert_eq!(out[USB as usize], bytes);
+        assert_eq!(out[DIN1 as usize], []);
+        assert_eq!(out[DIN2 as usize], []);
+    }
+
+    #[test]
+    fn routes_only_take_their_input() {
+        let routes = [Route::thru(DIN2, 1 << USB), Route::thru(USB, 1 << DIN1)];
+        let out = run(&routes, DIN1, &NOTES);
+        assert_eq!(out, [[], [], []]);
+        let out = run(&routes, USB, &NOTES);
+        assert_eq!(out[DIN1 as usize], NOTES);
+        assert_eq!(out[USB as usize], []);
+    }
+
+    #[test]
+    fn every_matching_route_sends() {
+        let routes = [
+            Route::thru(DIN1, 1 << USB),
+            Route::thru(DIN1, (1 << DIN2) | (1 << USB)),
+            Route::disabled(),
+        ];
+        let out = run(&routes, DIN1, &[0xB0, 1, 2]);
+        assert_eq!(out[DIN2 as usize], [0xB0, 1, 2]);
+        assert_eq!(out[USB as usize], [0xB0, 1, 2, 0xB0, 1, 2]);
+    }
+
+    #[test]
+    fn disabled_route_sends_nothing() {
+        let mut routes = [Route::thru(DIN1, 1 << USB)];
+        routes[0].enabled = false;
+        assert_eq!(run(&routes, DIN1, &[0x90, 60, 100, 0xF0, 1, 0xF7, 0xF8]), [[], [], []]);
+    }
+
+    #[test]
+    fn channel_filter() {
+        let filter = Filter { channels: (1 << 0) | (1 << 15), ..Filter::all() };
+        let out = run(&to_usb(filter, Transform::none()), DIN1, &[0x90, 60, 100, 0x91, 60, 100, 0x9F, 61, 100, 0xF8]);
+        // system messages have no channel and aren't filtered by it
+        assert_eq!(out[USB as usize], [0x90, 60, 100, 0x9F, 61, 100, 0xF8]);
+    }
+
+    #[test]
+    fn type_filter() {
+        let bytes = [
+            0x90, 60, 100, 0xA0, 60, 30, 0xB0, 7, 90, 0xC0, 5, 0xD0, 40, 0xE0, 0, 64,
+            0xF0, 1, 0xF7, 0xF2, 0, 8, 0xF8,
+        ];
+        let expected: [(u16, &[u8]); 9] = [
+            (TYPE_NOTE, &[0x90, 60, 100]),
+            (TYPE_POLY_AFTERTOUCH, &[0xA0, 60, 30]),
+            (TYPE_CONTROL, &[0xB0, 7, 90]),
+            (TYPE_PROGRAM, &[0xC0, 5]),
+            (TYPE_AFTERTOUCH, &[0xD0, 40]),
+            (TYPE_PITCH_BEND, &[0xE0, 0, 64]),
+            (TYPE_SYSEX, &[0xF0, 1, 0xF7]),
+            (TYPE_SYSTEM, &[0xF2, 0, 8]),
+            (TYPE_REALTIME, &[0xF8]),
+        ];
+        for (types, kept) in expected.iter() {
+            let filter = Filter { types: *types, ..Filter::all() };
+            let out = run(&to_usb(filter, Transform::none()), DIN1, &bytes);
+            assert_eq!(out[USB as usize], *kept);
+        }
+    }
+
+    #[test]
+    fn note_range_filter() {
+        let filter = Filter { note_low: 48, note_high: 59, ..Filter::all() };
+        let bytes = [0x90, 47, 100, 0x90, 48, 100, 0x80, 59, 0, 0x90, 60, 100, 0xA0, 50, 30, 0xB0, 50, 1];
+        let out = run(&to_usb(filter, Transform::none()), DIN1, &bytes);
+        // the range only applies to notes and poly aftertouch
+        assert_eq!(out[USB as usize], [0x90, 48, 100, 0x80, 59, 0, 0xA0, 50, 30, 0xB0, 50, 1]);
+    }
+
+    #[test]
+    fn channel_transform() {
+        let transform = Transform { channel: 4, ..Transform::none() };
+        let out = run(&to_usb(Filter::all(), transform), DIN1, &[0x90, 60, 100, 0xBF, 123, 0, 0xE1, 0, 64, 0xF8]);
+        assert_eq!(out[USB as usize], [0x93, 60, 100, 0xB3, 123, 0, 0xE3, 0, 64, 0xF8]);
+    }
+
+    #[test]
+    fn transpose() {
+        let transform = Transform { transpose: 12, ..Transform::none() };
+        let out = run(&to_usb(Filter::all(), transform), DIN1, &NOTES);
+        assert_eq!(out[USB as usize], [0x90, 72, 100, 0x80, 72, 64, 0xA0, 72, 30]);
+        let transform = Transform { transpose: -64, ..Transform::none() };
+        let out = run(&to_usb(Filter::all(), transform), DIN1, &[0x90, 64, 100, 0x80, 64, 0]);
+        assert_eq!(out[USB as usize], [0x90, 0, 100, 0x80, 0, 0]);
+    }
+
+    #[test]
+    fn notes_transposed_out_of_range_are_dropped() {
+        let transform = Transform { transpose: 12, ..Transform::none() };
+        let bytes = [0x90, 115, 100, 0x90, 116, 100, 0x80, 116, 0, 0xA0, 116, 30];
+        let out = run(&to_usb(Filter::all(), transform), DIN1, &bytes);
+        assert_eq!(out[USB as usize], [0x90, 127, 100]);
+        let transform = Transform { transpose: -64, ..Transform::none() };
+        let out = run(&to_usb(Filter::all(), transform), DIN1, &[0x90, 63, 100, 0x80, 63, 0, 0xB0, 63, 1]);
+        assert_eq!(out[USB as usize], [0xB0, 63, 1]);
+    }
+
+    #[test]
+    fn velocity_curve_and_scale() {
+        let transform = Transform { velocity_curve: VelocityCurve::Fixed(90), ..Transform::none() };
+        let out = run(&to_usb(Filter::all(), transform), DIN1, &[0x90, 60, 1, 0x90, 61, 127, 0x80, 60, 64]);
+        // note off velocities are left alone
+        assert_eq!(out[USB as usize], [0x90, 60, 90, 0x90, 61, 90, 0x80, 60, 64]);
+        let transform = Transform { velocity_scale: 32, ..Transform::none() };
+        let out = run(&to_usb(Filter::all(), transform), DIN1, &[0x90, 60, 100, 0x90, 61, 1, 0x90, 62, 127]);
+        assert_eq!(out[USB as usize], [0x90, 60, 50, 0x90, 61, 1, 0x90, 62, 63]);
+        let transform = Transform { velocity_scale: 127, ..Transform::none() };
+        let out = run(&to_usb(Filter::all(), transform), DIN1, &[0x90, 60, 100, 0x90, 61, 10]);
+        assert_eq!(out[USB as usize], [0x90, 60, 127, 0x90, 61, 19]);
+    }
+
+    #[test]
+    fn velocity_0_stays_a_note_off() {
+        let transform = Transform { velocity_curve: VelocityCurve::Fixed(90), velocity_scale: 127, ..Transform::none() };
+        let routes = to_usb(Filter::all(), transform);
+        let out = run(&routes, DIN1, &[0x90, 60, 0]);
+        assert_eq!(out[USB as usize], [0x80, 60, 0]);
+        // a note on with velocity 0 that didn't come through the parser
+        let message = MidiMessage::NoteOn { channel: 1, note: 60, velocity: 0 };
+        assert_eq!(routes[0].apply(DIN1, &message), Some(message));
+    }
+
+    #[test]
+    fn controller_remap() {
+        let transform = Transform { cc_from: 1, cc_to: 74, ..Transform::none() };
+        let out = run(&to_usb(Filter::all(), transform), DIN1, &[0xB0, 1, 10, 0xB0, 2, 20, 0xB0, 74, 30]);
+        assert_eq!(out[USB as usize], [0xB0, 74, 10, 0xB0, 2, 20, 0xB0, 74, 30]);
+        let out = run(&to_usb(Filter::all(), Transform::none()), DIN1, &[0xB0, 127, 0]);
+        assert_eq!(out[USB as usize], [0xB0, 127, 0]);
+    }
+
+    #[test]
+    fn sysex_goes_to_routes_that_let_it_through() {
+        let routes = [
+            Route::thru(DIN1, 1 << USB),
+            Route { filter: Filter { types: TYPE_NOTE, ..Filter::all() }, ..Route::thru(DIN1, 1 << DIN2) },
+            // the transforms don't apply to SysEx
+            Route { transform: Transform { channel: 2, transpose: 5, ..Transform::none() }, ..Route::thru(DIN1, 1 << DIN1) },
+        ];
+        let out = run(&routes, DIN1, &[0xF0, 0x7D, 60, 100, 0xF7]);
+        assert_eq!(out[USB as usize], [0xF0, 0x7D, 60, 100, 0xF7]);
+        assert_eq!(out[DIN1 as usize], [0xF0, 0x7D, 60, 100, 0xF7]);
+        assert_eq!(out[DIN2 as usize], []);
+    }
+}
@@ -0,0 +1,493 @@
+use crate::midi::{MidiMessage, ParameterKind, ParameterMessage};
+
+// Universal MIDI Packet message types, the top nibble of the first word
+const MT_UTILITY: u8 = 0x0;
+const MT_SYSTEM: u8 = 0x1;
+const MT_MIDI1_CHANNEL_VOICE: u8 = 0x2;
+const MT_DATA64: u8 = 0x3;
+const MT_MIDI2_CHANNEL_VOICE: u8 = 0x4;
+
+// number of 32-bit words in a packet, from the message type of its first word
+pub fn packet_words(word0: u32) -> usize {
+    match (word0 >> 28) as u8 {
+        0x0 | 0x1 | 0x2 | 0x6 | 0x7 => 1,
+        0x3 | 0x4 | 0x8 | 0x9 | 0xA => 2,
+        0xB | 0xC => 3,
+        _ => 4,
+    }
+}
+
+// one UMP of 1 to 4 words. groups are 0-15
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Packet {
+    words: [u32; 4],
+    len: usize,
+}
+
+impl Packet {
+    fn one(word0: u32) -> Self {
+        Packet { words: [word0, 0, 0, 0], len: 1 }
+    }
+
+    fn two(word0: u32, word1: u32) -> Self {
+        Packet { words: [word0, word1, 0, 0], len: 2 }
+    }
+
+    // takes the first packet from a stream of words, None if the stream is too short
+    pub fn from_words(words: &[u32]) -> Option<Self> {
+        let first = *words.first()?;
+        let len = packet_words(first);
+        if words.len() < len { return None }
+        let mut packet = Packet { words: [0; 4], len: len };
+        packet.words[..len].copy_from_slice(&words[..len]);
+        Some(packet)
+    }
+
+    pub fn words(&self) -> &[u32] {
+        &self.words[..self.len]
+    }
+
+    pub fn message_type(&self) -> u8 {
+        (self.words[0] >> 28) as u8
+    }
+
+    pub fn group(&self) -> u8 {
+        ((self.words[0] >> 24) & 0x0F) as u8
+    }
+
+    fn status(&self) -> u8 {
+        (self.words[0] >> 16) as u8
+    }
+
+    fn byte2(&self) -> u8 {
+        (self.words[0] >> 8) as u8
+    }
+
+    fn byte3(&self) -> u8 {
+        self.words[0] as u8
+    }
+}
+
+fn word(message_type: u8, group: u8, b1: u8, b2: u8, b3: u8) -> u32 {
+    ((message_type as u32) << 28)
+        | (((group & 0x0F) as u32) << 24)
+        | ((b1 as u32) << 16)
+        | ((b2 as u32) << 8)
+        | (b3 as u32)
+}
+
+// min-center-max upscaling from the MIDI 2.0 spec: 0 stays 0, the center value stays
+// the center and the maximum becomes the maximum, by repeating the lower bits
+pub fn scale_up(value: u32, src_bits: u32, dst_bits: u32) -> u32 {
+    let scale_bits = dst_bits - src_bits;
+    let src_center = 1 << (src_bits - 1);
+    if value <= src_center {
+        return value << scale_bits;
+    }
+    let repeat_bits = src_bits - 1;
+    let repeat_mask = (1 << repeat_bits) - 1;
+    let mut repeat_value = value & repeat_mask;
+    if scale_bits > repeat_bits {
+        repeat_value <<= scale_bits - repeat_bits;
+    } else {
+        repeat_value >>= repeat_bits - scale_bits;
+    }
+    let mut result = value << scale_bits;
+    while repeat_value != 0 {
+        result |= repeat_value;
+        repeat_value >>= repeat_bits;
+    }
+    result
+}
+
+// downscaling just drops the lower bits
+pub fn scale_down(value: u32, src_bits: u32, dst_bits: u32) -> u32 {
+    value >> (src_bits - dst_bits)
+}
+
+// MIDI 1.0 channel voice and system messages in MIDI 1.0 UMPs (message types 2 and 1).
+// SysEx is sent with Sysex7Packets since the data isn't part of the message
+pub fn midi1_to_packet(group: u8, message: &MidiMessage) -> Option<Packet> {
+    let mut buf = [0u8; 3];
+    let len = message.to_bytes(&mut buf);
+    if len == 0 { return None }
+    // unused data bytes must be 0
+    for b in buf[len..].iter_mut() { *b = 0 }
+    let message_type = if buf[0] >= 0xF0 { MT_SYSTEM } else { MT_MIDI1_CHANNEL_VOICE };
+    Some(Packet::one(word(message_type, group, buf[0], buf[1], buf[2])))
+}
+
+// reads MIDI 1.0 channel voice, system and MIDI 2.0 channel voice packets as MIDI 1.0
+// messages, MIDI 2.0 values are scaled down. (N)RPN controllers are left out, see
+// Midi2Message::to_parameter
+pub fn packet_to_midi1(packet: &Packet) -> Option<MidiMessage> {
+    match packet.message_type() {
+        MT_SYSTEM | MT_MIDI1_CHANNEL_VOICE => {
+            MidiMessage::from_bytes(&[packet.status(), packet.byte2() & 0x7F, packet.byte3() & 0x7F])
+        }
+        MT_MIDI2_CHANNEL_VOICE => Midi2Message::from_packet(packet)?.to_midi1(),
+        _ => None,
+    }
+}
+
+// channels are 1-16 like MidiMessage, velocities are 16-bit and controllers 32-bit
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Midi2Message {
+    NoteOff { channel: u8, note: u8, velocity: u16, attribute_type: u8, attribute: u16 },
+    NoteOn { channel: u8, note: u8, velocity: u16, attribute_type: u8, attribute: u16 },
+    PolyPressure { channel: u8, note: u8, value: u32 },
+    RegisteredController { channel: u8, bank: u8, index: u8, value: u32 },
+    AssignableController { channel: u8, bank: u8, index: u8, value: u32 },
+    ControlChange { channel: u8, control: u8, value: u32 },
+    // bank is (MSB, LSB) and is only applied when present
+    ProgramChange { channel: u8, program: u8, bank: Option<(u8, u8)> },
+    ChannelPressure { channel: u8, value: u32 },
+    PitchBend { channel: u8, value: u32 },  // 0x80000000 is the center
+}
+
+fn filter_channel(channel: u8) -> u8 {
+    (channel.max(1).min(16)) - 1
+}
+
+impl Midi2Message {
+    pub fn to_packet(&self, group: u8) -> Packet {
+        let cv = |status: u8, channel: u8, b2: u8, b3: u8, data: u32| {
+            Packet::two(word(MT_MIDI2_CHANNEL_VOICE, group, status | filter_channel(channel), b2, b3), data)
+        };
+        match *self {
+            Midi2Message::NoteOff { channel, note, velocity, attribute_type, attribute } => {
+                cv(0x80, channel, note & 0x7F, attribute_type, ((velocity as u32) << 16) | (attribute as u32))
+            }
+            Midi2Message::NoteOn { channel, note, velocity, attribute_type, attribute } => {
+                cv(0x90, channel, note & 0x7F, attribute_type, ((velocity as u32) << 16) | (attribute as u32))
+            }
+            Midi2Message::PolyPressure { channel, note, value } => cv(0xA0, channel, note & 0x7F, 0, value),
+            Midi2Message::RegisteredController { channel, bank, index, value } => {
+                cv(0x20, channel, bank & 0x7F, index & 0x7F, value)
+            }
+            Midi2Message::AssignableController { channel, bank, index, value } => {
+                cv(0x30, channel, bank & 0x7F, index & 0x7F, value)
+            }
+            Midi2Message::ControlChange { channel, control, value } => cv(0xB0, channel, control & 0x7F, 0, value),
+            Midi2Message::ProgramChange { channel, program, bank } => {
+                let (flags, msb, lsb) = match bank {
+                    Some((msb, lsb)) => (1, msb & 0x7F, lsb & 0x7F),
+                    None => (0, 0, 0),
+                };
+                cv(0xC0, channel, 0, flags, ((program as u32 & 0x7F) << 24) | ((msb as u32) << 8) | (lsb as u32))
+            }
+            Midi2Message::ChannelPressure { channel, value } => cv(0xD0, channel, 0, 0, value),
+            Midi2Message::PitchBend { channel, value } => cv(0xE0, channel, 0, 0, value),
+        }
+    }
+
+    pub fn from_packet(packet: &Packet) -> Option<Self> {
+        if packet.message_type() != MT_MIDI2_CHANNEL_VOICE { return None }
+        let channel = (packet.status() & 0x0F) + 1;
+        let b2 = packet.byte2() & 0x7F;
+        let b3 = packet.byte3();
+        let data = packet.words[1];
+        let message = match packet.status() & 0xF0 {
+            0x80 => Midi2Message::NoteOff {
+                channel: channel,
+                note: b2,
+                velocity: (data >> 16) as u16,
+                attribute_type: b3,
+                attribute: data as u16,
+            },
+            0x90 => Midi2Message::NoteOn {
+                channel: channel,
+                note: b2,
+                velocity: (data >> 16) as u16,
+                attribute_type: b3,
+                attribute: data as u16,
+            },
+            0xA0 => Midi2Message::PolyPressure { channel: channel, note: b2, value: data },
+            0x20 => Midi2Message::RegisteredController { channel: channel, bank: b2, index: b3 & 0x7F, value: data },
+            0x30 => Midi2Message::AssignableController { channel: channel, bank: b2, index: b3 & 0x7F, value: data },
+            0xB0 => Midi2Message::ControlChange { channel: channel, control: b2, value: data },
+            0xC0 => Midi2Message::ProgramChange {
+                channel: channel,
+                program: ((data >> 24) & 0x7F) as u8,
+                bank: if b3 & 1 != 0 { Some((((data >> 8) & 0x7F) as u8, (data & 0x7F) as u8)) } else { None },
+            },
+            0xD0 => Midi2Message::ChannelPressure { channel: channel, value: data },
+            0xE0 => Midi2Message::PitchBend { channel: channel, value: data },
+            // per-note controllers and management messages have no MIDI 1.0 equivalent here
+            _ => return None,
+        };
+        Some(message)
+    }
+
+    // upscales a MIDI 1.0 channel voice message. the controllers that make up bank select
+    // and (N)RPN are passed through as plain controllers, see from_parameter for those
+    pub fn from_midi1(message: &MidiMessage) -> Option<Self> {
+        let mut buf = [0u8; 3];
+        if message.to_bytes(&mut buf) == 0 || buf[0] >= 0xF0 { return None }
+        let channel = (buf[0] & 0x0F) + 1;
+        let (d0, d1) = (buf[1], buf[2]);
+        let message = match buf[0] & 0xF0 {
+            0x80 => Midi2Message::NoteOff {
+                channel: channel,
+                note: d0,
+                velocity: scale_up(d1 as u32, 7, 16) as u16,
+                attribute_type: 0,
+                attribute: 0,
+            },
+            // a MIDI 1.0 note on with velocity 0 is a note off, MIDI 2.0 note ons can have velocity 0
+            0x90 if d1 == 0 => Midi2Message::NoteOff {
+                channel: channel,
+                note: d0,
+                velocity: 0,
+                attribute_type: 0,
+                attribute: 0,
+            },
+            0x90 => Midi2Message::NoteOn {
+                channel: channel,
+                note: d0,
+                velocity: scale_up(d1 as u32, 7, 16) as u16,
+                attribute_type: 0,
+                attribute: 0,
+            },
+            0xA0 => Midi2Message::PolyPressure { channel: channel, note: d0, value: scale_up(d1 as u32, 7, 32) },
+            0xB0 => Midi2Message::ControlChange { channel: channel, control: d0, value: scale_up(d1 as u32, 7, 32) },
+            0xC0 => Midi2Message::ProgramChange { channel: channel, program: d0, bank: None },
+            0xD0 => Midi2Message::ChannelPressure { channel: channel, value: scale_up(d0 as u32, 7, 32) },
+            _ => {
+                let bend = (d0 as u32) | ((d1 as u32) << 7);
+                Midi2Message::PitchBend { channel: channel, value: scale_up(bend, 14, 32) }
+            }
+        };
+        Some(message)
+    }
+
+    // downscales to a MIDI 1.0 message. (N)RPN controllers and bank changes need several
+    // MIDI 1.0 messages, see to_parameter. the bank of a program change is dropped
+    pub fn to_midi1(&self) -> Option<MidiMessage> {
+        let message = match *self {
+            Midi2Message::NoteOff { channel, note, velocity, .. } => {
+                MidiMessage::NoteOff { channel: channel, note: note, velocity: scale_down(velocity as u32, 16, 7) as u8 }
+            }
+            Midi2Message::NoteOn { channel, note, velocity, .. } => {
+                // a MIDI 2.0 note on can have a velocity of 0, in MIDI 1.0 that would be a note off
+                let velocity = (scale_down(velocity as u32, 16, 7) as u8).max(1);
+                MidiMessage::NoteOn { channel: channel, note: note, velocity: velocity }
+            }
+            Midi2Message::PolyPressure { channel, note, value } => {
+                MidiMessage::PolyAftertouch { channel: channel, note: note, pressure: scale_down(value, 32, 7) as u8 }
+            }
+            Midi2Message::ControlChange { channel, control, value } => {
+                let value = scale_down(value, 32, 7) as u8;
+                return MidiMessage::from_bytes(&[0xB0 | filter_channel(channel), control, value]);
+            }
+            Midi2Message::ProgramChange { channel, program, .. } => {
+                MidiMessage::ProgramChange { channel: channel, program: program }
+            }
+            Midi2Message::ChannelPressure { channel, value } => {
+                MidiMessage::ChannelAftertouch { channel: channel, pressure: scale_down(value, 32, 7) as u8 }
+            }
+            Midi2Message::PitchBend { channel, value } => {
+                MidiMessage::PitchBend { channel: channel, value: scale_down(value, 32, 14) as u16 }
+            }
+            Midi2Message::RegisteredController { .. } | Midi2Message::AssignableController { .. } => return None,
+        };
+        Some(message)
+    }
+
+    // an RPN or NRPN with a 14-bit MIDI 1.0 value, the parameter MSB and LSB become the bank and index
+    pub fn from_parameter(message: &ParameterMessage) -> Option<Self> {
+        match *message {
+            ParameterMessage::Parameter { channel, kind, parameter, value } => {
+                let bank = ((parameter >> 7) & 0x7F) as u8;
+                let index = (parameter & 0x7F) as u8;
+                let value = scale_up(value.min(16383) as u32, 14, 32);
+                Some(match kind {
+                    ParameterKind::Rpn => Midi2Message::RegisteredController { channel: channel, bank: bank, index: index, value: value },
+                    ParameterKind::Nrpn => Midi2Message::AssignableController { channel: channel, bank: bank, index: index, value: value },
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn to_parameter(&self) -> Option<ParameterMessage> {
+        let (channel, kind, bank, index, value) = match *self {
+            Midi2Message::RegisteredController { channel, bank, index, value } => (channel, ParameterKind::Rpn, bank, index, value),
+            Midi2Message::AssignableController { channel, bank, index, value } => (channel, ParameterKind::Nrpn, bank, index, value),
+            _ => return None,
+        };
+        Some(ParameterMessage::Parameter {
+            channel: channel,
+            kind: kind,
+            parameter: ((bank as u16) << 7) | (index as u16),
+            value: scale_down(value, 32, 14) as u16,
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SysexStatus {
+    Complete,
+    Start,
+    Continue,
+    End,
+}
+
+// splits SysEx data (without 0xF0 and 0xF7) into 64-bit data packets of up to 6 bytes
+pub struct Sysex7Packets<'a> {
+    group: u8,
+    data: &'a [u8],
+    position: usize,
+    done: bool,
+}
+
+impl<'a> Sysex7Packets<'a> {
+    pub fn new(group: u8, data: &'a [u8]) -> Self {
+        Sysex7Packets {
+            group: group,
+            data: data,
+            position: 0,
+            done: false,
+        }
+    }
+}
+
+impl<'a> Iterator for Sysex7Packets<'a> {
+    type Item = Packet;
+
+    fn next(&mut self) -> Option<Packet> {
+        if self.done == true { return None }
+        let first = self.position == 0;
+        let end = (self.position + 6).min(self.data.len());
+        let chunk = &self.data[self.position..end];
+        self.position = end;
+        let last = self.position >= self.data.len();
+        if last == true { self.done = true }
+
+        let status = match (first, last) {
+            (true, true) => 0x0,
+            (true, false) => 0x1,
+            (false, false) => 0x2,
+            (false, true) => 0x3,
+        };
+        let mut bytes = [0u8; 6];
+        for (i, b) in chunk.iter().enumerate() { bytes[i] = *b & 0x7F }
+        let word0 = word(MT_DATA64, self.group, (status << 4) | chunk.len() as u8, bytes[0], bytes[1]);
+        let word1 = ((bytes[2] as u32) << 24) | ((bytes[3] as u32) << 16) | ((bytes[4] as u32) << 8) | (bytes[5] as u32);
+        Some(Packet::two(word0, word1))
+    }
+}
+
+// copies the data bytes of a SysEx packet into buf, returns the status and the byte count
+pub fn sysex7_data(packet: &Packet, buf: &mut [u8; 6]) -> Option<(SysexStatus, usize)> {
+    if packet.message_type() != MT_DATA64 { return None }
+    let status = match packet.status() >> 4 {
+        0x0 => SysexStatus::Complete,
+        0x1 => SysexStatus::Start,
+        0x2 => SysexStatus::Continue,
+        0x3 => SysexStatus::End,
+        _ => return None,
+    };
+    let len = ((packet.status() & 0x0F) as usize).min(6);
+    let word1 = packet.words[1];
+    *buf = [packet.byte2(), packet.byte3(), (word1 >> 24) as u8, (word1 >> 16) as u8, (word1 >> 8) as u8, word1 as u8];
+    Some((status, len))
+}
+
+// jitter reduction timestamps and NOOPs are the only utility messages a receiver has to skip
+pub fn is_utility(packet: &Packet) -> bool {
+    packet.message_type() == MT_UTILITY
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scale_up_keeps_min_center_max() {
+        assert_eq!(scale_up(0, 7, 16), 0);
+        assert_eq!(scale_up(64, 7, 16), 0x8000);
+        assert_eq!(scale_up(127, 7, 16), 0xFFFF);
+        assert_eq!(scale_up(0, 7, 32), 0);
+        assert_eq!(scale_up(64, 7, 32), 0x8000_0000);
+        assert_eq!(scale_up(127, 7, 32), 0xFFFF_FFFF);
+        assert_eq!(scale_up(8192, 14, 32), 0x8000_0000);
+        assert_eq!(scale_up(16383, 14, 32), 0xFFFF_FFFF);
+    }
+
+    #[test]
+    fn scale_down_undoes_scale_up() {
+        for value in 0..128 {
+            assert_eq!(scale_down(scale_up(value, 7, 16), 16, 7), value);
+            assert_eq!(scale_down(scale_up(value, 7, 32), 32, 7), value);
+        }
+        for value in 0..16384 {
+            assert_eq!(scale_down(scale_up(value, 14, 32), 32, 14), value);
+        }
+    }
+
+    #[test]
+    fn scale_up_is_increasing() {
+        for value in 1..128 {
+            assert!(scale_up(value, 7, 32) > scale_up(value - 1, 7, 32));
+        }
+    }
+
+    #[test]
+    fn channel_voice_round_trip() {
+        let messages = [
+            MidiMessage::NoteOff { channel: 1, note: 60, velocity: 64 },
+            MidiMessage::NoteOn { channel: 2, note: 61, velocity: 1 },
+            MidiMessage::NoteOn { channel: 16, note: 127, velocity: 127 },
+            MidiMessage::PolyAftertouch { channel: 3, note: 10, pressure: 100 },
+            MidiMessage::ControlChange { channel: 4, control: 7, value: 0 },
+            MidiMessage::ControlChange { channel: 4, control: 74, value: 127 },
+            MidiMessage::ProgramChange { channel: 5, program: 42 },
+            MidiMessage::ChannelAftertouch { channel: 6, pressure: 64 },
+            MidiMessage::PitchBend { channel: 7, value: 0 },
+            MidiMessage::PitchBend { channel: 7, value: 8192 },
+            MidiMessage::PitchBend { channel: 7, value: 16383 },
+        ];
+        for message in messages.iter() {
+            let midi2 = Midi2Message::from_midi1(message).unwrap();
+            assert_eq!(midi2.to_midi1(), Some(*message));
+            assert_eq!(Midi2Message::from_packet(&midi2.to_packet(0)), Some(midi2));
+        }
+    }
+
+    #[test]
+    fn note_on_velocity_0_is_a_note_off() {
+        let message = MidiMessage::NoteOn { channel: 10, note: 36, velocity: 0 };
+        let midi2 = Midi2Message::from_midi1(&message).unwrap();
+        assert_eq!(midi2, Midi2Message::NoteOff { channel: 10, note: 36, velocity: 0, attribute_type: 0, attribute: 0 });
+        assert_eq!(midi2.to_midi1(), Some(MidiMessage::NoteOff { channel: 10, note: 36, velocity: 0 }));
+    }
+
+    #[test]
+    fn midi2_note_on_velocity_0_stays_a_note_on() {
+        let midi2 = Midi2Message::NoteOn { channel: 1, note: 60, velocity: 0, attribute_type: 0, attribute: 0 };
+        assert_eq!(midi2.to_midi1(), Some(MidiMessage::NoteOn { channel: 1, note: 60, velocity: 1 }));
+    }
+
+    #[test]
+    fn midi1_packets_round_trip() {
+        let messages = [
+            MidiMessage::NoteOn { channel: 1, note: 60, velocity: 100 },
+            MidiMessage::TimingClock,
+            MidiMessage::SongPosition { position: 1000 },
+        ];
+        for message in messages.iter() {
+            let packet = midi1_to_packet(3, message).unwrap();
+            assert_eq!(packet.group(), 3);
+            assert_eq!(packet_to_midi1(&packet), Some(*message));
+        }
+    }
+
+    #[test]
+    fn parameter_round_trip() {
+        let message = ParameterMessage::Parameter { channel: 2, kind: ParameterKind::Nrpn, parameter: 1000, value: 8192 };
+        let midi2 = Midi2Message::from_parameter(&message).unwrap();
+        assert_eq!(midi2, Midi2Message::AssignableController { channel: 2, bank: 7, index: 104, value: 0x8000_0000 });
+        assert_eq!(midi2.to_parameter(), Some(message));
+    }
+}
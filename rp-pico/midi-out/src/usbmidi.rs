@@ -0,0 +1,201 @@
+use usb_device::class_prelude::*;
+use usb_device::Result;
+
+use crate::midi::{MidiMessage, ParameterMessage};
+
+const USB_CLASS_AUDIO: u8 = 0x01;
+const USB_SUBCLASS_AUDIOCONTROL: u8 = 0x01;
+const USB_SUBCLASS_MIDISTREAMING: u8 = 0x03;
+
+const CS_INTERFACE: u8 = 0x24;
+const CS_ENDPOINT: u8 = 0x25;
+const HEADER: u8 = 0x01;
+const MS_GENERAL: u8 = 0x01;
+const MIDI_IN_JACK: u8 = 0x02;
+const MIDI_OUT_JACK: u8 = 0x03;
+const EMBEDDED: u8 = 0x01;
+const EXTERNAL: u8 = 0x02;
+
+// the host sends into embedded IN jack 1, which is routed to external OUT jack 4 (the DIN
+// output). external IN jack 2 (the DIN input) is routed to embedded OUT jack 3, which the
+// host reads
+const JACK_IN_EMBEDDED: u8 = 1;
+const JACK_IN_EXTERNAL: u8 = 2;
+const JACK_OUT_EMBEDDED: u8 = 3;
+const JACK_OUT_EXTERNAL: u8 = 4;
+
+// class-specific MIDIStreaming descriptors: header, 4 jacks and both endpoints
+const MS_TOTAL_LENGTH: u16 = 7 + 6 + 6 + 9 + 9 + 7 + 5 + 7 + 5;
+
+const PACKET_SIZE: usize = 64;
+// events waiting for the host, four transfers
+pub const TX_SIZE: usize = 4 * PACKET_SIZE;
+
+// USB MIDI 1.0 class with one cable in each direction. messages are sent as 4 byte event
+// packets and collected until flush is called, so a burst of messages shares one transfer.
+// several transfers are buffered so a burst isn't lost while the host is busy
+pub struct MidiClass<'a, B: UsbBus> {
+    audio_if: InterfaceNumber,
+    midi_if: InterfaceNumber,
+    read_ep: EndpointOut<'a, B>,
+    write_ep: EndpointIn<'a, B>,
+    tx: [u8; TX_SIZE],
+    tx_len: usize,
+}
+
+impl<'a, B: UsbBus> MidiClass<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        MidiClass {
+            audio_if: alloc.interface(),
+            midi_if: alloc.interface(),
+            read_ep: alloc.bulk(PACKET_SIZE as u16),
+            write_ep: alloc.bulk(PACKET_SIZE as u16),
+            tx: [0; TX_SIZE],
+            tx_len: 0,
+        }
+    }
+
+    // adds the message to the buffered events, fails with WouldBlock and drops the
+    // message while the buffer is full
+    pub fn send(&mut self, message: &MidiMessage) -> Result<()> {
+        let mut buf = [0u8; 3];
+        let len = message.to_bytes(&mut buf);
+        if len == 0 { return Ok(()) }
+        let cin = match buf[0] {
+            0xF1 | 0xF3 => 0x2,
+            0xF2 => 0x3,
+            0xF6 => 0x5,
+            0xF8..=0xFF => 0xF,
+            status => status >> 4,
+        };
+        self.reserve(1)?;
+        self.push_event([cin, buf[0], buf[1], buf[2]]);
+        Ok(())
+    }
+
+    // each controller message of a 14-bit controller or (N)RPN, all of them or none
+    pub fn send_parameter(&mut self, message: &ParameterMessage, terminate: bool) -> Result<()> {
+        let mut messages = [MidiMessage::TuneRequest; 7];
+        let count = message.to_messages(terminate, &mut messages);
+        self.reserve(count)?;
+        for m in messages[..count].iter() {
+            self.send(m)?;
+        }
+        Ok(())
+    }
+
+    // data must not include the 0xF0 and 0xF7 bytes. the whole message is buffered or
+    // none of it, so it can't be longer than TX_SIZE / 4 * 3 - 2 bytes
+    pub fn send_sysex(&mut self, data: &[u8]) -> Result<()> {
+        // three bytes per event, with F0 and F7
+        self.reserve((data.len() + 2 + 2) / 3)?;
+        let mut bytes = [0u8; 3];
+        let mut count = 1;
+        bytes[0] = 0xF0;
+        for byte in data.iter().chain([0xF7].iter()) {
+            bytes[count] = *byte;
+            count += 1;
+            if count == 3 && *byte != 0xF7 {
+                self.push_event([0x4, bytes[0], bytes[1], bytes[2]]);
+                count = 0;
+            }
+        }
+        // the end packet tells how many of its bytes are used
+        let cin = match count {
+            1 => 0x5,
+            2 => 0x6,
+            _ => 0x7,
+        };
+        for b in bytes[count..].iter_mut() { *b = 0 }
+        self.push_event([cin, bytes[0], bytes[1], bytes[2]]);
+        Ok(())
+    }
+
+    // makes sure the next events fit, a full buffer tries to start a transfer first
+    fn reserve(&mut self, events: usize) -> Result<()> {
+        if self.tx_len + events * 4 > TX_SIZE {
+            self.flush()?;
+        }
+        if self.tx_len + events * 4 > TX_SIZE { return Err(UsbError::WouldBlock) }
+        Ok(())
+    }
+
+    fn push_event(&mut self, event: [u8; 4]) {
+        self.tx[self.tx_len..self.tx_len + 4].copy_from_slice(&event);
+        self.tx_len += 4;
+    }
+
+    // starts the transfer of the next buffered packet, call every loop after sending. the
+    // events stay buffered while the host hasn't taken the last transfer yet, any other
+    // error means they can't go out
+    pub fn flush(&mut self) -> Result<()> {
+        if self.tx_len == 0 { return Ok(()) }
+        let len = self.tx_len.min(PACKET_SIZE);
+        match self.write_ep.write(&self.tx[..len]) {
+            Ok(_) => (),
+            Err(UsbError::WouldBlock) => return Ok(()),
+            Err(error) => return Err(error),
+        }
+        self.tx.copy_within(len..self.tx_len, 0);
+        self.tx_len -= len;
+        Ok(())
+    }
+
+    // drops the buffered events, returns how many there were
+    pub fn clear(&mut self) -> usize {
+        let events = self.tx_len / 4;
+        self.tx_len = 0;
+        events
+    }
+
+    // reads one transfer of event packets from the host, use event_bytes on each
+    // 4 byte chunk to get the MIDI bytes back
+    pub fn read(&mut self, buf: &mut [u8; PACKET_SIZE]) -> Result<usize> {
+        self.read_ep.read(buf)
+    }
+}
+
+// the MIDI bytes carried by an event packet, the code index number gives the length
+pub fn event_bytes(event: &[u8]) -> &[u8] {
+    if event.len() < 4 { return &[] }
+    let len = match event[0] & 0x0F {
+        0x5 | 0xF => 1,
+        0x2 | 0x6 | 0xC | 0xD => 2,
+        0x3 | 0x4 | 0x7 | 0x8 | 0x9 | 0xA | 0xB | 0xE => 3,
+        // 0x0 and 0x1 are reserved
+        _ => 0,
+    };
+    &event[1..1 + len]
+}
+
+impl<B: UsbBus> UsbClass<B> for MidiClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        // groups both interfaces into one function when the device is a composite
+        // with the USB serial port
+        writer.iad(self.audio_if, 2, USB_CLASS_AUDIO, USB_SUBCLASS_AUDIOCONTROL, 0)?;
+        writer.interface(self.audio_if, USB_CLASS_AUDIO, USB_SUBCLASS_AUDIOCONTROL, 0)?;
+        // audio control header, bcdADC 1.0, total length 9, one streaming interface
+        writer.write(CS_INTERFACE, &[HEADER, 0x00, 0x01, 0x09, 0x00, 0x01, self.midi_if.into()])?;
+
+        writer.interface(self.midi_if, USB_CLASS_AUDIO, USB_SUBCLASS_MIDISTREAMING, 0)?;
+        writer.write(CS_INTERFACE, &[
+            HEADER,
+            0x00, 0x01,  // bcdMSC 1.0
+            MS_TOTAL_LENGTH as u8, (MS_TOTAL_LENGTH >> 8) as u8,
+        ])?;
+        writer.write(CS_INTERFACE, &[MIDI_IN_JACK, EMBEDDED, JACK_IN_EMBEDDED, 0])?;
+        writer.write(CS_INTERFACE, &[MIDI_IN_JACK, EXTERNAL, JACK_IN_EXTERNAL, 0])?;
+        writer.write(CS_INTERFACE, &[MIDI_OUT_JACK, EMBEDDED, JACK_OUT_EMBEDDED, 1, JACK_IN_EXTERNAL, 1, 0])?;
+        writer.write(CS_INTERFACE, &[MIDI_OUT_JACK, EXTERNAL, JACK_OUT_EXTERNAL, 1, JACK_IN_EMBEDDED, 1, 0])?;
+
+        writer.endpoint(&self.read_ep)?;
+        writer.write(CS_ENDPOINT, &[MS_GENERAL, 1, JACK_IN_EMBEDDED])?;
+        writer.endpoint(&self.write_ep)?;
+        writer.write(CS_ENDPOINT, &[MS_GENERAL, 1, JACK_OUT_EMBEDDED])?;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.tx_len = 0;
+    }
+}
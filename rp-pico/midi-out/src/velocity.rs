@@ -0,0 +1,444 @@
+use embedded_hal::digital::v2::InputPin;
+use rp2040_hal::gpio::dynpin::DynPin;
+
+use crate::analog::{AnalogMapping, AnalogSource, ADC_MAX};
+use crate::controls::{gpio_available, InputMapping};
+use crate::midi::MidiMessage;
+
+// hit strength is passed around as 0-1024 before the curve turns it into a velocity
+pub const AMOUNT_MAX: u32 = 1024;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum VelocityCurve {
+    Linear,
+    // soft hits come out louder (square root)
+    Log,
+    // soft hits come out quieter (square)
+    Exp,
+    // ignores the hit strength
+    Fixed(u8),
+}
+
+impl VelocityCurve {
+    // turns a hit strength of 0-1024 into a note on velocity of 1-127
+    pub fn velocity(&self, amount: u32) -> u8 {
+        let amount = amount.min(AMOUNT_MAX);
+        let shaped = match *self {
+            VelocityCurve::Linear => amount,
+            VelocityCurve::Log => isqrt(amount * AMOUNT_MAX),
+            VelocityCurve::Exp => amount * amount / AMOUNT_MAX,
+            VelocityCurve::Fixed(velocity) => return velocity.max(1).min(127),
+        };
+        (1 + shaped * 126 / AMOUNT_MAX) as u8
+    }
+}
+
+fn isqrt(value: u32) -> u32 {
+    let mut result = 0;
+    let mut bit = 1 << 30;
+    let mut value = value;
+    while bit > value { bit >>= 2 }
+    while bit != 0 {
+        if value >= result + bit {
+            value -= result + bit;
+            result = (result >> 1) + bit;
+        } else {
+            result >>= 1;
+        }
+        bit >>= 2;
+    }
+    result
+}
+
+// time between the two contacts of a key, fast_us or less is the hardest hit and
+// slow_us or more the softest
+pub fn time_to_amount(time_us: u32, fast_us: u32, slow_us: u32) -> u32 {
+    if time_us <= fast_us { return AMOUNT_MAX }
+    if time_us >= slow_us { return 0 }
+    (slow_us - time_us) * AMOUNT_MAX / (slow_us - fast_us)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum NoteEvent {
+    On(u8),
+    Off,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum KeyState {
+    Idle,
+    Travelling(u32),  // first contact closed at this time
+    Down,
+    Releasing(u32),   // both contacts open since this time
+}
+
+// a key with two contacts that close one after the other as it goes down, like most
+// keyboard mechanisms. contacts are read raw, the first change of each contact counts
+// and the bounce after it is ignored by the state machine
+pub struct DualSwitchKey {
+    state: KeyState,
+    fast_us: u32,
+    slow_us: u32,
+    release_us: u32,
+    curve: VelocityCurve,
+}
+
+impl DualSwitchKey {
+    pub fn new(fast_us: u32, slow_us: u32, release_us: u32, curve: VelocityCurve) -> Self {
+        DualSwitchKey {
+            state: KeyState::Idle,
+            fast_us: fast_us,
+            slow_us: slow_us,
+            release_us: release_us,
+            curve: curve,
+        }
+    }
+
+    pub fn update(&mut self, first: bool, second: bool, micros: u32) -> Option<NoteEvent> {
+        match self.state {
+            KeyState::Idle => {
+                if first == true {
+                    self.state = KeyState::Travelling(micros);
+                }
+                None
+            }
+            KeyState::Travelling(start) => {
+                if second == true {
+                    self.state = KeyState::Down;
+                    let amount = time_to_amount(micros.wrapping_sub(start), self.fast_us, self.slow_us);
+                    return Some(NoteEvent::On(self.curve.velocity(amount)));
+                }
+                // pressed part way and let go, or the first contact bounced
+                if first == false && micros.wrapping_sub(start) > self.slow_us {
+                    self.state = KeyState::Idle;
+                }
+                None
+            }
+            KeyState::Down => {
+                if first == false && second == false {
+                    self.state = KeyState::Releasing(micros);
+                    return Some(NoteEvent::Off);
+                }
+                None
+            }
+            KeyState::Releasing(start) => {
+                // both contacts must stay open a moment before the key can play again
+                if first == true || second == true {
+                    self.state = KeyState::Releasing(micros);
+                } else if micros.wrapping_sub(start) > self.release_us {
+                    self.state = KeyState::Idle;
+                }
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum PadState {
+    Idle,
+    Peaking { start: u32, peak: u16 },
+    Masked { start: u32 },
+}
+
+// finds the peak of a piezo hit on the ADC. the note on is sent scan_us after the signal
+// first crosses the threshold, then the pad is masked for mask_us so the ringing of the
+// piezo doesn't retrigger it, and the note off is sent when the mask ends
+pub struct PiezoDetector {
+    state: PadState,
+    threshold: u16,
+    scan_us: u32,
+    mask_us: u32,
+    curve: VelocityCurve,
+}
+
+impl PiezoDetector {
+    pub fn new(threshold: u16, scan_us: u32, mask_us: u32, curve: VelocityCurve) -> Self {
+        PiezoDetector {
+            state: PadState::Idle,
+            threshold: threshold.min(ADC_MAX - 1),
+            scan_us: scan_us,
+            mask_us: mask_us,
+            curve: curve,
+        }
+    }
+
+    pub fn update(&mut self, raw: u16, micros: u32) -> Option<NoteEvent> {
+        match self.state {
+            PadState::Idle => {
+                if raw > self.threshold {
+                    self.state = PadState::Peaking { start: micros, peak: raw };
+                }
+                None
+            }
+            PadState::Peaking { start, peak } => {
+                let peak = peak.max(raw);
+                if micros.wrapping_sub(start) < self.scan_us {
+                    self.state = PadState::Peaking { start: start, peak: peak };
+                    return None;
+                }
+                self.state = PadState::Masked { start: micros };
+                Some(NoteEvent::On(self.curve.velocity(self.amount(peak))))
+            }
+            PadState::Masked { start } => {
+                if micros.wrapping_sub(start) < self.mask_us { return None }
+                self.state = PadState::Idle;
+                Some(NoteEvent::Off)
+            }
+        }
+    }
+
+    pub fn amount(&self, peak: u16) -> u32 {
+        let range = (ADC_MAX - self.threshold) as u32;
+        (peak.saturating_sub(self.threshold) as u32) * AMOUNT_MAX / range
+    }
+}
+
+// a dual contact key, both gpios are wired to ground like the other inputs
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct KeyMapping {
+    pub first_gpio: u8,
+    pub second_gpio: u8,
+    pub channel: u8,
+    pub note: u8,
+    pub curve: VelocityCurve,
+}
+
+// a piezo pad on ADC0 or ADC1, which can't be used by an analog input at the same time
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PadMapping {
+    pub adc: u8,
+    pub channel: u8,
+    pub note: u8,
+    pub curve: VelocityCurve,
+}
+
+// checks the key and pad tables at compile time against the other inputs, like controls::validate
+pub const fn validate(keys: &[KeyMapping], pads: &[PadMapping], inputs: &[InputMapping], analog: &[AnalogMapping]) -> bool {
+    let mut i = 0;
+    while i < keys.len() {
+        let key = &keys[i];
+        assert!(gpio_available(key.first_gpio) && gpio_available(key.second_gpio), "key mapped to a gpio that isn't available");
+        assert!(key.first_gpio != key.second_gpio, "key contacts must be on different gpios");
+        assert!(key.channel >= 1 && key.channel <= 16, "key channel must be 1-16");
+        assert!(key.note <= 127, "key note must be 0-127");
+        let mut j = 0;
+        while j < keys.len() {
+            if j != i {
+                let other = &keys[j];
+                assert!(
+                    key.first_gpio != other.first_gpio && key.first_gpio != other.second_gpio
+                        && key.second_gpio != other.first_gpio && key.second_gpio != other.second_gpio,
+                    "gpio mapped to more than one key"
+                );
+            }
+            j += 1;
+        }
+        let mut j = 0;
+        while j < inputs.len() {
+            assert!(
+                inputs[j].gpio != key.first_gpio && inputs[j].gpio != key.second_gpio,
+                "gpio mapped to both a key and an input"
+            );
+            j += 1;
+        }
+        i += 1;
+    }
+    let mut i = 0;
+    while i < pads.len() {
+        let pad = &pads[i];
+        assert!(pad.adc <= 1, "pads can only use ADC0 and ADC1");
+        assert!(pad.channel >= 1 && pad.channel <= 16, "pad channel must be 1-16");
+        assert!(pad.note <= 127, "pad note must be 0-127");
+        let mut j = 0;
+        while j < pads.len() {
+            assert!(j == i || pads[j].adc != pad.adc, "ADC mapped to more than one pad");
+            j += 1;
+        }
+        let mut j = 0;
+        while j < analog.len() {
+            if let AnalogSource::Adc(n) = analog[j].source {
+                assert!(n != pad.adc, "ADC mapped to both a pad and an analog input");
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+fn note_message(channel: u8, note: u8, event: NoteEvent) -> MidiMessage {
+    match event {
+        NoteEvent::On(velocity) => MidiMessage::NoteOn { channel: channel, note: note, velocity: velocity },
+        NoteEvent::Off => MidiMessage::NoteOn { channel: channel, note: note, velocity: 0 },
+    }
+}
+
+pub struct VelocityKey {
+    pub mapping: KeyMapping,
+    first: DynPin,
+    second: DynPin,
+    key: DualSwitchKey,
+}
+
+impl VelocityKey {
+    pub fn new(mapping: KeyMapping, first: DynPin, second: DynPin, key: DualSwitchKey) -> Self {
+        VelocityKey {
+            mapping: mapping,
+            first: first,
+            second: second,
+            key: key,
+        }
+    }
+
+    pub fn poll(&mut self, micros: u32) -> Option<MidiMessage> {
+        let first = self.first.is_low().unwrap_or(false);
+        let second = self.second.is_low().unwrap_or(false);
+        let event = self.key.update(first, second, micros)?;
+        Some(note_message(self.mapping.channel, self.mapping.note, event))
+    }
+}
+
+pub struct Pad {
+    pub mapping: PadMapping,
+    detector: PiezoDetector,
+}
+
+impl Pad {
+    pub fn new(mapping: PadMapping, detector: PiezoDetector) -> Self {
+        Pad {
+            mapping: mapping,
+            detector: detector,
+        }
+    }
+
+    pub fn update(&mut self, raw: u16, micros: u32) -> Option<MidiMessage> {
+        let event = self.detector.update(raw, micros)?;
+        Some(note_message(self.mapping.channel, self.mapping.note, event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_to_amount_ends_and_middle() {
+        assert_eq!(time_to_amount(0, 1000, 30000), AMOUNT_MAX);
+        assert_eq!(time_to_amount(1000, 1000, 30000), AMOUNT_MAX);
+        assert_eq!(time_to_amount(30000, 1000, 30000), 0);
+        assert_eq!(time_to_amount(100000, 1000, 30000), 0);
+        assert_eq!(time_to_amount(15500, 1000, 30000), AMOUNT_MAX / 2);
+    }
+
+    #[test]
+    fn time_to_amount_falls_as_the_key_gets_slower() {
+        let mut last = AMOUNT_MAX;
+        for time in (1000..=30000).step_by(500) {
+            let amount = time_to_amount(time, 1000, 30000);
+            assert!(amount <= last);
+            last = amount;
+        }
+    }
+
+    #[test]
+    fn curves_cover_1_to_127() {
+        for curve in [VelocityCurve::Linear, VelocityCurve::Log, VelocityCurve::Exp] {
+            assert_eq!(curve.velocity(0), 1);
+            assert_eq!(curve.velocity(AMOUNT_MAX), 127);
+            assert_eq!(curve.velocity(AMOUNT_MAX * 2), 127);
+            let mut last = 1;
+            for amount in 0..=AMOUNT_MAX {
+                let velocity = curve.velocity(amount);
+                assert!(velocity >= last);
+                last = velocity;
+            }
+        }
+    }
+
+    #[test]
+    fn curve_shapes() {
+        let half = AMOUNT_MAX / 2;
+        assert_eq!(VelocityCurve::Linear.velocity(half), 64);
+        // square root of 0.5 and 0.5 squared
+        assert_eq!(VelocityCurve::Log.velocity(half), 90);
+        assert_eq!(VelocityCurve::Exp.velocity(half), 32);
+    }
+
+    #[test]
+    fn fixed_curve_ignores_the_amount() {
+        assert_eq!(VelocityCurve::Fixed(100).velocity(0), 100);
+        assert_eq!(VelocityCurve::Fixed(100).velocity(AMOUNT_MAX), 100);
+        assert_eq!(VelocityCurve::Fixed(0).velocity(0), 1);
+        assert_eq!(VelocityCurve::Fixed(200).velocity(0), 127);
+    }
+
+    #[test]
+    fn key_press_and_release() {
+        let mut key = DualSwitchKey::new(1000, 30000, 5000, VelocityCurve::Linear);
+        assert_eq!(key.update(false, false, 0), None);
+        assert_eq!(key.update(true, false, 100), None);
+        assert_eq!(key.update(true, false, 600), None);
+        // the fastest time or less is the hardest hit
+        assert_eq!(key.update(true, true, 1100), Some(NoteEvent::On(127)));
+        assert_eq!(key.update(true, true, 50000), None);
+        assert_eq!(key.update(true, false, 50100), None);
+        assert_eq!(key.update(false, false, 50200), Some(NoteEvent::Off));
+    }
+
+    #[test]
+    fn slow_key_is_soft() {
+        let mut key = DualSwitchKey::new(1000, 30000, 5000, VelocityCurve::Linear);
+        key.update(true, false, 0);
+        assert_eq!(key.update(true, true, 40000), Some(NoteEvent::On(1)));
+    }
+
+    #[test]
+    fn key_let_go_half_way_plays_nothing() {
+        let mut key = DualSwitchKey::new(1000, 30000, 5000, VelocityCurve::Linear);
+        key.update(true, false, 0);
+        assert_eq!(key.update(false, false, 31000), None);
+        // back to idle, the next press times from its own first contact
+        key.update(true, false, 40000);
+        assert_eq!(key.update(true, true, 41000), Some(NoteEvent::On(127)));
+    }
+
+    #[test]
+    fn key_bounce_after_release_is_ignored() {
+        let mut key = DualSwitchKey::new(1000, 30000, 5000, VelocityCurve::Linear);
+        key.update(true, false, 0);
+        key.update(true, true, 1000);
+        assert_eq!(key.update(false, false, 10000), Some(NoteEvent::Off));
+        // contact bounce restarts the release time
+        assert_eq!(key.update(true, false, 12000), None);
+        assert_eq!(key.update(true, true, 12100), None);
+        assert_eq!(key.update(false, false, 12200), None);
+        assert_eq!(key.update(false, false, 16000), None);
+        assert_eq!(key.update(false, false, 17300), None);
+        // idle again
+        assert_eq!(key.update(true, false, 18000), None);
+        assert_eq!(key.update(true, true, 18500), Some(NoteEvent::On(127)));
+    }
+
+    #[test]
+    fn piezo_peak_and_mask() {
+        let mut pad = PiezoDetector::new(200, 2000, 30000, VelocityCurve::Linear);
+        assert_eq!(pad.update(100, 0), None);
+        assert_eq!(pad.update(1000, 100), None);
+        assert_eq!(pad.update(ADC_MAX, 600), None);
+        assert_eq!(pad.update(500, 1500), None);
+        assert_eq!(pad.update(300, 2100), Some(NoteEvent::On(127)));
+        // ringing during the mask doesn't retrigger
+        assert_eq!(pad.update(3000, 10000), None);
+        assert_eq!(pad.update(0, 32100), Some(NoteEvent::Off));
+        assert_eq!(pad.update(0, 33000), None);
+    }
+
+    #[test]
+    fn piezo_amount_from_threshold_to_max() {
+        let pad = PiezoDetector::new(200, 2000, 30000, VelocityCurve::Linear);
+        assert_eq!(pad.amount(200), 0);
+        assert_eq!(pad.amount(ADC_MAX), AMOUNT_MAX);
+        assert_eq!(pad.amount(100), 0);
+    }
+}
@@ -0,0 +1,288 @@
+// host side of the usb-leds serial protocol. the frame format and command numbers come
+// straight from the firmware's protocol.rs so both ends always agree
+
+// written in the firmware's style, which has no Default impls
+#[path = "../../usb-leds/src/protocol.rs"]
+#[allow(clippy::new_without_default)]
+pub mod protocol;
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::protocol::{FrameDecoder, FrameError};
+
+// the USB ids the firmware enumerates with
+pub const VID: u16 = 0x16c0;
+pub const PID: u16 = 0x27dd;
+
+const TIMEOUT: Duration = Duration::from_millis(1000);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Hsv {
+    pub h: f32,
+    pub s: f32,
+    pub v: f32,
+}
+
+impl Hsv {
+    pub fn new(h: f32, s: f32, v: f32) -> Self {
+        Hsv { h, s, v }
+    }
+
+    fn write_bytes(&self, out: &mut [u8]) {
+        out[0..4].copy_from_slice(&self.h.to_le_bytes());
+        out[4..8].copy_from_slice(&self.s.to_le_bytes());
+        out[8..12].copy_from_slice(&self.v.to_le_bytes());
+    }
+
+    fn from_bytes(data: &[u8]) -> Self {
+        let f = |i: usize| f32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        Hsv::new(f(0), f(4), f(8))
+    }
+}
+
+impl std::str::FromStr for Hsv {
+    type Err = String;
+
+    // "h,s,v", each 0.0-1.0
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut values = [0.0f32; 3];
+        let mut count = 0;
+        for part in s.split(',') {
+            if count == 3 { return Err(format!("too many values in color {}", s)) }
+            values[count] = part.trim().parse().map_err(|_| format!("bad value {} in color {}", part, s))?;
+            count += 1;
+        }
+        if count != 3 { return Err(format!("color {} needs h,s,v", s)) }
+        Ok(Hsv::new(values[0], values[1], values[2]))
+    }
+}
+
+// what a board is showing, as read back with Board::get
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct State {
+    pub pattern: u8,
+    pub color_1: Hsv,
+    pub color_2: Hsv,
+    pub on: bool,
+    pub brightness: u8,
+    // showing streamed frames instead of the pattern
+    pub streaming: bool,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Serial(serialport::Error),
+    // the board refused a command, the error is None when it sent a code this side doesn't know
+    Nak { command: u8, error: Option<FrameError> },
+    Timeout,
+    // a reply that doesn't answer the command that was sent
+    Unexpected { command: u8 },
+    // no board matched, with the reason
+    NotFound(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Serial(e) => write!(f, "{}", e),
+            Error::Nak { command, error } => write!(f, "board refused command {}: {:?}", command, error),
+            Error::Timeout => write!(f, "no reply from the board"),
+            Error::Unexpected { command } => write!(f, "unexpected reply {:#04x} from the board", command),
+            Error::NotFound(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serialport::Error> for Error {
+    fn from(e: serialport::Error) -> Self {
+        Error::Serial(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// a board connected over anything that reads and writes bytes, usually a serial port
+pub struct Board<P: Read + Write> {
+    port: P,
+    decoder: FrameDecoder<{ protocol::MAX_FRAME }>,
+    timeout: Duration,
+}
+
+impl<P: Read + Write> Board<P> {
+    pub fn new(port: P) -> Self {
+        Board {
+            port,
+            decoder: FrameDecoder::new(),
+            timeout: TIMEOUT,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn set(&mut self, pattern: u8, color_1: &Hsv, color_2: &Hsv) -> Result<()> {
+        let mut payload = [0u8; protocol::SET_PAYLOAD];
+        payload[0] = pattern;
+        color_1.write_bytes(&mut payload[1..13]);
+        color_2.write_bytes(&mut payload[13..25]);
+        self.command(protocol::CMD_SET, &payload)
+    }
+
+    pub fn get(&mut self) -> Result<State> {
+        let payload = self.request(protocol::CMD_GET, &[], protocol::RESP_STATE)?;
+        if payload.len() < protocol::STATE_PAYLOAD {
+            return Err(Error::Unexpected { command: protocol::RESP_STATE });
+        }
+        Ok(State {
+            pattern: payload[0],
+            color_1: Hsv::from_bytes(&payload[1..13]),
+            color_2: Hsv::from_bytes(&payload[13..25]),
+            on: payload[25] != 0,
+            brightness: payload[26],
+            streaming: payload[27] != 0,
+        })
+    }
+
+    pub fn off(&mut self) -> Result<()> {
+        self.command(protocol::CMD_OFF, &[])
+    }
+
+    pub fn on(&mut self) -> Result<()> {
+        self.command(protocol::CMD_ON, &[])
+    }
+
+    pub fn brightness(&mut self, brightness: u8) -> Result<()> {
+        self.command(protocol::CMD_BRIGHTNESS, &[brightness])
+    }
+
+    // shows a frame of rgb triplets, one per LED. it goes over in chunks and only appears
+    // once all of it has arrived. the length check uses % because older toolchains don't
+    // have is_multiple_of
+    #[allow(clippy::manual_is_multiple_of)]
+    pub fn show_frame(&mut self, rgb: &[u8]) -> Result<()> {
+        if rgb.len() % 3 != 0 || rgb.len() > protocol::MAX_PIXELS * 3 {
+            return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, "a frame is up to 1024 rgb triplets")));
+        }
+        let mut payload = [0u8; protocol::MAX_PAYLOAD];
+        for (index, chunk) in rgb.chunks(protocol::PIXELS_PER_FRAME * 3).enumerate() {
+            let first = (index * protocol::PIXELS_PER_FRAME) as u16;
+            payload[0..2].copy_from_slice(&first.to_le_bytes());
+            payload[2..2 + chunk.len()].copy_from_slice(chunk);
+            self.command(protocol::CMD_PIXELS, &payload[..2 + chunk.len()])?;
+        }
+        self.command(protocol::CMD_SHOW, &((rgb.len() / 3) as u16).to_le_bytes())
+    }
+
+    // how long the last streamed frame stays before the pattern comes back, 0 keeps it
+    pub fn stream_timeout(&mut self, ms: u16) -> Result<()> {
+        self.command(protocol::CMD_STREAM_TIMEOUT, &ms.to_le_bytes())
+    }
+
+    // sends a command that is answered with an ACK
+    pub fn command(&mut self, command: u8, payload: &[u8]) -> Result<()> {
+        let reply = self.request(command, payload, protocol::RESP_ACK)?;
+        if reply.first() != Some(&command) {
+            return Err(Error::Unexpected { command: protocol::RESP_ACK });
+        }
+        Ok(())
+    }
+
+    // sends a frame and waits for the reply, a NAK becomes Error::Nak
+    pub fn request(&mut self, command: u8, payload: &[u8], reply: u8) -> Result<Vec<u8>> {
+        let mut frame = [0u8; protocol::MAX_FRAME];
+        let len = protocol::encode(command, payload, &mut frame);
+        self.decoder.reset();
+        self.port.write_all(&frame[..len])?;
+        self.port.flush()?;
+
+        let start = Instant::now();
+        let mut buf = [0u8; 64];
+        while start.elapsed() < self.timeout {
+            let count = match self.port.read(&mut buf) {
+                Ok(count) => count,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for byte in buf[..count].iter() {
+                match self.decoder.push(*byte) {
+                    // the board's frames can't be NAKed back, a broken one is only noise
+                    Some(Err(_)) | None => (),
+                    Some(Ok(protocol::RESP_NAK)) => {
+                        let nak = self.decoder.payload();
+                        return Err(Error::Nak {
+                            command: nak.first().copied().unwrap_or(command),
+                            error: nak.get(1).and_then(|e| FrameError::from_u8(*e)),
+                        });
+                    }
+                    Some(Ok(received)) if received == reply => return Ok(self.decoder.payload().to_vec()),
+                    Some(Ok(received)) => return Err(Error::Unexpected { command: received }),
+                }
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    pub fn into_inner(self) -> P {
+        self.port
+    }
+}
+
+// a board found on the USB bus
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoardInfo {
+    pub port: String,
+    // the NAME the firmware was built with
+    pub name: Option<String>,
+}
+
+// every connected usb-leds board
+pub fn list() -> Result<Vec<BoardInfo>> {
+    let mut boards = Vec::new();
+    for port in serialport::available_ports()? {
+        if let serialport::SerialPortType::UsbPort(usb) = port.port_type {
+            if usb.vid == VID && usb.pid == PID {
+                boards.push(BoardInfo { port: port.port_name, name: usb.serial_number });
+            }
+        }
+    }
+    Ok(boards)
+}
+
+// opens the board built with NAME=name, or the only board when name is None
+pub fn open(name: Option<&str>) -> Result<Board<Box<dyn serialport::SerialPort>>> {
+    let boards = list()?;
+    let board = match name {
+        Some(name) => boards.iter().find(|b| b.name.as_deref() == Some(name)),
+        None if boards.len() == 1 => boards.first(),
+        None => None,
+    };
+    match board {
+        Some(board) => open_port(&board.port),
+        None => Err(Error::NotFound(match name {
+            Some(name) => format!("no board named {} connected", name),
+            None if boards.len() > 1 => "more than one board connected, pick one with --board".to_string(),
+            None => "no board connected".to_string(),
+        })),
+    }
+}
+
+// opens a board by its serial port path, the baud rate doesn't matter for USB serial
+pub fn open_port(path: &str) -> Result<Board<Box<dyn serialport::SerialPort>>> {
+    let port = serialport::new(path, 115_200).timeout(Duration::from_millis(50)).open()?;
+    Ok(Board::new(port))
+}
@@ -0,0 +1,122 @@
+// usbleds: control usb-leds boards from the command line
+//
+//   usbleds list
+//   usbleds [--board NAME | --port PATH] get
+//   usbleds [--board NAME | --port PATH] set --pattern 3 --c1 0.1,1,1 --c2 0.6,1,0.5
+//   usbleds [--board NAME | --port PATH] off | on | brightness 0-255
+//   usbleds [--board NAME | --port PATH] fill R,G,B COUNT | stream-timeout MS
+
+use std::process::ExitCode;
+
+use usb_leds_host::{self as usbleds, Hsv};
+
+const USAGE: &str = "usage:
+  usbleds list
+  usbleds [--board NAME | --port PATH] get
+  usbleds [--board NAME | --port PATH] set --pattern N [--c1 H,S,V] [--c2 H,S,V]
+  usbleds [--board NAME | --port PATH] off | on | brightness 0-255
+  usbleds [--board NAME | --port PATH] fill R,G,B COUNT | stream-timeout MS
+
+--board picks a board by the NAME its firmware was built with, without --board or
+--port the only connected board is used. colors are hue, saturation and value, 0.0-1.0.
+fill streams one frame of COUNT LEDs in R,G,B (0-255), the pattern comes back after
+the stream timeout (ms, 0 never)";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("usbleds: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut board_name: Option<&str> = None;
+    let mut port: Option<&str> = None;
+    let mut rest = args;
+    loop {
+        match rest {
+            [flag, value, tail @ ..] if flag == "--board" => { board_name = Some(value); rest = tail; }
+            [flag, value, tail @ ..] if flag == "--port" => { port = Some(value); rest = tail; }
+            _ => break,
+        }
+    }
+    let (command, options) = match rest.split_first() {
+        Some((command, options)) => (command.as_str(), options),
+        None => return Err(USAGE.into()),
+    };
+
+    if command == "list" {
+        for board in usbleds::list()? {
+            println!("{}\t{}", board.port, board.name.as_deref().unwrap_or("-"));
+        }
+        return Ok(());
+    }
+    if command == "help" || command == "--help" || command == "-h" {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+
+    let mut board = match port {
+        Some(path) => usbleds::open_port(path)?,
+        None => usbleds::open(board_name)?,
+    };
+    match command {
+        "get" => {
+            let state = board.get()?;
+            println!("pattern {}", state.pattern);
+            println!("c1 {},{},{}", state.color_1.h, state.color_1.s, state.color_1.v);
+            println!("c2 {},{},{}", state.color_2.h, state.color_2.s, state.color_2.v);
+            println!("on {}", state.on);
+            println!("brightness {}", state.brightness);
+            println!("streaming {}", state.streaming);
+        }
+        "set" => {
+            // colors that aren't given stay as they are on the board
+            let current = board.get()?;
+            let mut pattern: Option<u8> = None;
+            let mut color_1 = current.color_1;
+            let mut color_2 = current.color_2;
+            for pair in options.chunks(2) {
+                match pair {
+                    [flag, value] if flag == "--pattern" => pattern = Some(value.parse().map_err(|_| format!("bad pattern {}", value))?),
+                    [flag, value] if flag == "--c1" => color_1 = value.parse::<Hsv>()?,
+                    [flag, value] if flag == "--c2" => color_2 = value.parse::<Hsv>()?,
+                    _ => return Err(USAGE.into()),
+                }
+            }
+            board.set(pattern.unwrap_or(current.pattern), &color_1, &color_2)?;
+        }
+        "off" => board.off()?,
+        "on" => board.on()?,
+        "brightness" => {
+            let level = match options {
+                [level] => level.parse::<u8>().map_err(|_| format!("brightness must be 0-255, not {}", level))?,
+                _ => return Err(USAGE.into()),
+            };
+            board.brightness(level)?;
+        }
+        "fill" => {
+            let (color, count) = match options {
+                [color, count] => (color, count.parse::<usize>().map_err(|_| format!("bad LED count {}", count))?),
+                _ => return Err(USAGE.into()),
+            };
+            let rgb: Vec<u8> = color.split(',').map(|c| c.trim().parse::<u8>()).collect::<Result<_, _>>()
+                .map_err(|_| format!("color {} needs R,G,B of 0-255", color))?;
+            if rgb.len() != 3 { return Err(format!("color {} needs R,G,B of 0-255", color).into()) }
+            board.show_frame(&rgb.repeat(count))?;
+        }
+        "stream-timeout" => {
+            let ms = match options {
+                [ms] => ms.parse::<u16>().map_err(|_| format!("timeout must be 0-65535 ms, not {}", ms))?,
+                _ => return Err(USAGE.into()),
+            };
+            board.stream_timeout(ms)?;
+        }
+        _ => return Err(USAGE.into()),
+    }
+    Ok(())
+}
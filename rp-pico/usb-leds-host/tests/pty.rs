@@ -0,0 +1,169 @@
+// runs Board against a fake board on the other end of a pseudo terminal, the fake
+// decodes the frames with the firmware's decoder and answers with protocol::encode
+#![cfg(unix)]
+
+use std::io::{self, Read, Write};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serialport::{SerialPort, TTYPort};
+use usb_leds_host::protocol::{self, FrameDecoder, FrameError};
+use usb_leds_host::{Board, Error, Hsv};
+
+// the reply to a command and its payload, None leaves the host waiting
+type Answer = fn(u8, &[u8]) -> Option<(u8, Vec<u8>)>;
+
+// every frame the fake board received, command and payload
+type Received = JoinHandle<Vec<(u8, Vec<u8>)>>;
+
+// a board answering with answer, the thread returns every frame it received once the
+// host side is dropped
+fn fake_board(answer: Answer) -> (Board<TTYPort>, Received) {
+    let (mut host, mut board) = TTYPort::pair().expect("no pseudo terminal");
+    host.set_timeout(Duration::from_millis(10)).unwrap();
+    board.set_timeout(Duration::from_millis(10)).unwrap();
+    let handle = thread::spawn(move || {
+        let mut decoder = FrameDecoder::<{ protocol::MAX_FRAME }>::new();
+        let mut received = Vec::new();
+        let mut buf = [0u8; 64];
+        loop {
+            let count = match board.read(&mut buf) {
+                Ok(count) => count,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // the host end was closed
+                Err(_) => return received,
+            };
+            if count == 0 { return received }
+            for byte in buf[..count].iter() {
+                let command = match decoder.push(*byte) {
+                    Some(Ok(command)) => command,
+                    Some(Err(_)) | None => continue,
+                };
+                let payload = decoder.payload().to_vec();
+                if let Some((reply, reply_payload)) = answer(command, &payload) {
+                    let mut frame = [0u8; protocol::MAX_FRAME];
+                    let len = protocol::encode(reply, &reply_payload, &mut frame);
+                    // a pty passes the bytes on right away, there's nothing to flush
+                    if board.write_all(&frame[..len]).is_err() { return received }
+                }
+                received.push((command, payload));
+            }
+        }
+    });
+    (Board::new(host).with_timeout(Duration::from_millis(500)), handle)
+}
+
+fn ack(command: u8, _payload: &[u8]) -> Option<(u8, Vec<u8>)> {
+    Some((protocol::RESP_ACK, vec![command]))
+}
+
+fn finish(board: Board<TTYPort>, handle: Received) -> Vec<(u8, Vec<u8>)> {
+    drop(board);
+    handle.join().unwrap()
+}
+
+#[test]
+fn set_sends_pattern_and_colors() {
+    let (mut board, handle) = fake_board(ack);
+    board.set(3, &Hsv::new(0.1, 1.0, 0.5), &Hsv::new(0.6, 0.25, 1.0)).unwrap();
+    let received = finish(board, handle);
+    assert_eq!(received.len(), 1);
+    let (command, payload) = &received[0];
+    assert_eq!(*command, protocol::CMD_SET);
+    assert_eq!(payload.len(), protocol::SET_PAYLOAD);
+    assert_eq!(payload[0], 3);
+    assert_eq!(payload[1..5], 0.1f32.to_le_bytes());
+    assert_eq!(payload[9..13], 0.5f32.to_le_bytes());
+    assert_eq!(payload[17..21], 0.25f32.to_le_bytes());
+}
+
+#[test]
+fn get_reads_the_state() {
+    let (mut board, handle) = fake_board(|command, _| {
+        if command != protocol::CMD_GET { return None }
+        let mut state = vec![0u8; protocol::STATE_PAYLOAD];
+        state[0] = 2;
+        state[1..5].copy_from_slice(&0.75f32.to_le_bytes());
+        state[13..17].copy_from_slice(&0.5f32.to_le_bytes());
+        state[25] = 1;
+        state[26] = 64;
+        state[27] = 0;
+        Some((protocol::RESP_STATE, state))
+    });
+    let state = board.get().unwrap();
+    assert_eq!(state.pattern, 2);
+    assert_eq!(state.color_1, Hsv::new(0.75, 0.0, 0.0));
+    assert_eq!(state.color_2, Hsv::new(0.5, 0.0, 0.0));
+    assert!(state.on);
+    assert_eq!(state.brightness, 64);
+    assert!(!state.streaming);
+    assert_eq!(finish(board, handle), [(protocol::CMD_GET, vec![])]);
+}
+
+#[test]
+fn off_on_and_brightness() {
+    let (mut board, handle) = fake_board(ack);
+    board.off().unwrap();
+    board.on().unwrap();
+    board.brightness(200).unwrap();
+    assert_eq!(finish(board, handle), [
+        (protocol::CMD_OFF, vec![]),
+        (protocol::CMD_ON, vec![]),
+        (protocol::CMD_BRIGHTNESS, vec![200]),
+    ]);
+}
+
+#[test]
+fn nak_becomes_an_error() {
+    let (mut board, handle) = fake_board(|command, _| {
+        Some((protocol::RESP_NAK, vec![command, FrameError::BadPayload as u8]))
+    });
+    match board.brightness(10) {
+        Err(Error::Nak { command, error }) => {
+            assert_eq!(command, protocol::CMD_BRIGHTNESS);
+            assert_eq!(error, Some(FrameError::BadPayload));
+        }
+        other => panic!("expected a NAK, got {:?}", other),
+    }
+    finish(board, handle);
+    // an error code this side doesn't know
+    let (mut board, handle) = fake_board(|command, _| Some((protocol::RESP_NAK, vec![command, 99])));
+    assert!(matches!(board.off(), Err(Error::Nak { command: protocol::CMD_OFF, error: None })));
+    finish(board, handle);
+}
+
+#[test]
+fn ack_for_another_command_is_unexpected() {
+    let (mut board, handle) = fake_board(|_, _| Some((protocol::RESP_ACK, vec![protocol::CMD_ON])));
+    assert!(matches!(board.off(), Err(Error::Unexpected { command: protocol::RESP_ACK })));
+    finish(board, handle);
+}
+
+#[test]
+fn no_reply_times_out() {
+    let (mut board, handle) = fake_board(|_, _| None);
+    let start = Instant::now();
+    assert!(matches!(board.off(), Err(Error::Timeout)));
+    assert!(start.elapsed() >= Duration::from_millis(500));
+    // the board got the command, it just never answered
+    assert_eq!(finish(board, handle), [(protocol::CMD_OFF, vec![])]);
+}
+
+#[test]
+fn frame_goes_over_in_chunks() {
+    let (mut board, handle) = fake_board(ack);
+    let rgb: Vec<u8> = (0..300u32).flat_map(|i| [i as u8, (i >> 8) as u8, 7]).collect();
+    board.show_frame(&rgb).unwrap();
+    let received = finish(board, handle);
+    let chunks = 300usize.div_ceil(protocol::PIXELS_PER_FRAME);
+    assert_eq!(received.len(), chunks + 1);
+    let mut pixels = Vec::new();
+    for (index, (command, payload)) in received[..chunks].iter().enumerate() {
+        assert_eq!(*command, protocol::CMD_PIXELS);
+        assert_eq!(u16::from_le_bytes([payload[0], payload[1]]) as usize, index * protocol::PIXELS_PER_FRAME);
+        pixels.extend_from_slice(&payload[2..]);
+    }
+    assert_eq!(pixels, rgb);
+    assert_eq!(received[chunks], (protocol::CMD_SHOW, 300u16.to_le_bytes().to_vec()));
+}
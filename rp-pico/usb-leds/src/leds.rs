@@ -19,6 +19,7 @@ pub struct Leds {
     buf_end: usize,
     //~ buffer: [u8; 4612],
     buffer: BufferType,
+    brightness: u8,
 }
 
 impl Leds {
@@ -38,6 +39,7 @@ impl Leds {
                     len: led_count,
                     buf_end: buf_end,
                     buffer: BufferType::Addressable([0; 4612]),
+                    brightness: 255,
                 }
             }
             LedType::Analog => {
@@ -46,6 +48,7 @@ impl Leds {
                     len: led_count,
                     buf_end: buf_end,
                     buffer: BufferType::Analog([0; 3]),
+                    brightness: 255,
                 }
             }
         }
@@ -126,6 +129,56 @@ impl Leds {
         &self.buffer
     }
     
+    pub fn set_brightness(&mut self, brightness: u8) {
+        self.brightness = brightness;
+    }
+    
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+    
+    // the addressable buffer as it goes out on SPI, scaled by the master brightness and
+    // handed to write in pieces. the buffer keeps the full levels, so a pattern that isn't
+    // drawn again on every update doesn't fade
+    pub fn write_scaled(&self, mut write: impl FnMut(&[u8])) {
+        let b = match self.buffer {
+            BufferType::Addressable(ref b) => &b[..self.buf_end],
+            BufferType::Analog(_) => return,
+        };
+        if self.brightness == 255 {
+            write(b);
+            return;
+        }
+        let brightness = self.brightness as u32;
+        // the 5-bit global brightness of the APA102 does the coarse part and the colors
+        // only the rest, so low levels don't lose their color resolution
+        let global = (brightness * 31 + 254) / 255;
+        let scale = if global == 0 { 0 } else { brightness * 31 * 256 / (global * 255) };
+        let mut piece = [0u8; 64];
+        for (n, chunk) in b.chunks(piece.len()).enumerate() {
+            for (i, v) in chunk.iter().enumerate() {
+                let index = n * piece.len() + i;
+                piece[i] = match self.led_type {
+                    // start frame, then a header byte and three colors for each LED, then the end frame
+                    LedType::Apa102 if index >= 4 && index < 4 + self.len * 4 => {
+                        if index % 4 == 0 { 0xE0 | global as u8 } else { ((*v as u32 * scale) >> 8) as u8 }
+                    }
+                    LedType::Ws2801 if index < self.len * 3 => (*v as u32 * brightness / 255) as u8,
+                    _ => *v,
+                };
+            }
+            write(&piece[..chunk.len()]);
+        }
+    }
+
+    // the PWM duties of an analog LED, scaled by the master brightness
+    pub fn analog_scaled(&self) -> [u16; 3] {
+        match self.buffer {
+            BufferType::Addressable(_) => [0; 3],
+            BufferType::Analog(ref b) => b.map(|v| (v as u32 * self.brightness as u32 / 255) as u16),
+        }
+    }
+    
     pub fn set_led(&mut self, rgb: Pixel, index: usize) {
         match self.led_type {
             LedType::Apa102 => {
@@ -170,6 +223,54 @@ impl Leds {
         }
     }
 
+    // rgb triplets from the host, LEDs past the end of rgb are turned off
+    pub fn fill_pixels(&mut self, rgb: &[u8]) {
+        match self.led_type {
+            LedType::Apa102 => {
+                match self.buffer {
+                    BufferType::Addressable(ref mut b) => {
+                        for (i, v) in b.chunks_mut(4).skip(1).enumerate() {
+                            if i >= self.len { break; }
+                            let pixel = match rgb.get(i * 3..i * 3 + 3) {
+                                Some(p) => Pixel::new(p[0], p[1], p[2]),
+                                None => Pixel::new(0, 0, 0),
+                            };
+                            set_array_apa102(v, pixel);
+                        }
+                    }
+                    BufferType::Analog(_) => {}
+                }
+            }
+            LedType::Ws2801 => {
+                match self.buffer {
+                    BufferType::Addressable(ref mut b) => {
+                        for (i, v) in b.chunks_mut(3).enumerate() {
+                            if i >= self.len { break; }
+                            let pixel = match rgb.get(i * 3..i * 3 + 3) {
+                                Some(p) => Pixel::new(p[0], p[1], p[2]),
+                                None => Pixel::new(0, 0, 0),
+                            };
+                            set_array_ws2801(v, pixel);
+                        }
+                    }
+                    BufferType::Analog(_) => {}
+                }
+            }
+            LedType::Analog => {
+                match self.buffer {
+                    BufferType::Addressable(_) => {}
+                    BufferType::Analog(ref mut b) => {
+                        let pixel = match rgb.get(0..3) {
+                            Some(p) => Pixel::new(p[0], p[1], p[2]),
+                            None => Pixel::new(0, 0, 0),
+                        };
+                        set_array_analog8(b, pixel);
+                    }
+                }
+            }
+        }
+    }
+
     pub fn fill_gradient(&mut self, start: &PixelHsv, end: &PixelHsv) {
         match self.led_type {
             LedType::Apa102 => {
@@ -1,11 +1,16 @@
 // build directions:
 // NAME=serial_num LED_COUNT=10 LED_TYPE=apa102 cargo build --release
 // elf2uf2-rs ./target/thumbv6m-none-eabi/release/usb-leds usb-leds.uf2
+// the protocol tests run on the build machine:
+// NAME=test LED_COUNT=10 LED_TYPE=apa102 cargo test --target x86_64-unknown-linux-gnu
 
-#![no_std]
-#![no_main]
+// host tests build with std and the test harness's main
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 
 use rp_pico::entry;
+#[cfg(not(test))]
 use panic_halt as _;
 use rp_pico::hal;
 use rp_pico::hal::pac;
@@ -24,6 +29,12 @@ mod leds;
 use crate::leds::{BufferType, Leds, LedType};
 mod prng;
 use crate::prng::Prng;
+// shared with the host side, which uses the parts the firmware doesn't
+#[allow(dead_code)]
+mod protocol;
+use crate::protocol::{FrameDecoder, FrameError};
+mod stream;
+use crate::stream::FrameBuffers;
 mod sprites;
 use crate::sprites::RandomSprites;
 
@@ -58,7 +69,7 @@ fn bytes_to_pixelhsv(data: &[u8]) -> PixelHsv {
     PixelHsv::new(out[0], out[1], out[2])
 }
 
-#[entry]
+#[cfg_attr(not(test), entry)]
 fn main() -> ! {
     let mut pac = pac::Peripherals::take().unwrap();
     let core = pac::CorePeripherals::take().unwrap();
@@ -86,7 +97,8 @@ fn main() -> ! {
     
     let timer = Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
     let update_time: Duration<u64, 1, 1000000> = 10_u64.millis();
-    let serial_timeout: Duration<u64, 1, 1000000> = 1_000_u64.millis();
+    // a frame that stops arriving part way is dropped after this long
+    let frame_timeout: Duration<u64, 1, 1000000> = 100_u64.millis();
     let mut last_update = timer.get_counter();
 
     let usb_bus = UsbBusAllocator::new(hal::usb::UsbBus::new(
@@ -171,6 +183,16 @@ fn main() -> ! {
     }
 
     let mut current_message = ColorMessage::new();
+    // CMD_OFF blacks out the output but keeps the pattern and colors for CMD_ON
+    let mut output_on = true;
+    // frames streamed with CMD_PIXELS and CMD_SHOW replace the pattern until none has
+    // been shown for stream_timeout
+    let mut frames = FrameBuffers::new(led_count);
+    let mut streaming = false;
+    let mut last_frame = timer.get_counter();
+    let mut stream_timeout: Duration<u64, 1, 1000000> = 1_000_u64.millis();
+    let mut decoder = FrameDecoder::<{ protocol::MAX_FRAME }>::new();
+    let mut last_byte = timer.get_counter();
 
     //let sine_test = hal::rom_data::float_funcs::fsin(0.5);
 
@@ -185,69 +207,132 @@ fn main() -> ! {
                 Ok(0) => {
                     // Do nothing
                 }
-                Ok(_count) => {
-                    //~ --first byte is the command type
-                    match usb_buf[0] {
-                        //~ --command 0: off
-                        0 => {},
-                        // --command 1: set pattern and color
-                        1 => {
-                            let mut message = ColorMessage::new();
-                            message.set_pattern(usb_buf[1]);
-                            
-                            let color_1 = bytes_to_pixelhsv(&usb_buf[2..14]);
-                            let color_2 = bytes_to_pixelhsv(&usb_buf[14..26]);
-                            
-                            message.set_colors(color_1, color_2);
-                            if message != current_message { current_message = message }
-                            
-                            // --write "OK\n" to serial:
-                            serial.write(&[0x4F, 0x4B, 0x0A]).ok();
-                        },
-                        //~ --command 2: get pattern and colors as binary data
-                        2 => {
-                            let start = timer.get_counter();
-                            loop {
-                                if serial.rts() { 
-                                    break;
+                Ok(count) => {
+                    last_byte = timer.get_counter();
+                    for byte in usb_buf[..count].iter() {
+                        let command = match decoder.push(*byte) {
+                            Some(Ok(command)) => command,
+                            Some(Err(e)) => {
+                                // the command of a broken frame can't be trusted
+                                send_nak(&mut serial, 0xFF, e);
+                                continue;
+                            }
+                            None => continue,
+                        };
+                        let payload = decoder.payload();
+                        match command {
+                            //~ --command 0: off
+                            protocol::CMD_OFF => {
+                                output_on = false;
+                                send_frame(&mut serial, protocol::RESP_ACK, &[command]);
+                            },
+                            // --command 1: set pattern and color
+                            protocol::CMD_SET => {
+                                if payload.len() != protocol::SET_PAYLOAD {
+                                    send_nak(&mut serial, command, FrameError::BadPayload);
+                                    continue;
                                 }
-                                if timer.get_counter() - start >= serial_timeout {
-                                    break;
+                                let mut message = ColorMessage::new();
+                                message.set_pattern(payload[0]);
+                                
+                                let color_1 = bytes_to_pixelhsv(&payload[1..13]);
+                                let color_2 = bytes_to_pixelhsv(&payload[13..25]);
+                                
+                                message.set_colors(color_1, color_2);
+                                if message != current_message { current_message = message }
+                                streaming = false;
+                                
+                                send_frame(&mut serial, protocol::RESP_ACK, &[command]);
+                            },
+                            //~ --command 2: get pattern and colors as binary data
+                            protocol::CMD_GET => {
+                                let mut state = [0u8; protocol::STATE_PAYLOAD];
+                                state[0] = current_message.pattern;
+                                state[1..5].copy_from_slice(&current_message.color_1.get_h().to_le_bytes());
+                                state[5..9].copy_from_slice(&current_message.color_1.get_s().to_le_bytes());
+                                state[9..13].copy_from_slice(&current_message.color_1.get_v().to_le_bytes());
+                                state[13..17].copy_from_slice(&current_message.color_2.get_h().to_le_bytes());
+                                state[17..21].copy_from_slice(&current_message.color_2.get_s().to_le_bytes());
+                                state[21..25].copy_from_slice(&current_message.color_2.get_v().to_le_bytes());
+                                state[25] = output_on as u8;
+                                state[26] = led.brightness();
+                                state[27] = streaming as u8;
+                                send_frame(&mut serial, protocol::RESP_STATE, &state);
+                            }
+                            // --command 3: on again after off
+                            protocol::CMD_ON => {
+                                output_on = true;
+                                send_frame(&mut serial, protocol::RESP_ACK, &[command]);
+                            }
+                            // --command 4: master brightness
+                            protocol::CMD_BRIGHTNESS => {
+                                if payload.len() != 1 {
+                                    send_nak(&mut serial, command, FrameError::BadPayload);
+                                    continue;
                                 }
+                                led.set_brightness(payload[0]);
+                                send_frame(&mut serial, protocol::RESP_ACK, &[command]);
                             }
-                            
-                            //~ while !serial.rts() {}
-                            
-                            let color_1_h = current_message.color_1.get_h().to_le_bytes();
-                            let color_1_s = current_message.color_1.get_s().to_le_bytes();
-                            let color_1_v = current_message.color_1.get_v().to_le_bytes();
-                            let color_2_h = current_message.color_2.get_h().to_le_bytes();
-                            let color_2_s = current_message.color_2.get_s().to_le_bytes();
-                            let color_2_v = current_message.color_2.get_v().to_le_bytes();
-                            serial.write(&[current_message.pattern]).ok();
-                            serial.write(&color_1_h[..]).ok();
-                            serial.write(&color_1_s[..]).ok();
-                            serial.write(&color_1_v[..]).ok();
-                            serial.write(&color_2_h[..]).ok();
-                            serial.write(&color_2_s[..]).ok();
-                            serial.write(&color_2_v[..]).ok();
-                            serial.write(&[0x0A]).ok();
-                            
-                            match serial.flush() {
-                                Ok(_) => {},
-                                Err(_) => {},
+                            // --command 5: pixels of the next streamed frame
+                            protocol::CMD_PIXELS => {
+                                if payload.len() < 2 {
+                                    send_nak(&mut serial, command, FrameError::BadPayload);
+                                    continue;
+                                }
+                                let first = u16::from_le_bytes([payload[0], payload[1]]) as usize;
+                                if frames.write(first, &payload[2..]) == false {
+                                    send_nak(&mut serial, command, FrameError::BadPayload);
+                                    continue;
+                                }
+                                send_frame(&mut serial, protocol::RESP_ACK, &[command]);
                             }
+                            // --command 6: show the streamed frame
+                            protocol::CMD_SHOW => {
+                                if payload.len() != 2 || frames.show(u16::from_le_bytes([payload[0], payload[1]]) as usize) == false {
+                                    send_nak(&mut serial, command, FrameError::BadPayload);
+                                    continue;
+                                }
+                                streaming = true;
+                                last_frame = timer.get_counter();
+                                send_frame(&mut serial, protocol::RESP_ACK, &[command]);
+                            }
+                            // --command 7: how long a streamed frame stays without a new one
+                            protocol::CMD_STREAM_TIMEOUT => {
+                                if payload.len() != 2 {
+                                    send_nak(&mut serial, command, FrameError::BadPayload);
+                                    continue;
+                                }
+                                stream_timeout = (u16::from_le_bytes([payload[0], payload[1]]) as u64).millis();
+                                send_frame(&mut serial, protocol::RESP_ACK, &[command]);
+                            }
+                            _ => {
+                                send_nak(&mut serial, command, FrameError::UnknownCommand);
+                            },
                         }
-                        _ => {},
                     }
                 }
             }
         }
         
+        if decoder.in_frame() && timer.get_counter() - last_byte >= frame_timeout {
+            decoder.reset();
+        }
+        
         if timer.get_counter() - last_update >= update_time {
             last_update = timer.get_counter();
             
+            // back to the pattern when the host stops streaming, a timeout of 0 never ends it
+            if streaming && stream_timeout.to_millis() != 0 && last_update - last_frame >= stream_timeout {
+                streaming = false;
+            }
+            
             match current_message.pattern {
+                _ if output_on == false => {
+                    led.all_off();
+                }
+                _ if streaming == true => {
+                    led.fill_pixels(frames.pixels());
+                }
                 0 => {
                     led.all_off();
                 }
@@ -267,13 +352,14 @@ fn main() -> ! {
             }
             
             match led.buffer() {
-                BufferType::Addressable(b) => {
-                    spi.write(&b[..led.len()]).ok();
+                BufferType::Addressable(_) => {
+                    led.write_scaled(|bytes| { spi.write(bytes).ok(); });
                 }
-                BufferType::Analog(b) => {
-                    r_channel.set_duty(b[0]);
-                    g_channel.set_duty(b[1]);
-                    b_channel.set_duty(b[2]);
+                BufferType::Analog(_) => {
+                    let duty = led.analog_scaled();
+                    r_channel.set_duty(duty[0]);
+                    g_channel.set_duty(duty[1]);
+                    b_channel.set_duty(duty[2]);
                 }
             }
             
@@ -282,3 +368,17 @@ fn main() -> ! {
         }
     }
 }
+
+fn send_frame<B: UsbBus>(serial: &mut SerialPort<B>, command: u8, payload: &[u8]) {
+    let mut frame = [0u8; 64];
+    let len = protocol::encode(command, payload, &mut frame);
+    serial.write(&frame[..len]).ok();
+    match serial.flush() {
+        Ok(_) => {},
+        Err(_) => {},
+    }
+}
+
+fn send_nak<B: UsbBus>(serial: &mut SerialPort<B>, command: u8, error: FrameError) {
+    send_frame(serial, protocol::RESP_NAK, &[command, error as u8]);
+}
@@ -0,0 +1,318 @@
+// framed serial protocol, used both ways between the host and the board:
+//
+//   0xA5, payload length (u16 LE), command, payload..., CRC-16 (u16 LE)
+//
+// the CRC (CCITT, poly 0x1021, init 0xFFFF) covers the length, command and payload.
+// frames can be split over any number of USB packets or share one, the decoder picks
+// them out of the byte stream and skips anything before the next start byte.
+// this file only uses core so the host side can include it as it is
+
+pub const START: u8 = 0xA5;
+pub const MAX_PAYLOAD: usize = 512;
+// start, length, command and CRC
+pub const OVERHEAD: usize = 6;
+pub const MAX_FRAME: usize = MAX_PAYLOAD + OVERHEAD;
+
+// host to board
+pub const CMD_OFF: u8 = 0x00;        // blacks out the LEDs, the pattern keeps running -> ACK
+pub const CMD_SET: u8 = 0x01;        // pattern, color 1 h s v, color 2 h s v (f32 LE) -> ACK
+pub const CMD_GET: u8 = 0x02;        // -> STATE
+pub const CMD_ON: u8 = 0x03;         // back from CMD_OFF -> ACK
+pub const CMD_BRIGHTNESS: u8 = 0x04; // master brightness 0-255 -> ACK
+pub const CMD_PIXELS: u8 = 0x05;     // first pixel (u16 LE), r g b... into the hidden frame -> ACK
+pub const CMD_SHOW: u8 = 0x06;       // pixel count (u16 LE), shows the hidden frame -> ACK
+pub const CMD_STREAM_TIMEOUT: u8 = 0x07; // ms (u16 LE) without CMD_SHOW before the pattern comes back, 0 never -> ACK
+// board to host
+pub const RESP_ACK: u8 = 0x80;       // command
+pub const RESP_NAK: u8 = 0x81;       // command (0xFF if it wasn't known), error
+pub const RESP_STATE: u8 = 0x82;     // CMD_SET payload, on (0/1), brightness, streaming (0/1)
+
+pub const SET_PAYLOAD: usize = 25;
+pub const STATE_PAYLOAD: usize = SET_PAYLOAD + 3;
+
+// a streamed frame is sent as CMD_PIXELS chunks of up to PIXELS_PER_FRAME pixels, then CMD_SHOW
+pub const MAX_PIXELS: usize = 1024;
+pub const PIXELS_PER_FRAME: usize = (MAX_PAYLOAD - 2) / 3;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FrameError {
+    TooLong = 1,
+    BadCrc = 2,
+    UnknownCommand = 3,
+    BadPayload = 4,
+}
+
+impl FrameError {
+    pub fn from_u8(value: u8) -> Option<FrameError> {
+        match value {
+            1 => Some(FrameError::TooLong),
+            2 => Some(FrameError::BadCrc),
+            3 => Some(FrameError::UnknownCommand),
+            4 => Some(FrameError::BadPayload),
+            _ => None,
+        }
+    }
+}
+
+pub fn crc16(bytes: &[u8]) -> u16 {
+    crc16_update(0xFFFF, bytes)
+}
+
+fn crc16_update(mut crc: u16, bytes: &[u8]) -> u16 {
+    for byte in bytes.iter() {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+// writes a whole frame into out and returns its length, 0 if it doesn't fit
+pub fn encode(command: u8, payload: &[u8], out: &mut [u8]) -> usize {
+    let len = payload.len() + OVERHEAD;
+    if payload.len() > MAX_PAYLOAD || out.len() < len { return 0 }
+    out[0] = START;
+    out[1..3].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+    out[3] = command;
+    out[4..4 + payload.len()].copy_from_slice(payload);
+    let crc = crc16(&out[1..4 + payload.len()]);
+    out[4 + payload.len()..len].copy_from_slice(&crc.to_le_bytes());
+    len
+}
+
+// picks frames out of a byte stream one byte at a time. N is the longest frame it
+// takes, start byte and CRC included
+pub struct FrameDecoder<const N: usize> {
+    // the frame in progress from its start byte on
+    buf: [u8; N],
+    len: usize,
+    // the frame at the start of buf was returned and goes away with the next byte
+    done: bool,
+}
+
+impl<const N: usize> FrameDecoder<N> {
+    pub fn new() -> Self {
+        FrameDecoder {
+            buf: [0; N],
+            len: 0,
+            done: false,
+        }
+    }
+
+    // returns the command of a complete frame, its payload is in payload() until the
+    // next byte is pushed. a frame that is too long or fails its CRC is an error, its start
+    // byte may have been noise so the bytes after it are searched again for the next start
+    pub fn push(&mut self, byte: u8) -> Option<Result<u8, FrameError>> {
+        if self.done {
+            let frame_len = self.payload_len() + OVERHEAD;
+            self.buf.copy_within(frame_len..self.len, 0);
+            self.len -= frame_len;
+            self.done = false;
+            self.skip_to_start(0);
+        }
+        if self.len == 0 && byte != START { return None }
+        // never full here: a frame is taken off as soon as it is complete and a rescan
+        // leaves less than was there before
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        let mut error = None;
+        loop {
+            if self.len < 3 { return error }
+            if self.payload_len() + OVERHEAD > N {
+                error = Some(Err(FrameError::TooLong));
+                self.skip_to_start(1);
+                continue;
+            }
+            let frame_len = self.payload_len() + OVERHEAD;
+            if self.len < frame_len { return error }
+            let crc = u16::from_le_bytes([self.buf[frame_len - 2], self.buf[frame_len - 1]]);
+            if crc16(&self.buf[1..frame_len - 2]) != crc {
+                error = Some(Err(FrameError::BadCrc));
+                self.skip_to_start(1);
+                continue;
+            }
+            // a frame found by a rescan wins over the error of the false start before it
+            self.done = true;
+            return Some(Ok(self.buf[3]));
+        }
+    }
+
+    fn payload_len(&self) -> usize {
+        u16::from_le_bytes([self.buf[1], self.buf[2]]) as usize
+    }
+
+    // drops what was received before the first start byte at or after from, from is 1 to
+    // drop the start byte of a broken frame
+    fn skip_to_start(&mut self, from: usize) {
+        match self.buf[from.min(self.len)..self.len].iter().position(|b| *b == START) {
+            Some(index) => {
+                self.buf.copy_within(from + index..self.len, 0);
+                self.len -= from + index;
+            }
+            None => self.len = 0,
+        }
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        if !self.done { return &[] }
+        &self.buf[4..4 + self.payload_len()]
+    }
+
+    // true while part of a frame has been received
+    pub fn in_frame(&self) -> bool {
+        self.len > 0 && !self.done
+    }
+
+    // drops a partly received frame, for when the rest of it never comes
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.done = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn frame(command: u8, payload: &[u8]) -> Vec<u8> {
+        let mut out = [0u8; MAX_FRAME];
+        let len = encode(command, payload, &mut out);
+        out[..len].to_vec()
+    }
+
+    // every result with its payload, in the order the bytes produced them
+    fn decode<const N: usize>(decoder: &mut FrameDecoder<N>, bytes: &[u8]) -> Vec<Result<(u8, Vec<u8>), FrameError>> {
+        let mut results = Vec::new();
+        for byte in bytes.iter() {
+            match decoder.push(*byte) {
+                Some(Ok(command)) => results.push(Ok((command, decoder.payload().to_vec()))),
+                Some(Err(error)) => results.push(Err(error)),
+                None => (),
+            }
+        }
+        results
+    }
+
+    #[test]
+    fn encode_layout() {
+        assert_eq!(frame(CMD_BRIGHTNESS, &[0x80])[..5], [START, 1, 0, CMD_BRIGHTNESS, 0x80]);
+        assert_eq!(frame(CMD_GET, &[]).len(), OVERHEAD);
+        // the CCITT check value
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        let mut out = [0u8; MAX_FRAME + 1];
+        assert_eq!(encode(CMD_PIXELS, &[0; MAX_PAYLOAD + 1], &mut out), 0);
+        assert_eq!(encode(CMD_PIXELS, &[0; 8], &mut out[..OVERHEAD + 7]), 0);
+    }
+
+    #[test]
+    fn frame_split_byte_by_byte() {
+        let mut decoder = FrameDecoder::<MAX_FRAME>::new();
+        let bytes = frame(CMD_SET, &[7; SET_PAYLOAD]);
+        for byte in bytes[..bytes.len() - 1].iter() {
+            assert_eq!(decoder.push(*byte), None);
+            assert!(decoder.in_frame());
+        }
+        assert_eq!(decoder.push(bytes[bytes.len() - 1]), Some(Ok(CMD_SET)));
+        assert_eq!(decoder.payload(), [7; SET_PAYLOAD]);
+        assert!(!decoder.in_frame());
+    }
+
+    #[test]
+    fn two_frames_in_one_buffer() {
+        let mut decoder = FrameDecoder::<MAX_FRAME>::new();
+        let mut bytes = frame(CMD_BRIGHTNESS, &[10]);
+        bytes.extend(frame(CMD_GET, &[]));
+        bytes.extend(frame(CMD_SHOW, &[3, 0]));
+        assert_eq!(decode(&mut decoder, &bytes), [
+            Ok((CMD_BRIGHTNESS, vec![10])),
+            Ok((CMD_GET, vec![])),
+            Ok((CMD_SHOW, vec![3, 0])),
+        ]);
+    }
+
+    #[test]
+    fn crc_bit_flip() {
+        let good = frame(CMD_SET, &[1; SET_PAYLOAD]);
+        // any single flipped bit after the start byte is caught
+        for index in 3..good.len() {
+            for bit in 0..8 {
+                let mut decoder = FrameDecoder::<MAX_FRAME>::new();
+                let mut bytes = good.clone();
+                bytes[index] ^= 1 << bit;
+                if bytes[index] == START { continue }
+                assert_eq!(decode(&mut decoder, &bytes), [Err(FrameError::BadCrc)], "byte {} bit {}", index, bit);
+                // and the next frame still gets through
+                assert_eq!(decode(&mut decoder, &good), [Ok((CMD_SET, vec![1; SET_PAYLOAD]))]);
+            }
+        }
+    }
+
+    #[test]
+    fn length_over_n() {
+        let mut decoder = FrameDecoder::<16>::new();
+        assert_eq!(decode(&mut decoder, &frame(CMD_PIXELS, &[0; 11])), [Err(FrameError::TooLong)]);
+        assert!(!decoder.in_frame());
+        assert_eq!(decode(&mut decoder, &frame(CMD_PIXELS, &[5; 10])), [Ok((CMD_PIXELS, vec![5; 10]))]);
+        // the length is checked as soon as it arrives
+        let mut decoder = FrameDecoder::<MAX_FRAME>::new();
+        assert_eq!(decode(&mut decoder, &[START, 0xFF, 0xFF]), [Err(FrameError::TooLong)]);
+    }
+
+    #[test]
+    fn junk_before_start() {
+        let mut decoder = FrameDecoder::<MAX_FRAME>::new();
+        let mut bytes = vec![0x00, 0x12, 0xFF, 0x80];
+        bytes.extend(frame(CMD_ON, &[]));
+        assert_eq!(decode(&mut decoder, &bytes), [Ok((CMD_ON, vec![]))]);
+        // junk between frames too
+        let mut bytes = frame(CMD_OFF, &[]);
+        bytes.extend([0x33, 0x44]);
+        bytes.extend(frame(CMD_ON, &[]));
+        assert_eq!(decode(&mut decoder, &bytes), [Ok((CMD_OFF, vec![])), Ok((CMD_ON, vec![]))]);
+    }
+
+    #[test]
+    fn false_start_byte_in_junk() {
+        // the junk start byte reads the real start byte as part of a length that is too long
+        let mut decoder = FrameDecoder::<MAX_FRAME>::new();
+        let mut bytes = vec![0x01, START];
+        bytes.extend(frame(CMD_PIXELS, &[0; 200]));
+        assert_eq!(decode(&mut decoder, &bytes), [Err(FrameError::TooLong), Ok((CMD_PIXELS, vec![0; 200]))]);
+        // a length that fits, the frame is found inside the bytes of the false one
+        let mut decoder = FrameDecoder::<MAX_FRAME>::new();
+        let mut bytes = vec![START, 4, 0];
+        bytes.extend(frame(CMD_BRIGHTNESS, &[9]));
+        assert_eq!(decode(&mut decoder, &bytes), [Ok((CMD_BRIGHTNESS, vec![9]))]);
+    }
+
+    #[test]
+    fn resync_after_bad_crc() {
+        // a corrupted frame whose payload holds a start byte, the next frame has to be
+        // found even though the rescan starts inside the broken one
+        let mut decoder = FrameDecoder::<MAX_FRAME>::new();
+        let mut bytes = frame(CMD_PIXELS, &[0, 0, START, 3, 0, CMD_OFF]);
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x01;
+        bytes.extend(frame(CMD_GET, &[]));
+        bytes.extend(frame(CMD_ON, &[]));
+        let results = decode(&mut decoder, &bytes);
+        assert_eq!(results.first(), Some(&Err(FrameError::BadCrc)));
+        // the start byte in the payload is tried as well and fails on its own
+        let frames: Vec<_> = results.into_iter().filter(|result| result.is_ok()).collect();
+        assert_eq!(frames, [Ok((CMD_GET, vec![])), Ok((CMD_ON, vec![]))]);
+    }
+
+    #[test]
+    fn reset_drops_a_partial_frame() {
+        let mut decoder = FrameDecoder::<MAX_FRAME>::new();
+        let bytes = frame(CMD_BRIGHTNESS, &[5]);
+        decode(&mut decoder, &bytes[..4]);
+        assert!(decoder.in_frame());
+        decoder.reset();
+        assert!(!decoder.in_frame());
+        assert_eq!(decode(&mut decoder, &bytes), [Ok((CMD_BRIGHTNESS, vec![5]))]);
+    }
+}
@@ -0,0 +1,43 @@
+use crate::protocol::MAX_PIXELS;
+
+// double buffer for frames streamed from the host. pixels are written into the hidden
+// frame, which is copied to the shown frame all at once, so a frame that is only partly
+// received never reaches the LEDs. the hidden frame keeps its pixels after a show, so
+// the host only has to send the ones that changed
+pub struct FrameBuffers {
+    hidden: [u8; MAX_PIXELS * 3],
+    shown: [u8; MAX_PIXELS * 3],
+    shown_len: usize,
+    led_count: usize,
+}
+
+impl FrameBuffers {
+    pub fn new(led_count: usize) -> Self {
+        Self {
+            hidden: [0; MAX_PIXELS * 3],
+            shown: [0; MAX_PIXELS * 3],
+            shown_len: 0,
+            led_count: led_count.min(MAX_PIXELS),
+        }
+    }
+    
+    // rgb triplets starting at pixel first, false if they don't fit on the LEDs
+    pub fn write(&mut self, first: usize, rgb: &[u8]) -> bool {
+        if rgb.len() % 3 != 0 || first + rgb.len() / 3 > self.led_count { return false }
+        self.hidden[first * 3..first * 3 + rgb.len()].copy_from_slice(rgb);
+        true
+    }
+    
+    // shows the first count pixels of the hidden frame
+    pub fn show(&mut self, count: usize) -> bool {
+        if count > self.led_count { return false }
+        self.shown[..count * 3].copy_from_slice(&self.hidden[..count * 3]);
+        self.shown_len = count;
+        true
+    }
+    
+    // rgb triplets of the shown frame
+    pub fn pixels(&self) -> &[u8] {
+        &self.shown[..self.shown_len * 3]
+    }
+}
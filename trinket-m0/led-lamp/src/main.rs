@@ -3,6 +3,10 @@
 
 mod buffer;
 mod prng;
+// shift register chains for panel builds, shared with the step sequencer
+#[allow(dead_code)]
+#[path = "../../../grand-central-m4/step-sequencer/src/shiftreg.rs"]
+mod shiftreg;
 mod utilities;
 
 use core::cell::Cell;